@@ -1,6 +1,7 @@
 use std::{
     fmt::{self, Debug, Display},
     ops::{Deref, DerefMut, Index, IndexMut, Range},
+    sync::Arc,
 };
 
 /// An owning object (e.g. `String` or `Vec<u8>` and a range used to index it.
@@ -22,14 +23,98 @@ where
         OwnedSubslice { data, range }
     }
 
+    /// The part of this subslice in `range`, which is relative to its start.  The range is
+    /// clamped to the end of this subslice, and a reversed range gives an empty subslice.
     pub fn subslice(self, range: Range<usize>) -> Self {
-        let start = self.range.start + range.start;
-        let end = std::cmp::min(self.range.start + range.end, self.range.end);
+        let clamp = |i: usize| std::cmp::min(self.range.start.saturating_add(i), self.range.end);
+        let start = clamp(range.start);
+        let end = std::cmp::max(clamp(range.end), start);
         OwnedSubslice {
             data: self.data,
             range: Range { start, end },
         }
     }
+
+    /// The length of the subslice.
+    pub fn len(&self) -> usize {
+        self.range.end - self.range.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.start == self.range.end
+    }
+
+    /// The range of the underlying data this subslice covers.
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    /// Like `subslice`, but `None` if `range` is reversed or extends past the end of this
+    /// subslice.  For strings, the range's bounds must still be on character boundaries.
+    pub fn try_subslice(self, range: Range<usize>) -> Option<Self> {
+        if range.start > range.end || range.end > self.range.end - self.range.start {
+            return None;
+        }
+        Some(self.subslice(range))
+    }
+}
+
+impl<S> OwnedSubslice<S>
+where
+    S: Index<Range<usize>, Output = str>,
+{
+    pub fn as_str(&self) -> &str {
+        self
+    }
+}
+
+impl<S> OwnedSubslice<S>
+where
+    S: Index<Range<usize>, Output = [u8]>,
+{
+    pub fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Shared ownership of `T`, used to let several `OwnedSubslice`s (e.g. one per argument of a
+/// received packet) index into the same buffer without copying it.  This is needed because
+/// `Arc<T>` doesn't implement `Index` itself.
+#[derive(Debug, Default)]
+pub struct Shared<T>(Arc<T>);
+
+impl<T> Shared<T> {
+    pub fn new(data: T) -> Self {
+        Shared(Arc::new(data))
+    }
+
+    /// The underlying `Arc`.
+    pub fn arc(&self) -> &Arc<T> {
+        &self.0
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
+
+impl<T> From<Arc<T>> for Shared<T> {
+    fn from(data: Arc<T>) -> Self {
+        Shared(data)
+    }
+}
+
+impl<T> Index<Range<usize>> for Shared<T>
+where
+    T: Index<Range<usize>>,
+{
+    type Output = T::Output;
+
+    fn index(&self, range: Range<usize>) -> &Self::Output {
+        &self.0[range]
+    }
 }
 
 impl<S> Deref for OwnedSubslice<S>
@@ -72,6 +157,26 @@ impl<T> From<Vec<T>> for OwnedSubslice<Vec<T>> {
     }
 }
 
+impl From<Arc<String>> for OwnedSubslice<Shared<String>> {
+    fn from(data: Arc<String>) -> Self {
+        let len = data.len();
+        OwnedSubslice {
+            data: data.into(),
+            range: 0..len,
+        }
+    }
+}
+
+impl<T> From<Arc<Vec<T>>> for OwnedSubslice<Shared<Vec<T>>> {
+    fn from(data: Arc<Vec<T>>) -> Self {
+        let len = data.len();
+        OwnedSubslice {
+            data: data.into(),
+            range: 0..len,
+        }
+    }
+}
+
 impl<S> Debug for OwnedSubslice<S>
 where
     S: Index<Range<usize>>,
@@ -112,6 +217,9 @@ where
     }
 }
 
+// Newer compilers warn that this impl may be rejected in future (rust-lang/rust#124559), but
+// replacing it with impls for each backing type would drop comparisons other crates rely on
+#[allow(uncovered_param_in_projection)]
 impl<S> PartialEq<OwnedSubslice<S>> for &S::Output
 where
     S: Index<Range<usize>>,
@@ -139,4 +247,64 @@ mod tests {
         let owned = OwnedSubslice::new(string, 1..4);
         assert_eq!(&*owned, "ell");
     }
+
+    #[test]
+    #[allow(clippy::reversed_empty_ranges)]
+    fn test_subslice_bounds() {
+        let owned = OwnedSubslice::new(String::from("Hello world!"), 6..11);
+        assert_eq!(&*owned.clone().subslice(1..100), "orld");
+        assert_eq!(&*owned.clone().subslice(100..200), "");
+        assert_eq!(&*owned.clone().subslice(2..2), "");
+        assert_eq!(&*owned.clone().subslice(3..1), "");
+        assert_eq!(&*owned.clone().subslice(usize::MAX..usize::MAX), "");
+
+        assert_eq!(&*owned.clone().try_subslice(0..5).unwrap(), "world");
+        assert_eq!(&*owned.clone().try_subslice(5..5).unwrap(), "");
+        assert!(owned.clone().try_subslice(0..6).is_none());
+        assert!(owned.clone().try_subslice(6..6).is_none());
+        assert!(owned.try_subslice(3..1).is_none());
+    }
+
+    #[test]
+    fn test_accessors() {
+        let owned = OwnedSubslice::new(String::from("Hello world!"), 6..11);
+        assert_eq!(owned.as_str(), "world");
+        assert_eq!(owned.len(), 5);
+        assert!(!owned.is_empty());
+        assert_eq!(owned.range(), 6..11);
+        assert!(owned.subslice(2..2).is_empty());
+
+        let owned = OwnedSubslice::from(Arc::new(vec![0u8, 1, 2, 3])).subslice(1..3);
+        assert_eq!(owned.as_bytes(), &[1, 2]);
+        assert_eq!(owned.len(), 2);
+        assert_eq!(owned.range(), 1..3);
+    }
+
+    #[test]
+    fn test_shared() {
+        let data = Arc::new(vec![0u8, 1, 2, 3, 4, 5, 6, 7]);
+        let owned = OwnedSubslice::from(data.clone());
+        let first = owned.clone().subslice(0..5);
+        let second = owned.clone().subslice(3..8);
+        let inner = second.clone().subslice(1..3);
+
+        assert_eq!(&*first, &[0, 1, 2, 3, 4][..]);
+        assert_eq!(&*second, &[3, 4, 5, 6, 7][..]);
+        assert_eq!(&*inner, &[4, 5][..]);
+        assert_eq!(Arc::strong_count(&data), 5);
+        assert!(Arc::ptr_eq(first.data.arc(), &data));
+
+        drop((owned, first, second, inner));
+        assert_eq!(Arc::strong_count(&data), 1);
+    }
+
+    #[test]
+    fn test_shared_string() {
+        let owned = OwnedSubslice::from(Arc::new(String::from("Hello world!")));
+        let hello = owned.clone().subslice(0..5);
+        let world = owned.subslice(6..11);
+        assert_eq!(&*hello, "Hello");
+        assert_eq!(&*world, "world");
+        assert_eq!(hello.to_string(), "Hello");
+    }
 }
@@ -1,14 +1,11 @@
 use std::time::Duration;
 
 use async_tungstenite::tokio::TokioAdapter;
-use futures::{
-    future::FutureExt,
-    task::{FutureObj, Spawn, SpawnError},
-};
+use futures::future::FutureExt;
 use structopt::StructOpt;
 use tokio::{io, net::TcpStream};
 
-use socket_io_client::{protocol, AckBuilder, Client};
+use socket_io_client::{protocol, spawn::TokioSpawner, AckBuilder, Client};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "ws_connect")]
@@ -21,14 +18,6 @@ struct Opt {
     timeout: u64,
 }
 
-struct TokioSpawn();
-impl Spawn for TokioSpawn {
-    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
-        let _ = tokio::spawn(future);
-        Ok(())
-    }
-}
-
 async fn connect(host: String, port: u16) -> Result<TokioAdapter<TcpStream>, io::Error> {
     Ok(TokioAdapter(
         TcpStream::connect((host.as_str(), port)).await?,
@@ -42,18 +31,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Opt::from_args();
     log::info!("Args: {:?}", opt);
 
-    let spawn = TokioSpawn();
-
-    let mut client = Client::connect(opt.url, connect, &spawn).await?;
+    let mut client = Client::connect(opt.url, connect, &TokioSpawner).await?;
 
-    client.set_fallback_callback(|args: &protocol::Args, _ack| println!("{}", args));
+    client.set_fallback_callback(|args: &protocol::Args, _ack| println!("{}", args))?;
     client.set_event_callback("types", |args: &protocol::Args, ack: Option<AckBuilder>| {
         println!("types messaged received: {}", args);
         if let Some(ack) = ack {
             println!("Emitting ack");
-            ack.args().arg("message received").unwrap().send();
+            if let Err(e) = ack.args().arg("message received").unwrap().send() {
+                println!("Failed to send ack: {}", e);
+            }
         }
-    });
+    })?;
     println!("Callbacks registered");
 
     client
@@ -65,7 +54,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .args()
         .arg(&vec![0xdeu8, 0xad, 0xbe, 0xef])?
         .arg("hello")?
-        .send();
+        .send()
+        .await?;
 
     let timeout = tokio::time::delay_for(Duration::from_secs(opt.timeout)).fuse();
 
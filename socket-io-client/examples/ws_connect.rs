@@ -1,14 +1,11 @@
 use std::time::Duration;
 
-use async_tungstenite::{tokio::TokioAdapter, tungstenite::Message as WsMessage};
-use futures::{
-    future::FutureExt,
-    task::{FutureObj, Spawn, SpawnError},
-};
+use async_tungstenite::tokio::TokioAdapter;
+use futures::future::FutureExt;
 use structopt::StructOpt;
 use tokio::{io, net::TcpStream};
 
-use socket_io_client::{protocol, Client};
+use socket_io_client::{protocol, spawn::TokioSpawner, Client};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "ws_connect")]
@@ -25,14 +22,6 @@ struct Opt {
     namespace: Option<String>,
 }
 
-struct TokioSpawn();
-impl Spawn for TokioSpawn {
-    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
-        let _ = tokio::spawn(future);
-        Ok(())
-    }
-}
-
 async fn connect(host: String, port: u16) -> Result<TokioAdapter<TcpStream>, io::Error> {
     Ok(TokioAdapter(
         TcpStream::connect((host.as_str(), port)).await?,
@@ -46,21 +35,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Opt::from_args();
     log::info!("Args: {:?}", opt);
 
-    let spawn = TokioSpawn();
-
-    let mut client = Client::connect(opt.url, connect, &spawn).await?;
+    let mut client = Client::connect(opt.url, connect, &TokioSpawner).await?;
 
-    client.set_fallback_callback(|args: &protocol::Args, _ack| println!("{}", args));
+    client.set_fallback_callback(|args: &protocol::Args, _ack| println!("{}", args))?;
     let timeout = tokio::time::delay_for(Duration::from_secs(opt.timeout)).fuse();
 
     if let Some(namespace) = &opt.namespace {
         let n2 = namespace.clone();
         client.set_namespace_fallback_callback(namespace, move |args: &protocol::Args, _ack| {
             println!("{}: {}", n2, args)
-        });
-        client
-            .send
-            .unbounded_send(vec![WsMessage::Text(format!("40{},", namespace))])?;
+        })?;
+        client.connect_namespace(namespace)?;
     }
 
     timeout.await;
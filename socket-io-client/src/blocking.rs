@@ -0,0 +1,163 @@
+//! A blocking wrapper around `Client`, for scripts and tests that don't otherwise use async code.
+
+use std::{error::Error as StdError, time::Duration};
+
+use futures::{
+    executor::{self, ThreadPool},
+    future::Future,
+    io::{AsyncRead, AsyncWrite},
+};
+use serde::Serialize;
+
+use super::{Client, ClientOptions, CloseReason, Error, EventCallback, Host, Port};
+
+/// A `Client` that runs its connection on a thread it owns, with methods that block until they
+/// complete.  The streams it connects over must not need a particular runtime, e.g. tokio's
+/// streams can't be used, but async-std's can.  Event callbacks are called on the connection's
+/// thread.  Failing to start the thread gives `Error::ConnectionError` with the `io::Error`.
+pub struct BlockingClient {
+    client: Client,
+    // Kept for as long as the client, as dropping it stops running the connection
+    _pool: ThreadPool,
+}
+
+impl BlockingClient {
+    /// Connect to `url` like `Client::connect`, blocking until the connection opens.
+    pub fn connect<C, F, S, E>(url: impl AsRef<str>, connect: C) -> Result<BlockingClient, Error>
+    where
+        C: 'static + Send + Fn(Host, Port) -> F,
+        F: 'static + Send + Future<Output = Result<S, E>>,
+        S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+        E: 'static + StdError + Send,
+    {
+        BlockingClient::connect_with_options(url, ClientOptions::default(), connect)
+    }
+
+    /// Like `connect`, with `options`.
+    pub fn connect_with_options<C, F, S, E>(
+        url: impl AsRef<str>,
+        options: ClientOptions,
+        connect: C,
+    ) -> Result<BlockingClient, Error>
+    where
+        C: 'static + Send + Fn(Host, Port) -> F,
+        F: 'static + Send + Future<Output = Result<S, E>>,
+        S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+        E: 'static + StdError + Send,
+    {
+        let pool = pool()?;
+        let client =
+            executor::block_on(Client::connect_reconnecting(url, options, connect, &pool))?;
+        Ok(BlockingClient {
+            client,
+            _pool: pool,
+        })
+    }
+
+    /// Connect over `connection` like `Client::from_stream_with_options`.
+    pub fn from_stream_with_options<S>(
+        url: impl AsRef<str>,
+        options: ClientOptions,
+        connection: S,
+    ) -> Result<BlockingClient, Error>
+    where
+        S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let pool = pool()?;
+        let client = executor::block_on(Client::from_stream_with_options(
+            url, options, connection, &pool,
+        ))?;
+        Ok(BlockingClient {
+            client,
+            _pool: pool,
+        })
+    }
+
+    /// Emit `event` to the default namespace with `payload` as its only argument, blocking until
+    /// it's queued to be sent.
+    pub fn emit<T>(&mut self, event: &str, payload: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        executor::block_on(self.client.emit(event).emit_one(payload))
+    }
+
+    /// Set the callback for `event` in the default namespace, like `Client::set_event_callback`.
+    pub fn on_event(
+        &mut self,
+        event: &str,
+        callback: impl Into<EventCallback>,
+    ) -> Result<(), Error> {
+        self.client.set_event_callback(event, callback)
+    }
+
+    /// Block until the server accepts the connection to `namespace`, like
+    /// `Client::wait_connected`.
+    pub fn wait_connected(&self, namespace: &str, timeout: Duration) -> Result<(), Error> {
+        executor::block_on(self.client.wait_connected(namespace, timeout))
+    }
+
+    /// The wrapped client, for the rest of its API.  Its async methods can be run with
+    /// `futures::executor::block_on`.
+    pub fn client(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    /// Close the connection like `Client::close`, blocking until it's closed.
+    pub fn close(mut self) -> Result<Option<CloseReason>, Error> {
+        executor::block_on(self.client.close())
+    }
+}
+
+fn pool() -> Result<ThreadPool, Error> {
+    ThreadPool::builder()
+        .pool_size(1)
+        .name_prefix("socket-io-client-")
+        .create()
+        .map_err(|e| Error::ConnectionError(Box::new(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread};
+
+    use async_tungstenite::tungstenite::Message as WsMessage;
+
+    use super::*;
+    use crate::{harness, protocol::Args};
+
+    #[test]
+    fn test_blocking() {
+        let (client, server) = harness::duplex();
+        let server = thread::spawn(move || {
+            executor::block_on(async {
+                let mut server = harness::Server::accept(server).await;
+                server.send(r#"40{"sid":"abc"}"#).await;
+                assert_eq!(server.recv_text().await, r#"42["event",1]"#);
+                server.send(r#"42["reply","ok"]"#).await;
+                server.closed().await
+            })
+        });
+
+        let mut client = BlockingClient::from_stream_with_options(
+            "http://localhost/",
+            Default::default(),
+            client,
+        )
+        .unwrap();
+        let (tx, rx) = mpsc::channel();
+        client
+            .on_event("reply", move |args: &Args, _ack| {
+                tx.send(args.to_string()).unwrap()
+            })
+            .unwrap();
+        client.wait_connected("/", Duration::from_secs(5)).unwrap();
+        client.emit("event", &1).unwrap();
+        assert_eq!(rx.recv().unwrap(), r#"["reply", "ok"]"#);
+        client.close().unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            vec![WsMessage::Text("41".into()), WsMessage::Text("1".into())]
+        );
+    }
+}
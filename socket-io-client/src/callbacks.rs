@@ -1,11 +1,15 @@
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap, VecDeque},
     sync::{Arc, Mutex},
+    time::Instant,
 };
 
-use socket_io_protocol::socket::Args;
+use async_tungstenite::tungstenite::Message as WsMessage;
+use futures::channel::{mpsc, oneshot};
 
-use super::AckBuilder;
+use socket_io_protocol::socket::{Args, OwnedArgs};
+
+use super::{AckBuilder, ConnectionStatus, Error};
 
 // TODO: Is there a cleaner way to do this?
 macro_rules! impl_fnmut_callback {
@@ -60,8 +64,6 @@ macro_rules! impl_fnonce_callback {
     }
 }
 
-// TODO: Add callback for connection status updates
-
 impl_fnmut_callback! {
     /// A wrapper type for event callbacks, which must be stored and called potentially repeatedly.
     /// They are stored as Arc<Mutex<dyn T>> to allow releasing the mutex on the main map of
@@ -69,40 +71,162 @@ impl_fnmut_callback! {
     EventCallback(args: &Args, ack: Option<AckBuilder>) // TODO: Add response builder
 }
 
+impl_fnmut_callback! {
+    /// A wrapper type for the global fallback callback, which is called for events in any namespace
+    /// without a callback or namespace fallback set, and so also receives the namespace and event.
+    GlobalFallbackCallback(namespace: &str, event: &str, args: &Args, ack: Option<AckBuilder>)
+}
+
+impl_fnmut_callback! {
+    /// A wrapper type for event transformers, which can modify an event's arguments before its
+    /// callback is called.
+    EventTransformer(args: &mut OwnedArgs)
+}
+
+impl_fnmut_callback! {
+    /// A wrapper type for connect callbacks, which are called with the namespace when the server
+    /// accepts a connection to it.
+    ConnectCallback(namespace: &str)
+}
+
+impl_fnmut_callback! {
+    /// A wrapper type for connect error callbacks, which are called with the namespace and the
+    /// server's error message when the server refuses a connection to it.
+    ConnectErrorCallback(namespace: &str, message: &str)
+}
+
+impl_fnmut_callback! {
+    /// A wrapper type for disconnect callbacks, which are called with the namespace when the
+    /// server disconnects the client from it.
+    DisconnectCallback(namespace: &str)
+}
+
 impl_fnonce_callback! {
     /// A wrapper type for ack callbacks, which only need to be called once.
     AckCallback(args: &Args)
 }
 
+impl_fnmut_callback! {
+    /// A wrapper type for the error callback, which is called with errors that occur while handling
+    /// received data but that don't affect the connection, e.g. a typed ack callback receiving
+    /// arguments of the wrong type.
+    ErrorCallback(error: &Error)
+}
+
+/// An event received without a callback to handle it, from `Client::events`.
+pub struct IncomingEvent {
+    pub namespace: String,
+    pub event: String,
+    /// The event's arguments, including the event name as the first argument, as event callbacks
+    /// receive them.
+    pub args: OwnedArgs,
+    /// Set if the server expects an ack for the event.
+    pub ack: Option<AckBuilder>,
+}
+
+/// Whether the client is connected to a namespace, from `Client::namespace_state`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NamespaceState {
+    Disconnected,
+    /// A connection was requested and the server hasn't accepted or refused it yet.  Events
+    /// emitted to the namespace are queued until it's accepted.
+    Connecting,
+    Connected,
+}
+
+/// The result of looking up the callback for a received ack.
+pub enum TakenAck {
+    Pending(AckCallback),
+    /// The ack expired or was cancelled before it was received, and its callback was dropped.
+    Expired,
+    /// No ack with the id was expected.
+    Unknown,
+}
+
+/// The default limit on the number of namespaces that callbacks or connection state can be
+/// stored for.
+pub const DEFAULT_MAX_NAMESPACES: usize = 1024;
+
 pub struct Callbacks {
     namespaces: HashMap<String, Namespace>,
+    max_namespaces: usize,
+    global_fallback: Option<GlobalFallbackCallback>,
+    error: Option<ErrorCallback>,
+    status: Vec<mpsc::UnboundedSender<ConnectionStatus>>,
+    // Receives events without a callback
+    events: Option<mpsc::UnboundedSender<IncomingEvent>>,
+    // Websocket pings waiting for their pong, by the id sent as the ping's payload
+    pings: HashMap<u64, oneshot::Sender<()>>,
+    next_ping: u64,
+    // The namespace and id of acks that were dropped before being received, e.g. because they
+    // expired, so one received late isn't mistaken for an ack that was never expected.  Oldest
+    // first, and cleared when the session ends as old ids can't be acked on a new session.
+    late_acks: VecDeque<(String, u64)>,
 }
 
+/// The most dropped acks remembered at once, so a server that never answers can't grow the list
+/// without bound.  The oldest is forgotten first.
+const MAX_LATE_ACKS: usize = 1024;
+
 struct Namespace {
     fallback: Option<EventCallback>,
     events: HashMap<String, EventCallback>,
-    acks: HashMap<u64, AckCallback>,
+    // Run in order of registration
+    transformers: HashMap<String, Vec<EventTransformer>>,
+    // Each ack's callback, and when it expires if it has a timeout
+    acks: HashMap<u64, (AckCallback, Option<Instant>)>,
+    connect: Option<ConnectCallback>,
+    connect_error: Option<ConnectErrorCallback>,
+    disconnect: Option<DisconnectCallback>,
+    state: NamespaceState,
+    // Packets emitted while connecting, sent once the server accepts the connection, along with
+    // the id of the ack each expects
+    queued: Vec<(Vec<WsMessage>, Option<u64>)>,
+    // The namespace's session id, sent by v5 servers when connecting
+    sid: Option<String>,
+    // The connect packet with auth last sent, which is sent again when reconnecting
+    connect_packet: Option<WsMessage>,
+    // Notified with the result of the next connect or connect error for the namespace
+    connect_waiters: Vec<oneshot::Sender<Result<(), String>>>,
 }
 
 impl Callbacks {
+    #[cfg(test)]
     pub fn new() -> Self {
+        Callbacks::with_max_namespaces(DEFAULT_MAX_NAMESPACES)
+    }
+
+    pub fn with_max_namespaces(max_namespaces: usize) -> Self {
         Callbacks {
             namespaces: HashMap::new(),
+            max_namespaces,
+            global_fallback: None,
+            error: None,
+            status: Vec::new(),
+            events: None,
+            pings: HashMap::new(),
+            next_ping: 0,
+            late_acks: VecDeque::new(),
         }
     }
 
+    /// The callback for the event, or the namespace's fallback if there isn't one.  If neither is
+    /// set the global fallback should be used.
     pub fn get_event(&self, namespace: &str, event: &str) -> Option<EventCallback> {
         let ns = self.namespaces.get(namespace)?;
-        ns.events
-            .get(event)
-            .or_else(|| ns.fallback.as_ref())
-            .map(EventCallback::clone)
+        ns.events.get(event).or(ns.fallback.as_ref()).cloned()
     }
 
-    pub fn set_event(&mut self, namespace: &str, event: &str, callback: impl Into<EventCallback>) {
-        self.get_or_create_namespace(namespace)
+    pub fn set_event(
+        &mut self,
+        namespace: &str,
+        event: &str,
+        callback: impl Into<EventCallback>,
+    ) -> Result<(), Error> {
+        self.get_or_create_namespace(namespace)?
             .events
             .insert(event.to_string(), callback.into());
+        Ok(())
     }
 
     pub fn clear_event(&mut self, namespace: &str, event: &str) {
@@ -111,8 +235,13 @@ impl Callbacks {
         }
     }
 
-    pub fn set_fallback(&mut self, namespace: &str, callback: impl Into<EventCallback>) {
-        self.get_or_create_namespace(namespace).fallback = Some(callback.into());
+    pub fn set_fallback(
+        &mut self,
+        namespace: &str,
+        callback: impl Into<EventCallback>,
+    ) -> Result<(), Error> {
+        self.get_or_create_namespace(namespace)?.fallback = Some(callback.into());
+        Ok(())
     }
 
     pub fn clear_fallback(&mut self, namespace: &str) {
@@ -121,21 +250,420 @@ impl Callbacks {
         }
     }
 
-    pub fn get_and_clear_ack(&mut self, namespace: &str, id: u64) -> Option<AckCallback> {
-        let ns = self.namespaces.get_mut(namespace)?;
-        ns.acks.remove(&id)
+    /// The transformers for the event, in the order they were added.
+    pub fn get_transformers(&self, namespace: &str, event: &str) -> Vec<EventTransformer> {
+        self.namespaces
+            .get(namespace)
+            .and_then(|ns| ns.transformers.get(event))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn add_transformer(
+        &mut self,
+        namespace: &str,
+        event: &str,
+        transformer: impl Into<EventTransformer>,
+    ) -> Result<(), Error> {
+        self.get_or_create_namespace(namespace)?
+            .transformers
+            .entry(event.to_string())
+            .or_default()
+            .push(transformer.into());
+        Ok(())
+    }
+
+    pub fn clear_transformers(&mut self, namespace: &str, event: &str) {
+        if let Some(ns) = self.namespaces.get_mut(namespace) {
+            ns.transformers.remove(event);
+        }
+    }
+
+    pub fn get_connect(&self, namespace: &str) -> Option<ConnectCallback> {
+        self.namespaces.get(namespace)?.connect.clone()
     }
 
-    pub fn set_ack(&mut self, namespace: &str, id: u64, callback: impl Into<AckCallback>) {
-        self.get_or_create_namespace(namespace)
+    pub fn set_connect(
+        &mut self,
+        namespace: &str,
+        callback: impl Into<ConnectCallback>,
+    ) -> Result<(), Error> {
+        self.get_or_create_namespace(namespace)?.connect = Some(callback.into());
+        Ok(())
+    }
+
+    pub fn clear_connect(&mut self, namespace: &str) {
+        if let Some(ns) = self.namespaces.get_mut(namespace) {
+            ns.connect = None;
+        }
+    }
+
+    pub fn get_connect_error(&self, namespace: &str) -> Option<ConnectErrorCallback> {
+        self.namespaces.get(namespace)?.connect_error.clone()
+    }
+
+    pub fn set_connect_error(
+        &mut self,
+        namespace: &str,
+        callback: impl Into<ConnectErrorCallback>,
+    ) -> Result<(), Error> {
+        self.get_or_create_namespace(namespace)?.connect_error = Some(callback.into());
+        Ok(())
+    }
+
+    pub fn clear_connect_error(&mut self, namespace: &str) {
+        if let Some(ns) = self.namespaces.get_mut(namespace) {
+            ns.connect_error = None;
+        }
+    }
+
+    pub fn get_disconnect(&self, namespace: &str) -> Option<DisconnectCallback> {
+        self.namespaces.get(namespace)?.disconnect.clone()
+    }
+
+    pub fn set_disconnect(
+        &mut self,
+        namespace: &str,
+        callback: impl Into<DisconnectCallback>,
+    ) -> Result<(), Error> {
+        self.get_or_create_namespace(namespace)?.disconnect = Some(callback.into());
+        Ok(())
+    }
+
+    pub fn clear_disconnect(&mut self, namespace: &str) {
+        if let Some(ns) = self.namespaces.get_mut(namespace) {
+            ns.disconnect = None;
+        }
+    }
+
+    pub fn get_and_clear_ack(&mut self, namespace: &str, id: u64) -> TakenAck {
+        let pending = self
+            .namespaces
+            .get_mut(namespace)
+            .and_then(|ns| ns.acks.remove(&id));
+        if let Some((callback, _)) = pending {
+            return TakenAck::Pending(callback);
+        }
+        let late = self
+            .late_acks
+            .iter()
+            .position(|(ns, late)| *late == id && ns == namespace);
+        match late {
+            Some(idx) => {
+                self.late_acks.remove(idx);
+                TakenAck::Expired
+            }
+            None => TakenAck::Unknown,
+        }
+    }
+
+    /// Remember that the ack was dropped, so it's ignored if it's received late.
+    fn add_late_ack(&mut self, namespace: &str, id: u64) {
+        if self.late_acks.len() == MAX_LATE_ACKS {
+            self.late_acks.pop_front();
+        }
+        self.late_acks.push_back((namespace.to_string(), id));
+    }
+
+    /// Forget the acks that were dropped, once the session they were sent on has ended.
+    pub fn clear_late_acks(&mut self) {
+        self.late_acks.clear();
+    }
+
+    /// Register an ack callback, which expires at `expires` if given.
+    pub fn set_ack(
+        &mut self,
+        namespace: &str,
+        id: u64,
+        callback: impl Into<AckCallback>,
+        expires: Option<Instant>,
+    ) -> Result<(), Error> {
+        self.get_or_create_namespace(namespace)?
             .acks
-            .insert(id, callback.into());
+            .insert(id, (callback.into(), expires));
+        Ok(())
+    }
+
+    /// Remove an ack whose expiry has passed, dropping its callback.  Returns whether it was
+    /// removed.
+    pub fn expire_ack(&mut self, namespace: &str, id: u64) -> bool {
+        let ns = match self.namespaces.get_mut(namespace) {
+            Some(ns) => ns,
+            None => return false,
+        };
+        match ns.acks.get(&id) {
+            Some((_, Some(expires))) if *expires <= Instant::now() => {
+                ns.acks.remove(&id);
+                self.add_late_ack(namespace, id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The ids of the namespace's acks that are still waiting for a response, sorted.
+    pub fn pending_ack_ids(&self, namespace: &str) -> Vec<u64> {
+        let mut ids: Vec<_> = self
+            .namespaces
+            .get(namespace)
+            .map(|ns| ns.acks.keys().copied().collect())
+            .unwrap_or_default();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// The number of acks still waiting for a response across all namespaces.
+    pub fn pending_acks(&self) -> usize {
+        self.namespaces.values().map(|ns| ns.acks.len()).sum()
+    }
+
+    /// Remove the namespace's pending ack callbacks, dropping them without calling them.  They're
+    /// remembered as dropped, so any the server sent before it saw the namespace was left are
+    /// ignored.
+    pub fn clear_acks(&mut self, namespace: &str) {
+        let ids: Vec<u64> = match self.namespaces.get_mut(namespace) {
+            Some(ns) => ns.acks.drain().map(|(id, _)| id).collect(),
+            None => return,
+        };
+        for id in ids {
+            self.add_late_ack(namespace, id);
+        }
+    }
+
+    /// Remove everything stored for the namespace: its callbacks, transformers, pending acks and
+    /// connection state.  Ack callbacks are dropped without being called, and remembered as
+    /// dropped like with `clear_acks`.
+    pub fn clear_namespace(&mut self, namespace: &str) {
+        self.clear_acks(namespace);
+        self.namespaces.remove(namespace);
+    }
+
+    /// Remove all registered ack callbacks, returning them along with their namespace and id.
+    /// They're remembered as dropped, so any that are received late are ignored.
+    pub fn take_acks(&mut self) -> Vec<(String, u64, AckCallback)> {
+        let mut acks = Vec::new();
+        for (name, ns) in self.namespaces.iter_mut() {
+            acks.extend(ns.acks.drain().map(|(id, (cb, _))| (name.clone(), id, cb)));
+        }
+        for (name, id, _) in &acks {
+            self.add_late_ack(name, *id);
+        }
+        acks
+    }
+
+    /// Allocate an id for a ping, returning it with a receiver that resolves when its pong is
+    /// received.
+    pub fn add_ping(&mut self) -> (u64, oneshot::Receiver<()>) {
+        let id = self.next_ping;
+        self.next_ping += 1;
+        let (tx, rx) = oneshot::channel();
+        self.pings.insert(id, tx);
+        (id, rx)
+    }
+
+    pub fn take_ping(&mut self, id: u64) -> Option<oneshot::Sender<()>> {
+        self.pings.remove(&id)
+    }
+
+    /// Remove all pings waiting for a pong, so they resolve as disconnected.
+    pub fn clear_pings(&mut self) {
+        self.pings.clear();
+    }
+
+    /// Returns a receiver that resolves once the namespace is connected, immediately if it already
+    /// is, or with the server's message if the connection is refused.
+    pub fn wait_connected(
+        &mut self,
+        namespace: &str,
+    ) -> Result<oneshot::Receiver<Result<(), String>>, Error> {
+        let (tx, rx) = oneshot::channel();
+        let ns = self.get_or_create_namespace(namespace)?;
+        if ns.state == NamespaceState::Connected {
+            let _ = tx.send(Ok(()));
+        } else {
+            ns.connect_waiters.retain(|waiter| !waiter.is_canceled());
+            ns.connect_waiters.push(tx);
+        }
+        Ok(rx)
+    }
+
+    /// The connect packet to send for the namespace when reconnecting, if it needs auth.
+    pub fn get_connect_packet(&self, namespace: &str) -> Option<WsMessage> {
+        self.namespaces.get(namespace)?.connect_packet.clone()
+    }
+
+    pub fn set_connect_packet(&mut self, namespace: &str, packet: WsMessage) -> Result<(), Error> {
+        self.get_or_create_namespace(namespace)?.connect_packet = Some(packet);
+        Ok(())
     }
 
-    fn get_or_create_namespace(&mut self, namespace: &str) -> &mut Namespace {
+    pub fn clear_connect_packet(&mut self, namespace: &str) {
+        if let Some(ns) = self.namespaces.get_mut(namespace) {
+            ns.connect_packet = None;
+        }
+    }
+
+    pub fn get_state(&self, namespace: &str) -> NamespaceState {
         self.namespaces
-            .entry(namespace.to_string())
-            .or_insert_with(Namespace::new)
+            .get(namespace)
+            .map_or(NamespaceState::Disconnected, |ns| ns.state)
+    }
+
+    /// Mark the namespace as waiting for the server to accept a connection, unless it's already
+    /// connected.
+    pub fn set_connecting(&mut self, namespace: &str) -> Result<(), Error> {
+        let ns = self.get_or_create_namespace(namespace)?;
+        if ns.state == NamespaceState::Disconnected {
+            ns.state = NamespaceState::Connecting;
+        }
+        Ok(())
+    }
+
+    /// Queue packets emitted to the namespace if it's connecting, or return them to be sent now.
+    pub fn queue_packets(
+        &mut self,
+        namespace: &str,
+        packets: Vec<WsMessage>,
+        ack: Option<u64>,
+    ) -> Option<Vec<WsMessage>> {
+        match self.namespaces.get_mut(namespace) {
+            Some(ns) if ns.state == NamespaceState::Connecting => {
+                ns.queued.push((packets, ack));
+                None
+            }
+            _ => Some(packets),
+        }
+    }
+
+    /// Returns the packets queued while connecting, to be sent now.
+    pub fn set_connected(
+        &mut self,
+        namespace: &str,
+        sid: Option<String>,
+    ) -> Result<Vec<Vec<WsMessage>>, Error> {
+        let ns = self.get_or_create_namespace(namespace)?;
+        ns.state = NamespaceState::Connected;
+        ns.sid = sid;
+        for waiter in ns.connect_waiters.drain(..) {
+            let _ = waiter.send(Ok(()));
+        }
+        Ok(ns.queued.drain(..).map(|(packets, _)| packets).collect())
+    }
+
+    pub fn set_connect_refused(&mut self, namespace: &str, message: &str) {
+        if let Some(ns) = self.namespaces.get_mut(namespace) {
+            ns.disconnect();
+            for waiter in ns.connect_waiters.drain(..) {
+                let _ = waiter.send(Err(message.to_string()));
+            }
+        }
+    }
+
+    pub fn set_disconnected(&mut self, namespace: &str) {
+        if let Some(ns) = self.namespaces.get_mut(namespace) {
+            ns.disconnect();
+        }
+    }
+
+    /// The namespaces that are connected, sorted.
+    pub fn connected_namespaces(&self) -> Vec<String> {
+        let mut connected: Vec<_> = self
+            .namespaces
+            .iter()
+            .filter(|(_, ns)| ns.state == NamespaceState::Connected)
+            .map(|(name, _)| name.clone())
+            .collect();
+        connected.sort();
+        connected
+    }
+
+    pub fn get_sid(&self, namespace: &str) -> Option<String> {
+        self.namespaces.get(namespace).and_then(|ns| ns.sid.clone())
+    }
+
+    /// Mark every namespace as disconnected, dropping anything waiting for a namespace to connect.
+    /// Returns the namespaces that were connected or connecting, i.e. that were joined and not
+    /// left or refused, sorted.
+    pub fn disconnect_all(&mut self) -> Vec<String> {
+        let mut joined = Vec::new();
+        for (name, ns) in self.namespaces.iter_mut() {
+            if ns.state != NamespaceState::Disconnected {
+                joined.push(name.clone());
+            }
+            ns.disconnect();
+            ns.connect_waiters.clear();
+        }
+        joined.sort();
+        joined
+    }
+
+    pub fn get_global_fallback(&self) -> Option<GlobalFallbackCallback> {
+        self.global_fallback.clone()
+    }
+
+    pub fn set_global_fallback(&mut self, callback: impl Into<GlobalFallbackCallback>) {
+        self.global_fallback = Some(callback.into());
+    }
+
+    pub fn clear_global_fallback(&mut self) {
+        self.global_fallback = None;
+    }
+
+    pub fn get_error(&self) -> Option<ErrorCallback> {
+        self.error.clone()
+    }
+
+    pub fn set_error(&mut self, callback: impl Into<ErrorCallback>) {
+        self.error = Some(callback.into());
+    }
+
+    pub fn clear_error(&mut self) {
+        self.error = None;
+    }
+
+    /// Returns a receiver for every change in the connection's status from now on.
+    pub fn subscribe_status(&mut self) -> mpsc::UnboundedReceiver<ConnectionStatus> {
+        let (tx, rx) = mpsc::unbounded();
+        self.status.push(tx);
+        rx
+    }
+
+    /// Send `status` to every subscriber, forgetting the ones whose receiver was dropped.
+    pub fn send_status(&mut self, status: ConnectionStatus) {
+        self.status
+            .retain(|subscriber| subscriber.unbounded_send(status.clone()).is_ok());
+    }
+
+    /// Returns a receiver for events without a callback, replacing any previous receiver.
+    pub fn subscribe_events(&mut self) -> mpsc::UnboundedReceiver<IncomingEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.events = Some(tx);
+        rx
+    }
+
+    pub fn get_events(&self) -> Option<mpsc::UnboundedSender<IncomingEvent>> {
+        self.events.clone()
+    }
+
+    /// Fails if the namespace doesn't exist and there are already `max_namespaces` namespaces.
+    fn get_or_create_namespace(&mut self, namespace: &str) -> Result<&mut Namespace, Error> {
+        let len = self.namespaces.len();
+        match self.namespaces.entry(namespace.to_string()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(_) if len >= self.max_namespaces => {
+                Err(Error::TooManyNamespaces(self.max_namespaces))
+            }
+            Entry::Vacant(entry) => Ok(entry.insert(Namespace::new())),
+        }
+    }
+}
+
+/// Pass `error` to the error callback if there is one, otherwise log it.
+pub fn report_error(callbacks: &Mutex<Callbacks>, error: Error) {
+    let cb = callbacks.lock().unwrap().get_error();
+    match cb {
+        Some(mut cb) => cb.call(&error),
+        None => log::warn!("{}", error),
     }
 }
 
@@ -144,7 +672,28 @@ impl Namespace {
         Namespace {
             fallback: None,
             events: HashMap::new(),
+            transformers: HashMap::new(),
             acks: HashMap::new(),
+            connect: None,
+            connect_error: None,
+            disconnect: None,
+            state: NamespaceState::Disconnected,
+            queued: Vec::new(),
+            sid: None,
+            connect_packet: None,
+            connect_waiters: Vec::new(),
+        }
+    }
+
+    /// Drop the namespace's connection state, including queued packets, which won't be sent.
+    /// Their acks are removed, so futures waiting on them resolve as disconnected.
+    fn disconnect(&mut self) {
+        self.state = NamespaceState::Disconnected;
+        self.sid = None;
+        for (_, ack) in self.queued.drain(..) {
+            if let Some(id) = ack {
+                self.acks.remove(&id);
+            }
         }
     }
 }
@@ -160,9 +709,9 @@ mod tests {
         let c0: EventCallback = (|_args: &Args, _ack| {}).into();
         let c1: EventCallback = (|_args: &Args, _ack| {}).into();
         let c2: AckCallback = (|_args: &Args| {}).into();
-        callbacks.set_event("/", "msg", c0.clone());
-        callbacks.set_fallback("/", c1.clone());
-        callbacks.set_ack("/", 0, c2);
+        callbacks.set_event("/", "msg", c0.clone()).unwrap();
+        callbacks.set_fallback("/", c1.clone()).unwrap();
+        callbacks.set_ack("/", 0, c2, None).unwrap();
 
         assert!(Arc::ptr_eq(
             &callbacks.get_event("/", "msg").as_ref().unwrap().0,
@@ -173,7 +722,172 @@ mod tests {
             &c1.0
         ));
         assert!(callbacks.get_event("/ns", "msg").is_none());
-        assert!(callbacks.get_and_clear_ack("/", 0).is_some());
-        assert!(callbacks.get_and_clear_ack("/", 0).is_none());
+        assert!(matches!(
+            callbacks.get_and_clear_ack("/", 0),
+            TakenAck::Pending(_)
+        ));
+        assert!(matches!(
+            callbacks.get_and_clear_ack("/", 0),
+            TakenAck::Unknown
+        ));
+
+        callbacks.set_ack("/", 1, |_args: &Args| {}, None).unwrap();
+        callbacks
+            .set_ack("/ns", 1, |_args: &Args| {}, None)
+            .unwrap();
+        let mut acks: Vec<_> = callbacks
+            .take_acks()
+            .into_iter()
+            .map(|(ns, id, _)| (ns, id))
+            .collect();
+        acks.sort();
+        assert_eq!(acks, vec![("/".to_string(), 1), ("/ns".to_string(), 1)]);
+        assert!(callbacks.take_acks().is_empty());
+    }
+
+    #[test]
+    fn test_clear_namespace() {
+        let mut callbacks = Callbacks::new();
+        for namespace in &["/", "/ns"] {
+            callbacks
+                .set_event(namespace, "event", |_args: &Args, _ack| {})
+                .unwrap();
+            callbacks
+                .set_ack(namespace, 0, |_args: &Args| {}, None)
+                .unwrap();
+        }
+
+        callbacks.set_ack("/", 2, |_args: &Args| {}, None).unwrap();
+        assert_eq!(callbacks.pending_ack_ids("/"), vec![0, 2]);
+        assert_eq!(callbacks.pending_acks(), 3);
+        callbacks.clear_acks("/");
+        assert!(callbacks.pending_ack_ids("/").is_empty());
+        assert_eq!(callbacks.pending_acks(), 1);
+        assert!(callbacks.get_event("/", "event").is_some());
+        callbacks.clear_namespace("/ns");
+        assert!(callbacks.get_event("/ns", "event").is_none());
+        assert!(callbacks.take_acks().is_empty());
+        assert_eq!(callbacks.namespaces.len(), 1);
+
+        // Acks dropped by either are ignored if they're received late
+        for namespace in &["/", "/ns"] {
+            assert!(matches!(
+                callbacks.get_and_clear_ack(namespace, 0),
+                TakenAck::Expired
+            ));
+        }
+    }
+
+    #[test]
+    fn test_expire_ack() {
+        let mut callbacks = Callbacks::new();
+        let now = Instant::now();
+        let later = now + std::time::Duration::from_secs(60);
+        callbacks
+            .set_ack("/", 0, |_args: &Args| {}, Some(now))
+            .unwrap();
+        callbacks
+            .set_ack("/", 1, |_args: &Args| {}, Some(later))
+            .unwrap();
+        callbacks.set_ack("/", 2, |_args: &Args| {}, None).unwrap();
+
+        assert!(callbacks.expire_ack("/", 0));
+        assert!(!callbacks.expire_ack("/", 0));
+        assert!(!callbacks.expire_ack("/", 1));
+        assert!(!callbacks.expire_ack("/", 2));
+        assert!(!callbacks.expire_ack("/ns", 0));
+
+        assert!(matches!(
+            callbacks.get_and_clear_ack("/", 0),
+            TakenAck::Expired
+        ));
+        // Only the first late ack is expected
+        assert!(matches!(
+            callbacks.get_and_clear_ack("/", 0),
+            TakenAck::Unknown
+        ));
+        assert!(matches!(
+            callbacks.get_and_clear_ack("/", 1),
+            TakenAck::Pending(_)
+        ));
+
+        callbacks
+            .set_ack("/", 3, |_args: &Args| {}, Some(now))
+            .unwrap();
+        assert!(callbacks.expire_ack("/", 3));
+        assert_eq!(callbacks.take_acks().len(), 1);
+        // Forgotten once the session ends
+        callbacks.clear_late_acks();
+        assert!(matches!(
+            callbacks.get_and_clear_ack("/", 3),
+            TakenAck::Unknown
+        ));
+
+        // Only the most recent are remembered
+        for id in 0..MAX_LATE_ACKS as u64 + 1 {
+            callbacks
+                .set_ack("/", id, |_args: &Args| {}, Some(now))
+                .unwrap();
+            assert!(callbacks.expire_ack("/", id));
+        }
+        assert_eq!(callbacks.late_acks.len(), MAX_LATE_ACKS);
+        assert!(matches!(
+            callbacks.get_and_clear_ack("/", 0),
+            TakenAck::Unknown
+        ));
+        assert!(matches!(
+            callbacks.get_and_clear_ack("/", 1),
+            TakenAck::Expired
+        ));
+    }
+
+    #[test]
+    fn test_wait_connected() {
+        let mut callbacks = Callbacks::new();
+
+        let mut w0 = callbacks.wait_connected("/ns").unwrap();
+        let mut w1 = callbacks.wait_connected("/ns").unwrap();
+        assert_eq!(w0.try_recv(), Ok(None));
+        callbacks
+            .set_connected("/ns", Some("abc".to_string()))
+            .unwrap();
+        assert_eq!(callbacks.get_sid("/ns"), Some("abc".to_string()));
+        assert_eq!(w0.try_recv(), Ok(Some(Ok(()))));
+        assert_eq!(w1.try_recv(), Ok(Some(Ok(()))));
+        let mut w2 = callbacks.wait_connected("/ns").unwrap();
+        assert_eq!(w2.try_recv(), Ok(Some(Ok(()))));
+
+        callbacks.set_disconnected("/ns");
+        assert_eq!(callbacks.get_sid("/ns"), None);
+        let mut w3 = callbacks.wait_connected("/ns").unwrap();
+        callbacks.set_connect_refused("/ns", "refused");
+        assert_eq!(w3.try_recv(), Ok(Some(Err("refused".to_string()))));
+
+        let mut w4 = callbacks.wait_connected("/ns").unwrap();
+        callbacks.set_connected("/other", None).unwrap();
+        assert_eq!(callbacks.disconnect_all(), vec!["/other".to_string()]);
+        assert!(w4.try_recv().is_err());
+        assert!(callbacks.disconnect_all().is_empty());
+    }
+
+    #[test]
+    fn test_max_namespaces() {
+        let mut callbacks = Callbacks::with_max_namespaces(2);
+
+        callbacks
+            .set_fallback("/", |_args: &Args, _ack| {})
+            .unwrap();
+        callbacks.set_connected("/a", None).unwrap();
+        assert!(matches!(
+            callbacks.set_connected("/b", None),
+            Err(Error::TooManyNamespaces(2))
+        ));
+        assert!(callbacks.set_ack("/b", 0, |_args: &Args| {}, None).is_err());
+        assert!(callbacks.wait_connected("/b").is_err());
+        // Existing namespaces can still be used
+        callbacks.set_ack("/a", 0, |_args: &Args| {}, None).unwrap();
+        callbacks
+            .set_event("/", "msg", |_args: &Args, _ack| {})
+            .unwrap();
     }
 }
@@ -1,98 +1,323 @@
 use std::{
-    sync::{Arc, Mutex},
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
     time::Duration,
 };
 
-use async_tungstenite::{async_tls, tungstenite::Message as WsMessage, WebSocketStream};
+use async_tungstenite::{
+    async_tls,
+    tungstenite::{Error as WsError, Message as WsMessage},
+    WebSocketStream,
+};
 use futures::{
     channel::{mpsc, oneshot},
-    future::{FutureExt, RemoteHandle},
+    future::{self, BoxFuture, Fuse, FutureExt, RemoteHandle},
     io::{AsyncRead, AsyncWrite},
     pin_mut, select,
-    sink::SinkExt,
-    stream::StreamExt,
-    task::{Spawn, SpawnError, SpawnExt},
+    sink::{Sink, SinkExt},
+    stream::{FuturesUnordered, Stream, StreamExt},
+    task::{Spawn, SpawnExt},
 };
 use futures_timer::Delay;
 use url::Url;
 
-use socket_io_protocol::engine;
+use socket_io_protocol::engine::{self, EngineVersion};
+
+use super::{
+    connect_packet, disconnect_packet, polling, receiver,
+    trace::{self, Instrument},
+    Callbacks, ClientOptions, CloseReason, ConnectionStatus, DisconnectReason, Error, Receiver,
+    Transport as TransportKind,
+};
+
+/// Opens a new stream to the server for each reconnect attempt, and for each request when
+/// polling.
+pub type Connector<S> = Arc<dyn Fn(&Url) -> BoxFuture<'static, Result<S, Error>> + Send + Sync>;
+
+/// The engine.io packets of a session, as the messages they're sent as over a websocket.
+pub(crate) trait Transport:
+    Stream<Item = Result<WsMessage, Error>> + Sink<WsMessage, Error = Error> + Send + Unpin
+{
+}
+
+impl<T> Transport for T where
+    T: Stream<Item = Result<WsMessage, Error>> + Sink<WsMessage, Error = Error> + Send + Unpin
+{
+}
+
+type BoxTransport = Box<dyn Transport>;
+
+/// Sends packets to a connection's task, shared by the client, its acks and the receiver.  Every
+/// batch sent counts towards the limit set by `ClientOptions::send_buffer` until the task takes
+/// it, but only `send` waits for room, so packets the client can't wait to send (e.g. pongs and
+/// acks) are never held up.
+#[derive(Clone)]
+pub(crate) struct Sender {
+    tx: mpsc::UnboundedSender<Vec<WsMessage>>,
+    buffer: Arc<SendBuffer>,
+}
+
+/// The receiving end of a `Sender`, owned by the connection's task.
+pub(crate) struct SendQueue {
+    rx: mpsc::UnboundedReceiver<Vec<WsMessage>>,
+    buffer: Arc<SendBuffer>,
+}
+
+struct SendBuffer {
+    capacity: Option<usize>,
+    state: Mutex<BufferState>,
+    // The largest message the server accepts, `usize::MAX` if it didn't say
+    max_payload: AtomicUsize,
+}
+
+#[derive(Default)]
+struct BufferState {
+    // Batches sent that the task hasn't taken yet
+    pending: usize,
+    // Set once the queue is dropped, so nothing waits for room that will never come
+    closed: bool,
+    waiters: Vec<Waker>,
+}
+
+/// A channel whose `Sender::send` waits while `capacity` batches are pending, if given.
+pub(crate) fn send_channel(capacity: Option<usize>) -> (Sender, SendQueue) {
+    let (tx, rx) = mpsc::unbounded();
+    let buffer = Arc::new(SendBuffer {
+        capacity,
+        state: Mutex::new(BufferState::default()),
+        max_payload: AtomicUsize::new(usize::MAX),
+    });
+    let sender = Sender {
+        tx,
+        buffer: buffer.clone(),
+    };
+    (sender, SendQueue { rx, buffer })
+}
+
+impl Sender {
+    /// Send `packets` without waiting for room.
+    pub fn unbounded_send(&self, packets: Vec<WsMessage>) -> Result<(), Error> {
+        // Counted before sending, so the task can't take the batch before it's counted
+        self.buffer.state.lock().unwrap().pending += 1;
+        self.tx.unbounded_send(packets).map_err(|_| {
+            self.buffer.release();
+            Error::Disconnected
+        })
+    }
+
+    /// Wait until there's room for another batch, failing with `Error::Disconnected` if the
+    /// connection's task has ended.
+    pub async fn ready(&self) -> Result<(), Error> {
+        future::poll_fn(|cx| self.buffer.poll_ready(cx)).await
+    }
+
+    /// Set the largest message the server accepts, from the `maxPayload` in its open packet.
+    pub fn set_max_payload(&self, limit: Option<usize>) {
+        let limit = limit.unwrap_or(usize::MAX);
+        self.buffer.max_payload.store(limit, Ordering::SeqCst);
+    }
+
+    /// Fail with `Error::PayloadTooLarge` if any of `packets` is larger than the server accepts,
+    /// since it would close the connection if they were sent.
+    pub fn check_payload(&self, packets: &[WsMessage]) -> Result<(), Error> {
+        let limit = self.buffer.max_payload.load(Ordering::SeqCst);
+        match packets
+            .iter()
+            .map(WsMessage::len)
+            .find(|&size| size > limit)
+        {
+            Some(size) => Err(Error::PayloadTooLarge { size, limit }),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a batch sent now would fit in the buffer.
+    pub fn has_room(&self) -> bool {
+        self.buffer
+            .state
+            .lock()
+            .unwrap()
+            .has_room(self.buffer.capacity)
+    }
+}
+
+impl SendQueue {
+    pub async fn next(&mut self) -> Option<Vec<WsMessage>> {
+        let packets = self.rx.next().await;
+        if packets.is_some() {
+            self.buffer.release();
+        }
+        packets
+    }
+
+    pub fn try_recv(&mut self) -> Option<Vec<WsMessage>> {
+        let packets = self.rx.try_recv().ok();
+        if packets.is_some() {
+            self.buffer.release();
+        }
+        packets
+    }
+}
 
-use super::{Callbacks, Error, Receiver};
+impl Drop for SendQueue {
+    fn drop(&mut self) {
+        let mut state = self.buffer.state.lock().unwrap();
+        state.closed = true;
+        state.waiters.drain(..).for_each(Waker::wake);
+    }
+}
+
+impl SendBuffer {
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            Poll::Ready(Err(Error::Disconnected))
+        } else if state.has_room(self.capacity) {
+            Poll::Ready(Ok(()))
+        } else {
+            state.waiters.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pending = state.pending.saturating_sub(1);
+        state.waiters.drain(..).for_each(Waker::wake);
+    }
+}
+
+impl BufferState {
+    #[allow(clippy::unnecessary_map_or)] // `Option::is_none_or` needs Rust 1.82
+    fn has_room(&self, capacity: Option<usize>) -> bool {
+        capacity.map_or(true, |capacity| self.pending < capacity)
+    }
+}
 
 pub struct Connection {
-    handle: Option<RemoteHandle<Result<(), Error>>>,
+    handle: Option<RemoteHandle<Result<Option<CloseReason>, Error>>>,
     close: Option<oneshot::Sender<()>>,
-    sid: String,
-    send: mpsc::UnboundedSender<Vec<WsMessage>>,
+    // The open packet of the current session, which changes when reconnecting
+    open: Arc<Mutex<Option<engine::Open>>>,
+    // The transport of the current session, which can change when reconnecting
+    transport: Arc<Mutex<TransportKind>>,
+    send: Sender,
+    timers: mpsc::UnboundedSender<BoxFuture<'static, ()>>,
     timeout: Duration,
+    // Cleared while there's no open session, and once the websocket task finishes
+    connected: Arc<AtomicBool>,
 }
 
 impl Connection {
+    /// Connect over `connection`.  If `connector` is given and `options` enable reconnecting,
+    /// it's used to open new streams when the connection is lost.
     pub async fn new<S>(
-        mut url: Url,
+        url: Url,
+        options: &ClientOptions,
         connection: S,
+        connector: Option<Connector<S>>,
         sid: Option<&str>,
         callbacks: Arc<Mutex<Callbacks>>,
-        timeout: Duration,
         spawn: &impl Spawn,
     ) -> Result<Connection, Error>
     where
         S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
     {
-        if let Some(sid) = sid {
-            url.query_pairs_mut().append_pair("sid", sid);
-        }
-        let timeout_fut = Delay::new(timeout).fuse();
-
-        let client = async_tls::client_async_tls(url.to_string(), connection).fuse();
-        pin_mut!(client);
-        pin_mut!(timeout_fut);
-
-        let client = select! {
-            c = client => c.map(|(c, _)| c).map_err(|e| e.into()),
-            _ = timeout_fut => Err(Error::Timeout("websocket handshake")),
-        }?;
+        let span = trace::connection(&url);
+        let timeout = options.get_timeout();
+        let mut timeout_fut = Delay::new(timeout).fuse();
+        let (client, kind) = handshake(
+            &url,
+            options,
+            connection,
+            connector.clone(),
+            sid,
+            &mut timeout_fut,
+        )
+        .instrument(span.clone())
+        .await?;
 
-        let (send_tx, send_rx) = mpsc::unbounded();
+        let (send_tx, send_rx) = send_channel(options.get_send_buffer());
         let (close_tx, close_rx) = oneshot::channel();
         let (open_tx, open_rx) = oneshot::channel();
+        let connected = Arc::new(AtomicBool::new(false));
+        let open = Arc::new(Mutex::new(None));
+        let transport = Arc::new(Mutex::new(kind));
 
-        let handle = process_websocket(
-            client,
-            send_tx.clone(),
+        let task = Task {
+            url,
+            options: options.clone(),
+            connector,
+            send_tx: send_tx.clone(),
             send_rx,
-            close_rx,
-            open_tx,
+            close: close_rx.fuse(),
+            first_open: Some(open_tx),
+            open: open.clone(),
+            transport: transport.clone(),
             callbacks,
-            spawn,
-        )
-        .await?;
+            connected: connected.clone(),
+            workers: receiver::spawn_workers(options.get_event_workers(), spawn)?,
+            timeout,
+        };
+        let handle = spawn.spawn_with_handle(task.run(client).instrument(span))?;
+        let (timers_tx, timers_rx) = mpsc::unbounded();
+        spawn.spawn(run_timers(timers_rx))?;
 
-        let open = select! {
-            open = open_rx.fuse() => Ok(open.unwrap()),
-            _ = timeout_fut => Err(Error::Timeout("engine.io protocol Open message")),
-        }?;
-        log::trace!("Received open: {:?}", open);
+        let first_open = select! {
+            open = open_rx.fuse() => open.ok(),
+            _ = timeout_fut => return Err(Error::Timeout("engine.io protocol Open message")),
+        };
+        if first_open.is_none() {
+            // The task dropped the sender, so it's finished and the handle resolves immediately
+            let error = handle.await.err().map(Box::new);
+            return Err(Error::ClosedDuringHandshake(error));
+        }
 
         Ok(Connection {
             handle: Some(handle),
             close: Some(close_tx),
-            sid: open.sid,
+            open,
+            transport,
             send: send_tx,
+            timers: timers_tx,
             timeout,
+            connected,
         })
     }
 
-    pub fn sid(&self) -> &str {
-        &*self.sid
+    /// The open packet of the current session, or of the last session while reconnecting.
+    pub fn open(&self) -> engine::Open {
+        self.open.lock().unwrap().clone().unwrap()
+    }
+
+    /// The transport of the current session, which is a websocket once a polling session is
+    /// upgraded.
+    pub fn transport(&self) -> TransportKind {
+        *self.transport.lock().unwrap()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.handle.is_some() && self.connected.load(Ordering::SeqCst)
     }
 
-    pub fn sender(&self) -> mpsc::UnboundedSender<Vec<WsMessage>> {
+    pub fn sender(&self) -> Sender {
         self.send.clone()
     }
 
-    pub async fn close(&mut self) -> Result<(), Error> {
+    /// Run `timer` on the connection's timer task, which outlives the connection's websocket
+    /// task and ends once the `Connection` is dropped.
+    pub fn spawn_timer(&self, timer: BoxFuture<'static, ()>) {
+        let _ = self.timers.unbounded_send(timer);
+    }
+
+    /// Close the connection, returning the close frame the server sent, if any.
+    pub async fn close(&mut self) -> Result<Option<CloseReason>, Error> {
         if let (Some(handle), Some(close)) = (self.handle.take(), self.close.take()) {
             let timeout = Delay::new(self.timeout);
             let _ = close.send(());
@@ -106,25 +331,446 @@ impl Connection {
     }
 }
 
-async fn process_websocket<S>(
-    stream: WebSocketStream<S>,
-    send_tx: mpsc::UnboundedSender<Vec<WsMessage>>,
-    mut send_rx: mpsc::UnboundedReceiver<Vec<WsMessage>>,
-    close: oneshot::Receiver<()>,
-    open: oneshot::Sender<engine::Open>,
+/// Drive the timers sent to `timers` until the sender is dropped, dropping any still running.
+async fn run_timers(mut timers: mpsc::UnboundedReceiver<BoxFuture<'static, ()>>) {
+    let mut running = FuturesUnordered::new();
+    loop {
+        select! {
+            timer = timers.next() => match timer {
+                Some(timer) => running.push(timer),
+                None => return,
+            },
+            () = running.select_next_some() => (),
+        }
+    }
+}
+
+/// Start a session with the transport set in `options` over `connection`, failing if `timeout`
+/// completes first.  Polling needs `connector` to open connections for later requests, and to
+/// upgrade to a websocket.  Returns the session and the transport it ended up on.
+async fn handshake<S>(
+    url: &Url,
+    options: &ClientOptions,
+    connection: S,
+    connector: Option<Connector<S>>,
+    sid: Option<&str>,
+    timeout: &mut Fuse<Delay>,
+) -> Result<(BoxTransport, TransportKind), Error>
+where
+    S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+{
+    match options.get_transport() {
+        TransportKind::Websocket => {
+            let ws = websocket_handshake(url, options, connection, sid, timeout).await?;
+            Ok((box_websocket(ws), TransportKind::Websocket))
+        }
+        TransportKind::Polling => {
+            let connector = connector.ok_or(Error::PollingWithoutConnector)?;
+            let polling =
+                polling::handshake(url, options, connection, connector.clone(), sid).fuse();
+            pin_mut!(polling);
+            let polling = select! {
+                p = polling => p?,
+                _ = &mut *timeout => return Err(Error::Timeout("polling handshake")),
+            };
+            if !options.get_upgrade() || !polling.can_upgrade() {
+                return Ok((Box::new(polling), TransportKind::Polling));
+            }
+            match upgrade(url, options, polling.sid(), &connector, timeout).await {
+                Ok(ws) => {
+                    let upgraded = Upgraded {
+                        received: polling.into_received(),
+                        ws: box_websocket(ws),
+                    };
+                    Ok((Box::new(upgraded), TransportKind::Websocket))
+                }
+                Err(e) => {
+                    log::debug!("Upgrade to websocket failed, staying on polling: {}", e);
+                    Ok((Box::new(polling), TransportKind::Polling))
+                }
+            }
+        }
+    }
+}
+
+fn box_websocket<S>(ws: WebSocketStream<S>) -> BoxTransport
+where
+    S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+{
+    Box::new(
+        ws.sink_map_err(Error::from)
+            .map(|msg| msg.map_err(Error::from)),
+    )
+}
+
+/// Open a websocket to the polling session `sid` and probe it, following engine.io's upgrade
+/// sequence: the client sends a ping with the payload "probe", the server answers with a pong
+/// with the same payload, then the client sends an upgrade packet and the session continues on
+/// the websocket.  Nothing is in flight on the polling side straight after the handshake, so it
+/// doesn't need to be paused.
+async fn upgrade<S>(
+    url: &Url,
+    options: &ClientOptions,
+    sid: &str,
+    connector: &Connector<S>,
+    timeout: &mut Fuse<Delay>,
+) -> Result<WebSocketStream<async_tls::ClientStream<Recorder<S>>>, Error>
+where
+    S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let stream = select! {
+        stream = connector(url).fuse() => stream?,
+        _ = &mut *timeout => return Err(Error::Timeout("websocket upgrade")),
+    };
+    let options = options.clone().transport(TransportKind::Websocket);
+    let mut ws = websocket_handshake(url, &options, stream, Some(sid), timeout).await?;
+    let probe = async move {
+        ws.send(WsMessage::Text("2probe".to_string())).await?;
+        loop {
+            match ws.next().await.transpose()? {
+                Some(WsMessage::Text(text)) if text == "3probe" => break,
+                Some(WsMessage::Ping(_)) | Some(WsMessage::Pong(_)) => (),
+                msg => return Err(Error::UpgradeProbeFailed(msg)),
+            }
+        }
+        ws.send(WsMessage::Text("5".to_string())).await?;
+        Ok(ws)
+    }
+    .fuse();
+    pin_mut!(probe);
+    select! {
+        ws = probe => ws,
+        _ = &mut *timeout => Err(Error::Timeout("websocket upgrade probe")),
+    }
+}
+
+/// A session upgraded from polling, which yields the messages polling received before the
+/// upgrade, then continues on the websocket.
+struct Upgraded {
+    received: VecDeque<WsMessage>,
+    ws: BoxTransport,
+}
+
+impl Stream for Upgraded {
+    type Item = Result<WsMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.received.pop_front() {
+            Some(msg) => Poll::Ready(Some(Ok(msg))),
+            None => self.ws.poll_next_unpin(cx),
+        }
+    }
+}
+
+impl Sink<WsMessage> for Upgraded {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.ws.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: WsMessage) -> Result<(), Error> {
+        self.ws.start_send_unpin(msg)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.ws.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.ws.poll_close_unpin(cx)
+    }
+}
+
+/// Open a websocket over `connection`, failing if `timeout` completes first.
+async fn websocket_handshake<S>(
+    url: &Url,
+    options: &ClientOptions,
+    connection: S,
+    sid: Option<&str>,
+    timeout: &mut Fuse<Delay>,
+) -> Result<WebSocketStream<async_tls::ClientStream<Recorder<S>>>, Error>
+where
+    S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut url = url.clone();
+    options.set_socket_io_path(&mut url);
+    options.add_query_params(&mut url, sid);
+
+    let request = options.request(&url)?;
+    let (connection, recording) = Recorder::new(connection);
+    let client = async_tls::client_async_tls(request, connection).fuse();
+    pin_mut!(client);
+
+    let client = select! {
+        c = client => c.map(|(c, _)| c).map_err(|e| handshake_error(e, &recording)),
+        _ = &mut *timeout => Err(Error::Timeout("websocket handshake")),
+    }?;
+    recording.lock().unwrap().take();
+    Ok(client)
+}
+
+/// The most handshake response data that's kept to look for a `Retry-After` header.
+const MAX_RECORDING: usize = 16 * 1024;
+
+/// Wraps a stream, keeping a copy of the data read from it until the recording is taken, so the
+/// headers of a failed handshake response can be inspected.  tungstenite only reports the status
+/// code.  With TLS the recording is of encrypted data, so the headers can't be found.
+struct Recorder<S> {
+    inner: S,
+    recording: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl<S> Recorder<S> {
+    fn new(inner: S) -> (Self, Arc<Mutex<Option<Vec<u8>>>>) {
+        let recording = Arc::new(Mutex::new(Some(Vec::new())));
+        let recorder = Recorder {
+            inner,
+            recording: recording.clone(),
+        };
+        (recorder, recording)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorder<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(len)) = result {
+            let mut recording = self.recording.lock().unwrap();
+            if let Some(recording) = recording.as_mut() {
+                let len = std::cmp::min(len, MAX_RECORDING - recording.len());
+                recording.extend_from_slice(&buf[..len]);
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorder<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+fn handshake_error(error: WsError, recording: &Mutex<Option<Vec<u8>>>) -> Error {
+    match error {
+        WsError::Http(status) => {
+            let recording = recording.lock().unwrap().take().unwrap_or_default();
+            Error::HttpHandshake {
+                status: status.as_u16(),
+                retry_after: retry_after(&recording),
+            }
+        }
+        e => e.into(),
+    }
+}
+
+/// Find the `Retry-After` header in an HTTP response, if it's given as a number of seconds.
+pub(crate) fn retry_after(response: &[u8]) -> Option<Duration> {
+    let end = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&response[..end]).ok()?;
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_at(line.find(':')?);
+        if name.trim().eq_ignore_ascii_case("retry-after") {
+            value[1..].trim().parse().ok().map(Duration::from_secs)
+        } else {
+            None
+        }
+    })
+}
+
+/// The state of a connection's websocket task, which outlives each engine.io session when
+/// reconnecting.
+struct Task<S> {
+    url: Url,
+    options: ClientOptions,
+    connector: Option<Connector<S>>,
+    send_tx: Sender,
+    send_rx: SendQueue,
+    close: Fuse<oneshot::Receiver<()>>,
+    // Notified when the first session opens, which `Connection::new` waits for
+    first_open: Option<oneshot::Sender<()>>,
+    open: Arc<Mutex<Option<engine::Open>>>,
+    transport: Arc<Mutex<TransportKind>>,
     callbacks: Arc<Mutex<Callbacks>>,
-    spawn: &impl Spawn,
-) -> Result<RemoteHandle<Result<(), Error>>, SpawnError>
+    connected: Arc<AtomicBool>,
+    workers: Vec<mpsc::UnboundedSender<receiver::Job>>,
+    timeout: Duration,
+}
+
+/// How a session ended without an error.
+/// Both hold the close frame the server sent, if any.
+enum SessionEnd {
+    /// The client closed the connection.
+    Closed(Option<CloseReason>),
+    /// The server closed the connection, or the stream ended.
+    Lost(Option<CloseReason>),
+}
+
+impl SessionEnd {
+    fn close_reason(self) -> Option<CloseReason> {
+        match self {
+            SessionEnd::Closed(reason) | SessionEnd::Lost(reason) => reason,
+        }
+    }
+}
+
+impl<S> Task<S>
 where
-    S: 'static + Unpin + AsyncRead + AsyncWrite + Send,
+    S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
 {
-    let (mut sink, mut stream) = stream.split();
-    let mut receiver = Receiver::new(send_tx.clone(), callbacks, open);
+    #[allow(clippy::unnecessary_map_or)] // `Option::is_some_and` needs Rust 1.70
+    async fn run(mut self, mut stream: BoxTransport) -> Result<Option<CloseReason>, Error> {
+        let mut attempt = 0;
+        // Connected again once a new session opens
+        let mut namespaces = Vec::new();
+        loop {
+            let span = trace::session();
+            let result = self
+                .session(stream, &namespaces, &span)
+                .instrument(span.clone())
+                .await;
+            let opened = self.connected.load(Ordering::SeqCst);
+            let connected = self.end_session();
+            if opened {
+                attempt = 0;
+                namespaces = connected;
+            }
+            let config = match (&result, &self.connector, self.options.get_reconnect()) {
+                (Ok(SessionEnd::Closed(_)), _, _) => return result.map(SessionEnd::close_reason),
+                // Reconnecting wouldn't help, as nothing can be sent on the new connection either
+                (Err(Error::SendChannelClosed), _, _) => {
+                    return result.map(SessionEnd::close_reason)
+                }
+                // Failing before the first session opens fails `Connection::new`
+                _ if self.first_open.is_some() => return result.map(SessionEnd::close_reason),
+                (_, Some(_), Some(config)) => config.clone(),
+                _ => return result.map(SessionEnd::close_reason),
+            };
+            let mut last = result.map(SessionEnd::close_reason);
+            match &last {
+                Ok(_) if opened => log::info!("Connection lost"),
+                Err(e) if opened => log::info!("Connection lost: {}", e),
+                Ok(_) => log::debug!("Reconnect attempt {} closed before opening", attempt),
+                Err(e) => log::debug!("Reconnect attempt {} failed: {}", attempt, e),
+            }
+            if opened {
+                self.send_status(ConnectionStatus::Disconnected(DisconnectReason::new(&last)));
+            }
+
+            stream = loop {
+                attempt += 1;
+                if config.max_retries.map_or(false, |max| attempt > max) {
+                    log::info!("Giving up reconnecting after {} attempts", attempt - 1);
+                    self.send_status(ConnectionStatus::ReconnectFailed);
+                    return last;
+                }
+                let delay = config.delay(attempt);
+                self.send_status(ConnectionStatus::Reconnecting { attempt, delay });
+                select! {
+                    _ = Delay::new(delay).fuse() => (),
+                    _ = &mut self.close => return Ok(None),
+                }
+                match self.connect().await {
+                    Ok(stream) => break stream,
+                    Err(e) => {
+                        log::debug!("Reconnect attempt {} failed: {}", attempt, e);
+                        last = Err(e);
+                    }
+                }
+            };
+        }
+    }
+
+    /// Open a new stream and start a session over it for a reconnect attempt.
+    async fn connect(&mut self) -> Result<BoxTransport, Error> {
+        let connector = self.connector.as_ref().unwrap();
+        let mut timeout = Delay::new(self.timeout).fuse();
+        let stream = select! {
+            stream = connector(&self.url).fuse() => stream?,
+            _ = timeout => return Err(Error::Timeout("connect")),
+        };
+        let connector = self.connector.clone();
+        let (stream, kind) = handshake(
+            &self.url,
+            &self.options,
+            stream,
+            connector,
+            None,
+            &mut timeout,
+        )
+        .await?;
+        *self.transport.lock().unwrap() = kind;
+        Ok(stream)
+    }
+
+    /// Run an engine.io session over `stream` until it ends.  `namespaces` are connected once
+    /// the session opens.  The session's id is recorded in `span`.
+    async fn session(
+        &mut self,
+        stream: BoxTransport,
+        namespaces: &[String],
+        span: &trace::Span,
+    ) -> Result<SessionEnd, Error> {
+        let Task {
+            options,
+            send_tx,
+            send_rx,
+            close,
+            first_open,
+            open,
+            callbacks,
+            connected,
+            workers,
+            timeout,
+            ..
+        } = self;
+        let (open_tx, mut open_rx) = oneshot::channel();
+        let mut receiver = Receiver::new(
+            send_tx.clone(),
+            callbacks.clone(),
+            open_tx,
+            workers.clone(),
+            options.get_max_attachments(),
+        );
+        let (mut sink, mut stream) = stream.split();
 
-    let task = async move {
         let mut next = stream.next().fuse();
-        let mut closed = close.fuse();
-        loop {
+        let mut opened = false;
+        let mut open_timer = Delay::new(*timeout).fuse();
+        // Started once the open packet gives the ping interval and timeout
+        let mut ping_timer = Fuse::terminated();
+        // In engine.io v3 the client sends pings, and the server's pongs keep the connection alive
+        let mut ping_interval = Duration::default();
+        let mut send_ping = Fuse::terminated();
+        // Set if the connection is shut down because of an error rather than closed by the client
+        let error = loop {
+            // Nothing is sent until the session opens and namespaces are connected again, so
+            // events emitted while reconnecting go to the new session
+            let sending = opened;
+            let outgoing = async {
+                if sending {
+                    send_rx.next().await
+                } else {
+                    future::pending().await
+                }
+            };
             select! {
                 result = next => {
                     let msg = match result {
@@ -134,46 +780,398 @@ where
                         },
                         None => {
                             log::trace!("got None, stream ended");
-                            return Ok(()); // Connection closed without errors
+                            // Connection closed without errors
+                            return Ok(SessionEnd::Lost(receiver.take_close_reason()));
                         }
                     };
                     next = stream.next().fuse();
                     match msg {
                         Ok(msg) => receiver.process_websocket_packet(msg)?,
-                        Err(e) => return Err(e.into()),
+                        Err(e) => return Err(e),
+                    }
+                    if let Ok(Some(packet)) = open_rx.try_recv() {
+                        log::trace!("Received open: {:?}", packet);
+                        trace::record_sid(span, &packet.sid);
+                        if options.get_engine_version() == EngineVersion::V3 {
+                            ping_interval = packet.ping_interval();
+                            send_ping = Delay::new(ping_interval).fuse();
+                        }
+                        *open.lock().unwrap() = Some(packet);
+                        connected.store(true, Ordering::SeqCst);
+                        opened = true;
+                        open_timer = Fuse::terminated();
+                        for namespace in namespaces {
+                            let packet = {
+                                let mut callbacks = callbacks.lock().unwrap();
+                                // It was connected before, so it still fits the namespace limit
+                                let _ = callbacks.set_connecting(namespace);
+                                callbacks.get_connect_packet(namespace)
+                            };
+                            let packet = packet.unwrap_or_else(|| connect_packet(namespace));
+                            sink.send(packet).await?;
+                        }
+                        match first_open.take() {
+                            Some(first) => {
+                                let _ = first.send(());
+                            }
+                            None => callbacks
+                                .lock()
+                                .unwrap()
+                                .send_status(ConnectionStatus::Reconnected),
+                        }
+                    }
+                    if let Some(window) = receiver.take_heartbeat() {
+                        ping_timer = Delay::new(window).fuse();
                     }
                 }
-                result = send_rx.next() => {
+                result = outgoing.fuse() => {
                     let msgs = match result {
                         Some(msg) => msg,
-                        None => panic!("Sending stream closed unexpectedly"),
+                        None => {
+                            // The task holds a sender itself, so this shouldn't happen, but
+                            // nothing more can be sent so shut down cleanly
+                            log::error!("Sending channel closed unexpectedly");
+                            break Some(Error::SendChannelClosed);
+                        }
                     };
+                    // Flushed together so polling sends them in one request
                     for msg in msgs.into_iter() {
                         log::trace!("Sending websocket packet: {:?}", msg);
-                        match sink.send(msg).await {
-                            Ok(()) => (),
-                            Err(e) => return Err(e.into()),
-                        }
+                        sink.feed(msg).await?;
                     }
+                    sink.flush().await?;
                 }
-                _ = closed => {
+                _ = &mut *close => {
+                    break None;
+                }
+                _ = send_ping => {
+                    log::trace!("Sending engine.io ping");
+                    sink.send(engine::encode_ping()).await?;
+                    send_ping = Delay::new(ping_interval).fuse();
+                }
+                _ = ping_timer => {
+                    log::debug!("No ping received from the server in time");
+                    return Err(Error::PingTimeout);
+                }
+                _ = open_timer => {
+                    return Err(Error::Timeout("engine.io protocol Open message"));
+                }
+            }
+        };
+        drop(next);
+        if error.is_none() && opened {
+            // Send what was emitted before closing, then leave each namespace so the server runs
+            // its disconnect handlers without waiting for the connection to close
+            let mut msgs = Vec::new();
+            while let Some(packets) = send_rx.try_recv() {
+                msgs.extend(packets);
+            }
+            let namespaces = callbacks.lock().unwrap().connected_namespaces();
+            msgs.extend(
+                namespaces
+                    .iter()
+                    .map(|namespace| disconnect_packet(namespace)),
+            );
+            for msg in msgs {
+                log::trace!("Sending websocket packet: {:?}", msg);
+                if let Err(e) = sink.send(msg).await {
+                    log::debug!("Failed to send packet while closing: {}", e);
                     break;
                 }
             }
         }
-        drop(next);
         let mut ws_stream = sink.reunite(stream).expect("Reunite should succeed");
+        if options.get_send_close_packet() {
+            log::debug!("Sending engine.io close packet");
+            if let Err(e) = ws_stream.send(engine::encode_close()).await {
+                log::debug!("Failed to send close packet: {}", e);
+            }
+        }
         log::debug!("Sending close message");
-        let _ = ws_stream.close(None).await;
+        let _ = ws_stream.close().await;
         // Now we want to keep reading until the stream closed
         loop {
             match ws_stream.next().await {
                 Some(Ok(msg)) => receiver.process_websocket_packet(msg)?,
-                Some(Err(e)) => return Err(e.into()),
-                None => return Ok(()), // Connection closed without errors
+                Some(Err(e)) => return Err(e),
+                // Connection closed without errors
+                None => {
+                    let reason = receiver.take_close_reason();
+                    return error.map_or(Ok(SessionEnd::Closed(reason)), Err);
+                }
             }
         }
-    };
+    }
+
+    /// Clean up after a session ends, returning the namespaces to connect again, including those
+    /// still waiting for the server to accept them.
+    fn end_session(&mut self) -> Vec<String> {
+        self.connected.store(false, Ordering::SeqCst);
+        // Acks can't be received once the session is gone, so cancel any that are pending
+        let mut callbacks = self.callbacks.lock().unwrap();
+        let acks = callbacks.take_acks();
+        drop(acks);
+        callbacks.clear_late_acks();
+        callbacks.clear_pings();
+        callbacks.disconnect_all()
+    }
+
+    fn send_status(&self, status: ConnectionStatus) {
+        self.callbacks.lock().unwrap().send_status(status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{harness, protocol, spawn::TokioSpawner, Client, ReconnectConfig};
+
+    #[test]
+    fn test_retry_after() {
+        let response =
+            b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nretry-after:  30 \r\n\r\n";
+        assert_eq!(retry_after(response), Some(Duration::from_secs(30)));
+        let response = b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: Wed, 21 Oct 2015 07:28:00 GMT\r\n\r\n";
+        assert_eq!(retry_after(response), None);
+        let response = b"HTTP/1.1 429 Too Many Requests\r\n\r\n";
+        assert_eq!(retry_after(response), None);
+        // Incomplete response
+        assert_eq!(
+            retry_after(b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 30"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_buffer() {
+        let (sender, mut queue) = send_channel(Some(1));
+        let packet = || vec![WsMessage::Text("42[\"event\"]".to_string())];
+        assert!(sender.has_room());
+        sender.unbounded_send(packet()).unwrap();
+        assert!(!sender.has_room());
+        // Only waiting for room is limited, sending without waiting still goes over the limit
+        sender.unbounded_send(packet()).unwrap();
 
-    spawn.spawn_with_handle(task)
+        let ready = sender.ready();
+        pin_mut!(ready);
+        assert!(ready.as_mut().now_or_never().is_none());
+        queue.next().await.unwrap();
+        assert!(!sender.has_room());
+        queue.next().await.unwrap();
+        ready.await.unwrap();
+
+        sender.unbounded_send(packet()).unwrap();
+        let ready = sender.ready();
+        drop(queue);
+        assert!(matches!(ready.await, Err(Error::Disconnected)));
+        assert!(matches!(
+            sender.unbounded_send(packet()),
+            Err(Error::Disconnected)
+        ));
+
+        // Ack futures wait for room too, sending their event once there is
+        let options = ClientOptions::new().send_buffer(1);
+        let (mut client, mut server) = harness::connect_with_options(options).await;
+        let first = client.emit("first").args().send_with_ack();
+        // The task hasn't taken the first event yet, so there's no room for the second
+        let second = client.emit("second").args().send_with_ack();
+        assert_eq!(server.recv_text().await, r#"420["first"]"#);
+        server.send("430[]").await;
+        first.await.unwrap();
+        client.emit("third").args().send().await.unwrap();
+        assert_eq!(server.recv_text().await, r#"42["third"]"#);
+        let (second, ()) = future::join(second, async {
+            assert_eq!(server.recv_text().await, r#"421["second"]"#);
+            server.send("431[]").await;
+        })
+        .await;
+        second.unwrap();
+
+        // Volatile events fail rather than waiting
+        let fourth = client.emit("fourth").args().send_with_ack();
+        let volatile = client.emit("volatile").volatile(true).args();
+        assert!(matches!(
+            volatile.send_with_ack().await,
+            Err(Error::SendBufferFull)
+        ));
+        assert_eq!(server.recv_text().await, r#"422["fourth"]"#);
+        server.send("432[]").await;
+        fourth.await.unwrap();
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_ping_timeout() {
+        let open = r#"0{"sid":"abc","upgrades":[],"pingInterval":50,"pingTimeout":50}"#;
+        let (client, server) = harness::duplex();
+        let (client, mut server) = future::join(
+            Client::from_stream("http://localhost/", client, &TokioSpawner),
+            harness::Server::accept_with_open(server, open),
+        )
+        .await;
+        let mut client = client.unwrap();
+
+        // Pings keep the connection alive past the window
+        for _ in 0..3 {
+            Delay::new(Duration::from_millis(60)).await;
+            server.send("2").await;
+            assert_eq!(server.recv_text().await, "3");
+        }
+        assert!(client.is_connected());
+
+        Delay::new(Duration::from_millis(150)).await;
+        assert!(!client.is_connected());
+        assert!(matches!(client.close().await, Err(Error::PingTimeout)));
+    }
+
+    #[tokio::test]
+    async fn test_close_reason() {
+        use async_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+        let (mut client, server) = harness::connect().await;
+        server
+            .close_with(Some(CloseFrame {
+                code: CloseCode::Policy,
+                reason: "go away".into(),
+            }))
+            .await;
+        let reason = client.close().await.unwrap().unwrap();
+        assert_eq!(reason.code, 1008);
+        assert_eq!(reason.reason, "go away");
+
+        // Closing from the client gets the server's reply, which has no frame here
+        let (mut client, server) = harness::connect().await;
+        let (result, _) = future::join(client.close(), async move {
+            let mut server = server;
+            server.closed().await
+        })
+        .await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_engine_v3() {
+        let open = r#"0{"sid":"abc","upgrades":[],"pingInterval":50,"pingTimeout":50}"#;
+        let options = ClientOptions::new().engine_version(EngineVersion::V3);
+        let (client, server) = harness::duplex();
+        let (client, mut server) = future::join(
+            Client::from_stream_with_options("http://localhost/", options, client, &TokioSpawner),
+            harness::Server::accept_with_open(server, open),
+        )
+        .await;
+        let mut client = client.unwrap();
+        assert_eq!(server.path, "/socket.io/?EIO=3&transport=websocket");
+
+        // The client pings, and pongs keep the connection alive past the window
+        for _ in 0..3 {
+            assert_eq!(server.recv_text().await, "2");
+            server.send("3").await;
+        }
+        assert!(client.is_connected());
+
+        // Without pongs the connection times out
+        Delay::new(Duration::from_millis(150)).await;
+        assert!(!client.is_connected());
+        assert!(matches!(client.close().await, Err(Error::PingTimeout)));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        use async_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+        // Each call to the connect closure sends the server end of a new stream
+        let (streams_tx, mut streams) = mpsc::unbounded();
+        let connect = move |_host, _port| {
+            let (client, server) = harness::duplex();
+            streams_tx.unbounded_send(server).unwrap();
+            future::ok::<_, io::Error>(client)
+        };
+        let delay = Duration::from_millis(10);
+        let config = ReconnectConfig {
+            max_retries: Some(2),
+            base_delay: delay,
+            max_delay: delay,
+            jitter: 0.0,
+        };
+        let options = ClientOptions::default().reconnect(config);
+        let (client, mut server) = future::join(
+            Client::connect_reconnecting("http://localhost/", options, connect, &TokioSpawner),
+            async { harness::Server::accept(streams.next().await.unwrap()).await },
+        )
+        .await;
+        let mut client = client.unwrap();
+        let mut status = client.status_changes();
+        let (events_tx, mut events) = mpsc::unbounded();
+        client
+            .set_event_callback("event", move |args: &protocol::Args, _ack| {
+                events_tx.unbounded_send(args.to_string()).unwrap()
+            })
+            .unwrap();
+        let mut auth = HashMap::new();
+        auth.insert("token", "abc");
+        client.connect_namespace_with_auth("/nsp", &auth).unwrap();
+        let auth = r#"40/nsp,{"token":"abc"}"#;
+        assert_eq!(server.recv_text().await, auth);
+        server.send(r#"40/nsp,{"sid":"abc"}"#).await;
+        client
+            .wait_connected("/nsp", Duration::from_secs(5))
+            .await
+            .unwrap();
+        // Not accepted before the connection is lost, but still joined once it's back
+        client.connect_namespace("/chat").unwrap();
+        assert_eq!(server.recv_text().await, "40/chat,");
+
+        drop(server);
+        assert!(matches!(
+            status.next().await,
+            Some(ConnectionStatus::Disconnected(DisconnectReason::Error(_)))
+        ));
+        assert_eq!(
+            status.next().await,
+            Some(ConnectionStatus::Reconnecting { attempt: 1, delay })
+        );
+        assert!(!client.is_connected());
+        // Emitted while reconnecting, so sent once the new session opens
+        client.emit("queued").args().send().await.unwrap();
+
+        let mut server = harness::Server::accept(streams.next().await.unwrap()).await;
+        assert_eq!(status.next().await, Some(ConnectionStatus::Reconnected));
+        assert!(client.is_connected());
+        assert_eq!(server.recv_text().await, "40/chat,");
+        assert_eq!(server.recv_text().await, auth);
+        assert_eq!(server.recv_text().await, r#"42["queued"]"#);
+        // Callbacks set before the reconnect are still called
+        server.send(r#"42["event",1]"#).await;
+        assert_eq!(events.next().await.unwrap(), r#"["event", 1]"#);
+
+        // Attempts that fail don't reset the count, so the client gives up after two
+        server
+            .close_with(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: "restarting".into(),
+            }))
+            .await;
+        let reason = CloseReason {
+            code: 1001,
+            reason: "restarting".to_string(),
+        };
+        assert_eq!(
+            status.next().await,
+            Some(ConnectionStatus::Disconnected(DisconnectReason::Closed(
+                Some(reason)
+            )))
+        );
+        for attempt in 1..=2 {
+            assert_eq!(
+                status.next().await,
+                Some(ConnectionStatus::Reconnecting { attempt, delay })
+            );
+            drop(streams.next().await.unwrap());
+        }
+        assert_eq!(status.next().await, Some(ConnectionStatus::ReconnectFailed));
+        assert!(!client.is_connected());
+        assert!(client.close().await.is_err());
+    }
 }
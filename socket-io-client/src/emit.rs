@@ -1,75 +1,186 @@
+use std::{
+    borrow::Cow,
+    mem,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
 use async_tungstenite::tungstenite::Message as WsMessage;
-use futures::channel::mpsc;
-use serde::Serialize;
 
-use socket_io_protocol::socket::PacketBuilder;
+use futures::{channel::oneshot, future::Future};
+use futures_timer::Delay;
+use serde::{de::DeserializeOwned, Serialize};
+
+use socket_io_protocol::socket::{BinaryMode, PacketBuilder};
 
-use super::{protocol::ArgsError, AckCallback, Client};
+use super::{
+    callbacks::{self, Callbacks},
+    connection::Sender,
+    protocol::{Args, ArgsError, OwnedArgs, PacketPreview},
+    trace, AckCallback, Client, Error, NamespaceState,
+};
 
+/// Builds an event to emit.  Events are sent uncompressed: the websocket implementation doesn't
+/// support the permessage-deflate extension, so it's never negotiated, and there's no per-message
+/// compression to control.  Already compressed data (e.g. media) in binary attachments is sent as
+/// is, without being compressed again.
 pub struct EventBuilder<'a> {
     client: &'a mut Client,
     event: &'a str,
-    namespace: &'a str,
+    namespace: Cow<'a, str>,
     binary: bool,
+    binary_mode: BinaryMode,
     callback: Option<(AckCallback, u64)>,
+    ack_timeout: Option<Duration>,
+    volatile: bool,
+    batch: Option<&'a mut Pending>,
 }
 
 pub struct EventArgsBuilder<'a> {
     client: &'a mut Client,
-    namespace: &'a str,
+    event: &'a str,
+    namespace: Cow<'a, str>,
     callback: Option<(AckCallback, u64)>,
+    ack_timeout: Option<Duration>,
+    volatile: bool,
+    batch: Option<&'a mut Pending>,
     builder: PacketBuilder,
 }
 
+/// Events emitted together with `Client::batch`, which are sent at once when the batch is sent,
+/// with nothing else sent between them.  Acks requested by events in a batch that's dropped
+/// without being sent are cancelled.
+pub struct Batch<'a> {
+    client: &'a mut Client,
+    pending: Pending,
+}
+
+#[derive(Default)]
+struct Pending {
+    packets: Vec<WsMessage>,
+    // The namespaces and ids of the acks the events request
+    acks: Vec<(String, u64)>,
+}
+
 pub struct AckBuilder {
-    send: mpsc::UnboundedSender<Vec<WsMessage>>,
+    send: Sender,
     namespace: String,
     id: u64,
     binary: bool,
+    binary_mode: BinaryMode,
 }
 
 pub struct AckArgsBuilder {
-    send: mpsc::UnboundedSender<Vec<WsMessage>>,
+    send: Sender,
+    namespace: String,
+    id: u64,
     builder: PacketBuilder,
 }
 
 impl<'a> EventBuilder<'a> {
-    pub(crate) fn new(client: &'a mut Client, event: &'a str, namespace: &'a str) -> Self {
+    pub(crate) fn new(
+        client: &'a mut Client,
+        event: &'a str,
+        namespace: impl Into<Cow<'a, str>>,
+    ) -> Self {
         EventBuilder {
             client,
             event,
-            namespace,
+            namespace: namespace.into(),
             binary: false,
+            binary_mode: BinaryMode::default(),
             callback: None,
+            ack_timeout: None,
+            volatile: false,
+            batch: None,
         }
     }
 
+    /// Send the event as a binary packet, in which byte strings and sequences consisting entirely
+    /// of `u8`s (e.g. `Vec<u8>`) are sent as binary attachments.  Use `protocol::NoBinary` to
+    /// keep a sequence of `u8`s as a JSON array.
     pub fn binary(mut self, b: bool) -> Self {
         self.binary = b;
         self
     }
 
+    /// Set which values are sent as attachments if the event is sent as a binary packet.
+    pub fn binary_mode(mut self, mode: BinaryMode) -> Self {
+        self.binary_mode = mode;
+        self
+    }
+
+    /// Stop waiting for the ack after `timeout`.  The callback is then dropped without being
+    /// called, and futures from `EventArgsBuilder::send_with_ack` resolve to `Error::AckTimeout`.
+    /// An ack received after the timeout is ignored.
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// Drop the event instead of queueing it if it can't be written right away, i.e. if the
+    /// connection isn't open (e.g. while reconnecting) or the namespace isn't connected.  For
+    /// frequently updated data such as positions, where a late event is worthless.  If the event
+    /// is dropped its ack callback is dropped too, and futures from
+    /// `EventArgsBuilder::send_with_ack` resolve to `Error::Disconnected`.  If
+    /// `ClientOptions::send_buffer` is full, `EventArgsBuilder::send` and
+    /// `EventArgsBuilder::send_with_ack` fail instead of waiting.
+    pub fn volatile(mut self, volatile: bool) -> Self {
+        self.volatile = volatile;
+        self
+    }
+
     pub fn callback(mut self, c: impl Into<AckCallback>) -> Self {
-        let id = self.client.next_id;
-        self.client.next_id += 1;
+        let id = self.client.allocate_id();
         self.callback = Some((c.into(), id));
         self
     }
 
+    /// Set a callback for the ack which receives the ack's arguments deserialized into `R`.  An
+    /// ack with a single argument is deserialized directly from that argument, while an ack with
+    /// zero or several arguments is deserialized as a sequence, so `R` should be `()` or a tuple.
+    /// If deserialization fails, the callback isn't called and the error is passed to the error
+    /// callback instead.
+    pub fn callback_typed<R, F>(self, callback: F) -> Self
+    where
+        R: DeserializeOwned,
+        F: 'static + Send + FnOnce(R),
+    {
+        let callbacks = self.client.callbacks.clone();
+        self.callback(move |args: &Args| match deserialize_ack(args) {
+            Ok(r) => callback(r),
+            Err(e) => callbacks::report_error(&callbacks, Error::ArgsError(e)),
+        })
+    }
+
     pub fn args(self) -> EventArgsBuilder<'a> {
-        let builder = PacketBuilder::new_event(
+        let mut builder = PacketBuilder::new_event(
             self.event,
-            self.namespace,
+            &*self.namespace,
             self.callback.as_ref().map(|(_, id)| *id),
             self.binary,
         );
+        builder.set_binary_mode(self.binary_mode);
         EventArgsBuilder {
             client: self.client,
+            event: self.event,
             namespace: self.namespace,
             callback: self.callback,
+            ack_timeout: self.ack_timeout,
+            volatile: self.volatile,
+            batch: self.batch,
             builder,
         }
     }
+
+    /// Send the event with `payload` as its only argument, short for
+    /// `.args().arg(payload)?.send().await`.
+    pub async fn emit_one<T>(self, payload: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.args().arg(payload)?.send().await
+    }
 }
 
 impl<'a> EventArgsBuilder<'a> {
@@ -88,45 +199,328 @@ impl<'a> EventArgsBuilder<'a> {
         self.builder.serialize_arg(arg)
     }
 
-    pub fn send(self) {
+    /// Add a signature over the packet as the last argument, e.g. an HMAC for servers that check
+    /// message integrity.  `sign` receives the bytes of the text message as it would be sent
+    /// without the signature, see `PacketBuilder::sign` for exactly what's covered.
+    pub fn sign<T, F>(mut self, sign: F) -> Result<Self, ArgsError>
+    where
+        T: Serialize,
+        F: FnOnce(&[u8]) -> T,
+    {
+        self.builder.sign(sign)?;
+        Ok(self)
+    }
+
+    /// The size the packet will be sent as with the arguments added so far, e.g. to enforce a
+    /// limit on packet size before sending.
+    pub fn preview(&self) -> PacketPreview {
+        self.builder.preview()
+    }
+
+    /// Send the event, first waiting for room if `ClientOptions::send_buffer` limits the packets
+    /// waiting to be sent.  A volatile event isn't waited for: it fails with
+    /// `Error::SendBufferFull` if there's no room.  Fails with `Error::Disconnected` if the
+    /// connection has ended, in which case the event's ack is cancelled.  An event in a `Batch`
+    /// is added to the batch, to be sent with it.  Fails with `Error::PayloadTooLarge` without
+    /// sending anything if the packet is larger than the server accepts.
+    pub async fn send(self) -> Result<(), Error> {
+        if self.batch.is_some() {
+            // Waited for when the batch is sent
+        } else if self.volatile {
+            if !self.client.send.has_room() {
+                return Err(Error::SendBufferFull);
+            }
+        } else {
+            self.client.send.ready().await?;
+        }
+        let callbacks = self.client.callbacks.clone();
+        let (delivery, ack_error) = self.prepare()?;
+        if let Some(delivery) = delivery {
+            delivery.send()?;
+        }
+        if let Some(e) = ack_error {
+            // The ack can't be received, so the callback was dropped and the event sent without
+            // waiting for it
+            callbacks::report_error(&callbacks, e);
+        }
+        Ok(())
+    }
+
+    /// Set the event's ack and finish its packets, returning the `Delivery` to send them with,
+    /// unless the event was added to a batch or dropped as volatile.  Also returns the error if
+    /// the ack couldn't be set, in which case the event is still sent.
+    fn prepare(self) -> Result<(Option<Delivery>, Option<Error>), Error> {
+        if self.volatile && !self.writable() {
+            log::debug!(
+                "Dropping volatile event {} for {}",
+                self.event,
+                self.namespace
+            );
+            return Ok((None, None));
+        }
         let packets = self.builder.finish();
+        // Dropping the callback cancels the ack
+        self.client.send.check_payload(&packets)?;
+        let id = self.callback.as_ref().map(|(_, id)| *id);
+        trace::emit(&self.namespace, Some(self.event), id);
+        let mut ack_error = None;
         if let Some((callback, id)) = self.callback {
-            self.client
-                .callbacks
+            let expires = self.ack_timeout.map(|timeout| Instant::now() + timeout);
+            let result = self.client.callbacks.lock().unwrap().set_ack(
+                &self.namespace,
+                id,
+                callback,
+                expires,
+            );
+            match result {
+                Ok(()) => {
+                    if let Some(timeout) = self.ack_timeout {
+                        let callbacks = self.client.callbacks.clone();
+                        let namespace = self.namespace.to_string();
+                        self.client.spawn_timer(async move {
+                            Delay::new(timeout).await;
+                            if callbacks.lock().unwrap().expire_ack(&namespace, id) {
+                                log::debug!("Ack {} for {} expired", id, namespace);
+                            }
+                        });
+                    }
+                }
+                Err(e) => ack_error = Some(e),
+            }
+        }
+        if let Some(batch) = self.batch {
+            batch.packets.extend(packets);
+            if let Some(id) = id {
+                batch.acks.push((self.namespace.to_string(), id));
+            }
+            return Ok((None, ack_error));
+        }
+        let delivery = Delivery {
+            callbacks: self.client.callbacks.clone(),
+            send: self.client.send.clone(),
+            namespace: self.namespace.to_string(),
+            packets,
+            id,
+        };
+        Ok((Some(delivery), ack_error))
+    }
+
+    fn writable(&self) -> bool {
+        self.client.is_connected()
+            && self.client.namespace_state(&self.namespace) == NamespaceState::Connected
+    }
+
+    /// Send the event, returning a future that resolves to the ack's arguments.  If the ack is
+    /// cancelled, e.g. by the client closing before it's received, the future resolves to
+    /// `Error::Disconnected`, or to `Error::AckTimeout` if it isn't received within the
+    /// `EventBuilder::ack_timeout`.  If the event can't be sent, or its ack can't be set, e.g.
+    /// with `Error::PayloadTooLarge` or `Error::TooManyNamespaces`, the future resolves to that
+    /// error.  Replaces any callback set on the `EventBuilder`.  The event is sent straight away
+    /// if there's room in the `ClientOptions::send_buffer`, otherwise the future waits for room
+    /// and sends it, or fails with `Error::SendBufferFull` if the event is volatile.
+    pub fn send_with_ack(self) -> impl Future<Output = Result<OwnedArgs, Error>> {
+        self.send_with_ack_map(|args| Ok(args.to_owned_args()))
+    }
+
+    /// Like `send_with_ack`, with the ack's arguments deserialized into `R`, following the same
+    /// rules as `EventBuilder::callback_typed`.
+    pub fn send_with_typed_ack<R>(self) -> impl Future<Output = Result<R, Error>>
+    where
+        R: 'static + DeserializeOwned + Send,
+    {
+        self.send_with_ack_map(|args| deserialize_ack(args).map_err(Error::ArgsError))
+    }
+
+    fn send_with_ack_map<R, F>(mut self, map: F) -> impl Future<Output = Result<R, Error>>
+    where
+        R: 'static + Send,
+        F: 'static + Send + FnOnce(&Args) -> Result<R, Error>,
+    {
+        let id = match &self.callback {
+            Some((_, id)) => *id,
+            None => self.client.allocate_id(),
+        };
+        self.builder.set_id(id);
+        let (tx, rx) = oneshot::channel();
+        let callback = move |args: &Args| {
+            let _ = tx.send(map(args));
+        };
+        self.callback = Some((callback.into(), id));
+        // Checked when the callback is dropped, since it's dropped both when the ack expires and
+        // when it's cancelled
+        let expires = self.ack_timeout.map(|timeout| Instant::now() + timeout);
+        let sender = self.client.send.clone();
+        let full = self.batch.is_none() && !sender.has_room();
+        // A failure cancels the ack, so the future resolves to the error rather than waiting
+        let result = if full && self.volatile {
+            Err(Error::SendBufferFull)
+        } else {
+            self.prepare()
+        };
+        let result = result.and_then(|(delivery, ack_error)| match delivery {
+            Some(delivery) if !full => delivery.send().map(|()| (None, ack_error)),
+            delivery => Ok((delivery, ack_error)),
+        });
+        async move {
+            let (delivery, ack_error) = result?;
+            if let Some(delivery) = delivery {
+                // Dropping the delivery unsent cancels the ack
+                sender.ready().await?;
+                delivery.send()?;
+            }
+            if let Some(e) = ack_error {
+                return Err(e);
+            }
+            rx.await.unwrap_or_else(|_| match expires {
+                Some(expires) if expires <= Instant::now() => Err(Error::AckTimeout),
+                _ => Err(Error::Disconnected),
+            })
+        }
+    }
+}
+
+/// An event's packets, ready to be sent.  The event's ack is cancelled if they're dropped unsent,
+/// or if sending them fails.
+struct Delivery {
+    callbacks: Arc<Mutex<Callbacks>>,
+    send: Sender,
+    namespace: String,
+    packets: Vec<WsMessage>,
+    id: Option<u64>,
+}
+
+impl Delivery {
+    fn send(mut self) -> Result<(), Error> {
+        let packets = mem::take(&mut self.packets);
+        let mut callbacks = self.callbacks.lock().unwrap();
+        // Events emitted to a namespace that's connecting are sent once it's connected
+        let sent = match callbacks.queue_packets(&self.namespace, packets, self.id) {
+            Some(packets) => self.send.unbounded_send(packets),
+            None => Ok(()),
+        };
+        if sent.is_ok() {
+            self.id = None;
+        }
+        sent
+    }
+}
+
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        // The event wasn't sent so the ack will never arrive, cancel it
+        if let Some(id) = self.id {
+            self.callbacks
                 .lock()
                 .unwrap()
-                .set_ack(self.namespace, id, callback);
+                .get_and_clear_ack(&self.namespace, id);
+        }
+    }
+}
+
+impl<'a> Batch<'a> {
+    pub(crate) fn new(client: &'a mut Client) -> Self {
+        Batch {
+            client,
+            pending: Pending::default(),
+        }
+    }
+
+    /// Add an event for the given namespace to the batch, which is added when the
+    /// `EventArgsBuilder` is sent.
+    pub fn namespace_emit<'b>(
+        &'b mut self,
+        namespace: &'b str,
+        event: &'b str,
+    ) -> EventBuilder<'b> {
+        let mut builder = EventBuilder::new(self.client, event, namespace);
+        builder.batch = Some(&mut self.pending);
+        builder
+    }
+
+    /// Add an event for the client's default namespace to the batch, like `Client::emit`.
+    pub fn emit<'b>(&'b mut self, event: &'b str) -> EventBuilder<'b> {
+        let namespace = self.client.default_namespace.clone();
+        let mut builder = EventBuilder::new(self.client, event, namespace);
+        builder.batch = Some(&mut self.pending);
+        builder
+    }
+
+    /// The number of websocket messages in the batch, including binary attachments.
+    pub fn len(&self) -> usize {
+        self.pending.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.packets.is_empty()
+    }
+
+    /// Send the batch's events, waiting for room first like `EventArgsBuilder::send`.  They're
+    /// sent straight away, even to namespaces that are still connecting.  Fails with
+    /// `Error::Disconnected` if the connection has ended, in which case the acks are cancelled.
+    pub async fn send(mut self) -> Result<(), Error> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        self.client.send.ready().await?;
+        let packets = mem::take(&mut self.pending.packets);
+        self.client.send.unbounded_send(packets)?;
+        self.pending.acks.clear();
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Batch<'a> {
+    fn drop(&mut self) {
+        // The batch wasn't sent, so the acks will never arrive
+        let mut callbacks = self.client.callbacks.lock().unwrap();
+        for (namespace, id) in self.pending.acks.drain(..) {
+            callbacks.get_and_clear_ack(&namespace, id);
         }
-        let _ = self.client.send.unbounded_send(packets); // TODO: Determine if we care about the result.
     }
 }
 
 impl AckBuilder {
-    pub(crate) fn new(
-        send: mpsc::UnboundedSender<Vec<WsMessage>>,
-        namespace: impl Into<String>,
-        id: u64,
-    ) -> Self {
+    pub(crate) fn new(send: Sender, namespace: impl Into<String>, id: u64) -> Self {
         AckBuilder {
             send,
             namespace: namespace.into(),
             id,
             binary: false,
+            binary_mode: BinaryMode::default(),
         }
     }
 
+    /// Send the ack as a binary packet, in which byte strings and sequences consisting entirely of
+    /// `u8`s are sent as binary attachments, as for `EventBuilder::binary`.  This isn't inferred
+    /// from the event being acked: an event received with attachments can be acked with plain
+    /// JSON, so replies containing binary data must set this explicitly.
     pub fn binary(mut self, b: bool) -> Self {
         self.binary = b;
         self
     }
 
+    /// Set which values are sent as attachments if the ack is sent as a binary packet.
+    pub fn binary_mode(mut self, mode: BinaryMode) -> Self {
+        self.binary_mode = mode;
+        self
+    }
+
     pub fn args(self) -> AckArgsBuilder {
-        let builder = PacketBuilder::new_ack(self.namespace, self.id, self.binary);
+        let mut builder = PacketBuilder::new_ack(&self.namespace, self.id, self.binary);
+        builder.set_binary_mode(self.binary_mode);
         AckArgsBuilder {
             send: self.send,
+            namespace: self.namespace,
+            id: self.id,
             builder,
         }
     }
+
+    /// Send the ack with no arguments, just acknowledging receipt, short for
+    /// `.args().send()`.
+    pub fn send_empty(self) -> Result<(), Error> {
+        self.args().send()
+    }
 }
 
 impl AckArgsBuilder {
@@ -145,8 +539,564 @@ impl AckArgsBuilder {
         self.builder.serialize_arg(arg)
     }
 
-    pub fn send(self) {
+    /// Add a signature over the packet as the last argument, e.g. an HMAC for servers that check
+    /// message integrity.  `sign` receives the bytes of the text message as it would be sent
+    /// without the signature, see `PacketBuilder::sign` for exactly what's covered.
+    pub fn sign<T, F>(mut self, sign: F) -> Result<Self, ArgsError>
+    where
+        T: Serialize,
+        F: FnOnce(&[u8]) -> T,
+    {
+        self.builder.sign(sign)?;
+        Ok(self)
+    }
+
+    /// The size the packet will be sent as with the arguments added so far, e.g. to enforce a
+    /// limit on packet size before sending.
+    pub fn preview(&self) -> PacketPreview {
+        self.builder.preview()
+    }
+
+    /// Send the ack.  Fails with `Error::Disconnected` if the connection has ended, or
+    /// `Error::PayloadTooLarge` if the packet is larger than the server accepts.
+    pub fn send(self) -> Result<(), Error> {
         let packets = self.builder.finish();
-        let _ = self.send.unbounded_send(packets); // TODO: Determine if we care about the result.
+        self.send.check_payload(&packets)?;
+        trace::emit(&self.namespace, None, Some(self.id));
+        self.send.unbounded_send(packets)
+    }
+}
+
+fn deserialize_ack<R>(args: &Args) -> Result<R, ArgsError>
+where
+    R: DeserializeOwned,
+{
+    match args.get(0) {
+        Some(arg) if args.len() == 1 => arg.deserialize(),
+        _ => args.deserialize_all(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_tungstenite::tungstenite::Message as WsMessage;
+    use futures::{channel::oneshot, future, stream::StreamExt};
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{harness, spawn::TokioSpawner};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Response {
+        status: String,
+    }
+
+    #[tokio::test]
+    async fn test_callback_typed() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let (single_tx, single_rx) = oneshot::channel();
+        client
+            .emit("single")
+            .callback_typed(move |r: Response| single_tx.send(r).unwrap())
+            .args()
+            .send()
+            .await
+            .unwrap();
+        let (multi_tx, multi_rx) = oneshot::channel();
+        client
+            .emit("multi")
+            .callback_typed(move |r: (u32, String)| multi_tx.send(r).unwrap())
+            .args()
+            .send()
+            .await
+            .unwrap();
+        let (empty_tx, empty_rx) = oneshot::channel();
+        client
+            .emit("empty")
+            .callback_typed(move |r: ()| empty_tx.send(r).unwrap())
+            .args()
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(server.recv_text().await, r#"420["single"]"#);
+        assert_eq!(server.recv_text().await, r#"421["multi"]"#);
+        assert_eq!(server.recv_text().await, r#"422["empty"]"#);
+        server.send(r#"430[{"status":"ok"}]"#).await;
+        server.send(r#"431[5,"five"]"#).await;
+        server.send(r#"432[]"#).await;
+
+        assert_eq!(
+            single_rx.await.unwrap(),
+            Response {
+                status: "ok".to_string()
+            }
+        );
+        assert_eq!(multi_rx.await.unwrap(), (5, "five".to_string()));
+        empty_rx.await.unwrap();
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_typed_ack_future() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let ack = client
+            .emit("event")
+            .args()
+            .arg(&1)
+            .unwrap()
+            .send_with_typed_ack::<(u32, u32)>();
+        assert_eq!(server.recv_text().await, r#"420["event",1]"#);
+        server.send(r#"430[2,3]"#).await;
+        assert_eq!(ack.await.unwrap(), (2, 3));
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_ack_future() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let ack = client.emit("event").args().send_with_ack();
+        assert_eq!(server.recv_text().await, r#"420["event"]"#);
+        server.send(r#"430[{"status":"ok"},"text"]"#).await;
+        let args = ack.await.unwrap();
+        assert_eq!(args.len(), 2);
+        let response: Response = args.args().get(0).unwrap().deserialize().unwrap();
+        assert_eq!(response.status, "ok");
+        assert_eq!(args.args().get(1).unwrap().raw_json(), r#""text""#);
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_typed_ack_future_cancelled() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let ack = client
+            .namespace_emit("/nsp", "event")
+            .args()
+            .send_with_typed_ack::<String>();
+        assert_eq!(server.recv_text().await, r#"42/nsp,0["event"]"#);
+        assert_eq!(client.cancel_acks(), vec![("/nsp".to_string(), 0)]);
+        match ack.await {
+            Err(Error::Disconnected) => (),
+            r => panic!("Unexpected ack result: {:?}", r),
+        }
+
+        // The cancelled ack arriving late is ignored rather than ending the connection
+        server.send(r#"43/nsp,0["late"]"#).await;
+        let ack = client.emit("event").args().send_with_typed_ack::<String>();
+        assert_eq!(server.recv_text().await, r#"421["event"]"#);
+        server.send(r#"431["ok"]"#).await;
+        assert_eq!(ack.await.unwrap(), "ok");
+
+        let ack = client.emit("event").args().send_with_typed_ack::<String>();
+        assert_eq!(server.recv_text().await, r#"422["event"]"#);
+        harness::close(client, server).await;
+        match ack.await {
+            Err(Error::Disconnected) => (),
+            r => panic!("Unexpected ack result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ack_future_too_many_namespaces() {
+        let options = crate::ClientOptions::new().max_namespaces(1);
+        let (mut client, mut server) = harness::connect_with_options(options).await;
+
+        let ack = client.emit("event").args().send_with_ack();
+        assert_eq!(server.recv_text().await, r#"420["event"]"#);
+        server.send(r#"430[]"#).await;
+        assert_eq!(ack.await.unwrap().len(), 0);
+
+        // The event is still sent, but its ack can't be set
+        let ack = client
+            .namespace_emit("/nsp", "event")
+            .args()
+            .send_with_ack();
+        assert_eq!(server.recv_text().await, r#"42/nsp,1["event"]"#);
+        match ack.await {
+            Err(Error::TooManyNamespaces(1)) => (),
+            r => panic!("Unexpected ack result: {:?}", r),
+        }
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_ack_timeout() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let timeout = Duration::from_millis(50);
+        let ack = client
+            .emit("event")
+            .ack_timeout(timeout)
+            .args()
+            .send_with_ack();
+        assert_eq!(server.recv_text().await, r#"420["event"]"#);
+        match ack.await {
+            Err(Error::AckTimeout) => (),
+            r => panic!("Unexpected ack result: {:?}", r),
+        }
+
+        client
+            .emit("event")
+            .ack_timeout(timeout)
+            .callback(|_args: &Args| panic!("Callback called after timeout"))
+            .args()
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(server.recv_text().await, r#"421["event"]"#);
+        Delay::new(timeout * 2).await;
+
+        // Late acks are ignored rather than ending the connection
+        server.send(r#"430["late"]"#).await;
+        server.send(r#"431["late"]"#).await;
+        let ack = client
+            .emit("event")
+            .ack_timeout(Duration::from_secs(5))
+            .args()
+            .send_with_typed_ack::<String>();
+        assert_eq!(server.recv_text().await, r#"422["event"]"#);
+        server.send(r#"432["ok"]"#).await;
+        assert_eq!(ack.await.unwrap(), "ok");
+        assert!(client.cancel_acks().is_empty());
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_preview() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let builder = client
+            .emit("event")
+            .binary(true)
+            .args()
+            .arg(&vec![1u8, 2])
+            .unwrap()
+            .arg("text")
+            .unwrap();
+        let preview = builder.preview();
+        assert_eq!(preview.attachment_count, 1);
+        builder.send().await.unwrap();
+
+        let text = server.recv_text().await;
+        assert_eq!(
+            text,
+            r#"451-["event",{"_placeholder":true,"num":0},"text"]"#
+        );
+        assert_eq!(preview.byte_len, text.len() + 3);
+        assert_eq!(server.recv().await, Some(WsMessage::Binary(vec![4, 1, 2])));
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_sign() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let checksum = |text: &[u8]| text.iter().map(|&b| u64::from(b)).sum::<u64>();
+        client
+            .emit("event")
+            .args()
+            .arg("arg")
+            .unwrap()
+            .sign(checksum)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+
+        let text = server.recv_text().await;
+        // The server verifies by removing the signature from the end of the message
+        let (unsigned, signature) = text.split_at(text.rfind(',').unwrap());
+        let signature: u64 = signature[1..signature.len() - 1].parse().unwrap();
+        assert_eq!(signature, checksum(format!("{}]", unsigned).as_bytes()));
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_binary_mode() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let data = vec![1u8, 2];
+        client
+            .emit("event")
+            .binary(true)
+            .args()
+            .arg(&data)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        client
+            .emit("event")
+            .binary(true)
+            .binary_mode(BinaryMode::Explicit)
+            .args()
+            .arg(&data)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            server.recv_text().await,
+            r#"451-["event",{"_placeholder":true,"num":0}]"#
+        );
+        assert_eq!(server.recv().await, Some(WsMessage::Binary(vec![4, 1, 2])));
+        assert_eq!(server.recv_text().await, r#"450-["event",[1,2]]"#);
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_binary_ack() {
+        let (mut client, mut server) = harness::connect().await;
+
+        client
+            .set_namespace_event_callback(
+                "/nsp",
+                "upload",
+                |args: &Args, ack: Option<AckBuilder>| {
+                    let mut data: Vec<u8> = args.get(1).unwrap().deserialize().unwrap();
+                    data.reverse();
+                    let ack = ack.unwrap().binary(true).args();
+                    ack.arg("reversed")
+                        .unwrap()
+                        .arg(&data)
+                        .unwrap()
+                        .send()
+                        .unwrap();
+                },
+            )
+            .unwrap();
+
+        server
+            .send(r#"451-/nsp,7["upload",{"_placeholder":true,"num":0}]"#)
+            .await;
+        server.send_binary(vec![4, 1, 2, 3]).await;
+
+        assert_eq!(
+            server.recv_text().await,
+            r#"461-/nsp,7["reversed",{"_placeholder":true,"num":0}]"#
+        );
+        assert_eq!(
+            server.recv().await,
+            Some(WsMessage::Binary(vec![4, 3, 2, 1]))
+        );
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_empty_ack() {
+        let (mut client, mut server) = harness::connect().await;
+
+        client
+            .set_event_callback("ping", |_args: &Args, ack: Option<AckBuilder>| {
+                ack.unwrap().send_empty().unwrap()
+            })
+            .unwrap();
+        client
+            .set_namespace_event_callback(
+                "/nsp",
+                "ping",
+                |_args: &Args, ack: Option<AckBuilder>| ack.unwrap().args().send().unwrap(),
+            )
+            .unwrap();
+
+        server.send(r#"421["ping"]"#).await;
+        assert_eq!(server.recv_text().await, "431[]");
+        server.send(r#"42/nsp,2["ping"]"#).await;
+        assert_eq!(server.recv_text().await, "43/nsp,2[]");
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_volatile() {
+        let (mut client, mut server) = harness::connect().await;
+
+        // The default namespace isn't connected until the server's connect packet arrives
+        let ack = client.emit("dropped").volatile(true).args().send_with_ack();
+        assert!(matches!(ack.await, Err(Error::Disconnected)));
+        client.emit("queued").args().send().await.unwrap();
+        assert_eq!(server.recv_text().await, r#"42["queued"]"#);
+
+        let timeout = Duration::from_secs(5);
+        let (result, _) = future::join(
+            client.wait_connected("/", timeout),
+            server.send(r#"40{"sid":"abc"}"#),
+        )
+        .await;
+        result.unwrap();
+        client
+            .emit("sent")
+            .volatile(true)
+            .args()
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(server.recv_text().await, r#"42["sent"]"#);
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_max_payload() {
+        let open = r#"0{"sid":"abc","pingInterval":25000,"pingTimeout":5000,"maxPayload":64}"#;
+        let (client, server) = harness::duplex();
+        let (client, mut server) = future::join(
+            Client::from_stream("http://localhost/", client, &TokioSpawner),
+            harness::Server::accept_with_open(server, open),
+        )
+        .await;
+        let mut client = client.unwrap();
+
+        // The attachment is sent with a one byte header
+        let big = vec![0u8; 100];
+        let result = client
+            .emit("big")
+            .binary(true)
+            .args()
+            .arg(&big)
+            .unwrap()
+            .send()
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::PayloadTooLarge {
+                size: 101,
+                limit: 64
+            })
+        ));
+        let ack = client
+            .emit("big")
+            .binary(true)
+            .args()
+            .arg(&big)
+            .unwrap()
+            .send_with_ack();
+        assert!(matches!(ack.await, Err(Error::PayloadTooLarge { .. })));
+        assert_eq!(client.pending_acks(), 0);
+
+        client
+            .emit("small")
+            .binary(true)
+            .args()
+            .arg(&[1u8, 2, 3][..])
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            server.recv_text().await,
+            r#"451-["small",{"_placeholder":true,"num":0}]"#
+        );
+        assert_eq!(
+            server.recv().await,
+            Some(WsMessage::Binary(vec![4, 1, 2, 3]))
+        );
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_emit_one() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let payload = Response {
+            status: "ok".to_string(),
+        };
+        client.emit("event").emit_one(&payload).await.unwrap();
+        client.emit("event").emit_one("text").await.unwrap();
+        assert_eq!(server.recv_text().await, r#"42["event",{"status":"ok"}]"#);
+        assert_eq!(server.recv_text().await, r#"42["event","text"]"#);
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let mut batch = client.batch();
+        batch.emit("first").args().send().await.unwrap();
+        let ack = batch
+            .namespace_emit("/nsp", "second")
+            .args()
+            .arg(&2)
+            .unwrap()
+            .send_with_typed_ack::<String>();
+        batch.emit("third").emit_one(&3).await.unwrap();
+        assert_eq!(batch.len(), 3);
+        batch.send().await.unwrap();
+        assert_eq!(server.recv_text().await, r#"42["first"]"#);
+        assert_eq!(server.recv_text().await, r#"42/nsp,0["second",2]"#);
+        assert_eq!(server.recv_text().await, r#"42["third",3]"#);
+        server.send(r#"43/nsp,0["ok"]"#).await;
+        assert_eq!(ack.await.unwrap(), "ok");
+
+        // Dropping a batch without sending it cancels its acks
+        let mut batch = client.batch();
+        let ack = batch.emit("dropped").args().send_with_ack();
+        drop(batch);
+        assert!(matches!(ack.await, Err(Error::Disconnected)));
+        client.emit("after").args().send().await.unwrap();
+        assert_eq!(server.recv_text().await, r#"42["after"]"#);
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_send_disconnected() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let mut events = client.events();
+        server.send(r#"421["event"]"#).await;
+        let ack = events.next().await.unwrap().ack.unwrap();
+        harness::close_client(&mut client, server).await;
+
+        let result = client.emit("event").args().send().await;
+        assert!(matches!(result, Err(Error::Disconnected)));
+        let result = ack.args().arg("ok").unwrap().send();
+        assert!(matches!(result, Err(Error::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn test_callback_typed_error() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let (error_tx, error_rx) = oneshot::channel();
+        let error_tx = Arc::new(Mutex::new(Some(error_tx)));
+        client.set_error_callback(move |error: &Error| {
+            if let Some(tx) = error_tx.lock().unwrap().take() {
+                tx.send(error.to_string()).unwrap();
+            }
+        });
+        client
+            .emit("event")
+            .callback_typed(|_: Response| panic!("Callback called with invalid args"))
+            .args()
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(server.recv_text().await, r#"420["event"]"#);
+        server.send(r#"430["not a response"]"#).await;
+
+        assert!(error_rx.await.unwrap().contains("not a response"));
+
+        harness::close(client, server).await;
     }
 }
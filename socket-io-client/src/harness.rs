@@ -0,0 +1,274 @@
+//! An in-memory socket.io server for exercising a `Client` end-to-end in tests.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_tungstenite::{
+    tungstenite::{
+        handshake::server::{Request, Response},
+        http::HeaderMap,
+        protocol::CloseFrame,
+        Message as WsMessage,
+    },
+    WebSocketStream,
+};
+use futures::{
+    channel::mpsc,
+    future,
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sink::SinkExt,
+    stream::StreamExt,
+};
+
+use super::{spawn::TokioSpawner, Client, ClientOptions};
+
+pub const OPEN: &str =
+    r#"0{"sid":"harness","upgrades":[],"pingInterval":25000,"pingTimeout":5000}"#;
+
+/// One end of an in-memory byte pipe.
+pub struct MemStream {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+pub fn duplex() -> (MemStream, MemStream) {
+    let (a_tx, a_rx) = mpsc::unbounded();
+    let (b_tx, b_rx) = mpsc::unbounded();
+    let stream = |tx, rx| MemStream {
+        tx,
+        rx,
+        buf: Vec::new(),
+        pos: 0,
+    };
+    (stream(a_tx, b_rx), stream(b_tx, a_rx))
+}
+
+impl AsyncRead for MemStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pos == self.buf.len() {
+            match self.rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(data)) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = std::cmp::min(buf.len(), self.buf.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for MemStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.tx.unbounded_send(buf.to_vec()) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.tx.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The server side of a harness connection.
+pub struct Server {
+    ws: WebSocketStream<MemStream>,
+    /// The path and query of the upgrade request.
+    pub path: String,
+    /// The headers of the upgrade request.
+    pub headers: HeaderMap,
+}
+
+impl Server {
+    /// Accept the websocket handshake on `stream` and send the engine.io open packet.
+    pub async fn accept(stream: MemStream) -> Server {
+        Server::accept_with_open(stream, OPEN).await
+    }
+
+    /// Like `accept`, but sends `open` as the open packet.
+    pub async fn accept_with_open(stream: MemStream, open: &str) -> Server {
+        let mut server = Server::accept_upgrade(stream).await;
+        server.send(open).await;
+        server
+    }
+
+    /// Accept the websocket handshake on `stream` without sending an open packet, as when a
+    /// polling session is upgraded.
+    #[allow(clippy::result_large_err)] // The callback's error type is fixed by tungstenite
+    pub async fn accept_upgrade(stream: MemStream) -> Server {
+        let mut request = None;
+        let callback = |req: &Request, resp: Response| {
+            request = Some((req.uri().to_string(), req.headers().clone()));
+            Ok(resp)
+        };
+        let ws = async_tungstenite::accept_hdr_async(stream, callback)
+            .await
+            .unwrap();
+        let (path, headers) = request.unwrap();
+        Server { ws, path, headers }
+    }
+
+    pub async fn send(&mut self, text: &str) {
+        self.ws
+            .send(WsMessage::Text(text.to_string()))
+            .await
+            .unwrap();
+    }
+
+    /// Send a binary frame, which must include the engine.io packet type, e.g. 4 for attachments.
+    pub async fn send_binary(&mut self, data: Vec<u8>) {
+        self.ws.send(WsMessage::Binary(data)).await.unwrap();
+    }
+
+    /// Close the websocket with `frame`, read until the client replies, then close the
+    /// underlying stream.
+    pub async fn close_with(mut self, frame: Option<CloseFrame<'static>>) {
+        self.ws.close(frame).await.unwrap();
+        while self.ws.next().await.is_some() {}
+    }
+
+    /// The next message received from the client, or `None` if the connection ended.
+    pub async fn recv(&mut self) -> Option<WsMessage> {
+        self.ws.next().await.map(Result::unwrap)
+    }
+
+    /// Read messages until the client closes the connection, returning everything received
+    /// before the close frame.
+    pub async fn closed(&mut self) -> Vec<WsMessage> {
+        let mut msgs = Vec::new();
+        while let Some(msg) = self.recv().await {
+            if !msg.is_close() {
+                msgs.push(msg);
+            }
+        }
+        msgs
+    }
+
+    /// The next text message received from the client, which must exist.
+    pub async fn recv_text(&mut self) -> String {
+        match self.recv().await {
+            Some(WsMessage::Text(text)) => text,
+            msg => panic!("Expected text message, received {:?}", msg),
+        }
+    }
+}
+
+/// The server side of a connection carrying HTTP requests, for the polling transport.
+pub struct HttpServer {
+    stream: BufReader<MemStream>,
+}
+
+/// A request received by an `HttpServer`.
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    /// The path and query.
+    pub target: String,
+    pub body: String,
+}
+
+impl HttpServer {
+    pub fn new(stream: MemStream) -> HttpServer {
+        HttpServer {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    /// The next request received from the client, which must exist.
+    pub async fn recv(&mut self) -> HttpRequest {
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            let len = self.stream.read_line(&mut head).await.unwrap();
+            assert_ne!(len, 0, "Connection closed, received {:?}", head);
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut request = httparse::Request::new(&mut headers);
+        request.parse(head.as_bytes()).unwrap();
+        let length = request
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+            .map_or(0, |h| {
+                std::str::from_utf8(h.value).unwrap().parse().unwrap()
+            });
+        let mut body = vec![0; length];
+        self.stream.read_exact(&mut body).await.unwrap();
+        HttpRequest {
+            method: request.method.unwrap().to_string(),
+            target: request.path.unwrap().to_string(),
+            body: String::from_utf8(body).unwrap(),
+        }
+    }
+
+    /// Respond to a request with `body` and a 200 status, keeping the connection alive.
+    pub async fn respond(&mut self, body: &str) {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=UTF-8\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        self.stream
+            .get_mut()
+            .write_all(response.as_bytes())
+            .await
+            .unwrap();
+    }
+}
+
+/// Connect a `Client` to an in-memory `Server`.
+pub async fn connect() -> (Client, Server) {
+    connect_with_options(ClientOptions::default()).await
+}
+
+/// Connect a `Client` created with `options` to an in-memory `Server`.
+pub async fn connect_with_options(options: ClientOptions) -> (Client, Server) {
+    let (client, server) = duplex();
+    let (client, server) = future::join(
+        Client::from_stream_with_options("http://localhost/", options, client, &TokioSpawner),
+        Server::accept(server),
+    )
+    .await;
+    (client.unwrap(), server)
+}
+
+/// Close `client` while driving the server side of the close handshake, returning the messages
+/// the server received before the close frame.
+pub async fn close(mut client: Client, server: Server) -> Vec<WsMessage> {
+    close_client(&mut client, server).await
+}
+
+/// Like `close`, but keeps the closed client.
+pub async fn close_client(client: &mut Client, mut server: Server) -> Vec<WsMessage> {
+    let server = async move {
+        let msgs = server.closed().await;
+        // Dropping the server closes the underlying stream, which completes the close handshake
+        drop(server);
+        msgs
+    };
+    let (result, msgs) = future::join(client.close(), server).await;
+    result.unwrap();
+    msgs
+}
@@ -1,37 +1,76 @@
 #![recursion_limit = "1024"] // Needed for select
 
 use std::{
+    any::Any,
     error::Error as StdError,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use async_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
 use futures::{
     channel::mpsc,
-    future::Future,
+    future::{Future, FutureExt, TryFutureExt},
     io::{AsyncRead, AsyncWrite},
+    select,
     task::{Spawn, SpawnError},
 };
+use futures_timer::Delay;
+use serde::Serialize;
 use url::Url;
 
+use socket_io_protocol::{
+    engine::{self, Message as EngineMessage},
+    socket,
+};
+
+pub mod blocking;
 mod callbacks;
 mod connection;
 mod emit;
+#[cfg(test)]
+mod harness;
+mod options;
+mod polling;
+mod pool;
 pub mod protocol;
 mod receiver;
+mod reconnect;
+#[cfg(any(test, feature = "tokio", feature = "async-std"))]
+pub mod spawn;
+mod trace;
 
 use callbacks::Callbacks;
-pub use callbacks::{AckCallback, EventCallback};
-use connection::Connection;
-pub use emit::{AckArgsBuilder, AckBuilder, EventArgsBuilder, EventBuilder};
+pub use callbacks::{
+    AckCallback, ConnectCallback, ConnectErrorCallback, DisconnectCallback, ErrorCallback,
+    EventCallback, EventTransformer, GlobalFallbackCallback, IncomingEvent, NamespaceState,
+    DEFAULT_MAX_NAMESPACES,
+};
+use connection::{Connection, Sender};
+pub use emit::{AckArgsBuilder, AckBuilder, Batch, EventArgsBuilder, EventBuilder};
+pub use options::{ClientOptions, Transport, DEFAULT_TIMEOUT};
+pub use pool::ClientPool;
 use receiver::Receiver;
+pub use reconnect::{ConnectionStatus, DisconnectReason, ReconnectConfig};
+pub use socket_io_protocol::engine::EngineVersion;
 
 pub struct Client {
     connection: Connection,
-    pub send: mpsc::UnboundedSender<Vec<WsMessage>>,
+    send: Sender,
     callbacks: Arc<Mutex<Callbacks>>,
     next_id: u64,
+    context: Arc<Mutex<Option<Context>>>,
+    // The namespace the methods without a namespace argument use
+    default_namespace: String,
+}
+
+type Context = Arc<dyn Any + Send + Sync>;
+
+/// The code and reason of a websocket close frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseReason {
+    pub code: u16,
+    pub reason: String,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -46,10 +85,69 @@ pub enum Error {
     SpawnError(#[from] SpawnError),
     #[error("Error processing packet: {0}")]
     ProcessingError(#[from] receiver::Error),
+    #[error("Error deserializing argument: {0}")]
+    ArgsError(#[from] protocol::ArgsError),
     #[error("Connection timed out waiting for {0}")]
     Timeout(&'static str),
     #[error("Already closed")]
     AlreadyClosed,
+    #[error("Disconnected")]
+    Disconnected,
+    /// The channel of packets to send ended while the connection was open, so the connection was
+    /// closed.
+    #[error("Channel of packets to send closed unexpectedly")]
+    SendChannelClosed,
+    /// The ack for an event wasn't received within the timeout set with
+    /// `EventBuilder::ack_timeout`.
+    #[error("Timed out waiting for an ack")]
+    AckTimeout,
+    /// A volatile event was emitted while `ClientOptions::send_buffer` was full.
+    #[error("The send buffer is full")]
+    SendBufferFull,
+    #[error("Connection to namespace refused: {0}")]
+    ConnectRefused(String),
+    #[error("Can't send control frame as a packet: {0:?}")]
+    ControlFrame(WsMessage),
+    #[error("Exceeded the maximum of {0} namespaces")]
+    TooManyNamespaces(usize),
+    /// The server responded to the websocket upgrade request, or a polling request, with an HTTP
+    /// error, e.g. 429 or 503.  `retry_after` is the delay from the response's `Retry-After`
+    /// header, if it was given in seconds and the websocket upgrade response wasn't encrypted
+    /// with TLS.
+    #[error("Websocket handshake failed with HTTP status {status}")]
+    HttpHandshake {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    /// The connection ended after the websocket handshake but before the engine.io open packet
+    /// was received, e.g. because the server restarted.  Holds the error the connection ended
+    /// with, or `None` if it was closed cleanly.
+    #[error("Connection closed before the engine.io open packet, error: {0:?}")]
+    ClosedDuringHandshake(Option<Box<Error>>),
+    /// The server didn't send a ping within the ping interval and timeout it gave in the open
+    /// packet, so the connection was assumed to be dead and closed.
+    #[error("No ping received from the server within the ping timeout")]
+    PingTimeout,
+    /// A packet, or one of its binary attachments, is larger than the `maxPayload` the server
+    /// gave in its open packet, so it wasn't sent: the server would close the connection.
+    #[error("Packet of {size} bytes exceeds the server's maximum payload of {limit} bytes")]
+    PayloadTooLarge { size: usize, limit: usize },
+    /// An HTTP long-polling request failed.
+    #[error("Polling request failed: {0}")]
+    PollingError(std::io::Error),
+    /// `Transport::Polling` was set for a client not created with `connect_reconnecting`, which
+    /// keeps the `connect` closure to open a connection for each request.
+    #[error("The polling transport needs a connect closure")]
+    PollingWithoutConnector,
+    /// The server didn't answer the probe sent while upgrading a polling session to a
+    /// websocket, holding what it sent instead, or `None` if the websocket ended.  The session
+    /// stays on polling.
+    #[error("Websocket upgrade probe failed, received {0:?}")]
+    UpgradeProbeFailed(Option<WsMessage>),
+    /// `Client::ping` was called while the session uses `Transport::Polling`, which has no
+    /// websocket frames to ping with.
+    #[error("Can't send a websocket ping over HTTP long-polling")]
+    PingWhilePolling,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -63,35 +161,39 @@ pub enum UrlError {
 }
 
 macro_rules! fwd_cbs {
-    ($(#[$attrs:meta])* $n1:ident $n2:ident $tgt:ident $inv:expr, ($($arg:ident : $ty:ty),*)) => {
+    (
+        $(#[$attrs:meta])* $n1:ident $n2:ident $tgt:ident $inv:expr,
+        ($($arg:ident : $ty:ty),*) $(-> $ret:ty)?
+    ) => {
         paste::paste! {
             $(#[$attrs])*
             pub fn $n1(
                 &mut self,
                 namespace: &str,
                 $( $arg : $ty ),*
-            ) {
+            ) $(-> $ret)? {
                 self.callbacks.lock().unwrap().$tgt(namespace, $( $arg ),*)
             }
 
             #[doc = "Equivalent to `"]
             #[doc = $inv]
-            #[doc = "`."]
-            pub fn $n2(&mut self, $( $arg : $ty ),*) {
-                self.$n1("/", $( $arg ),*)
+            #[doc = "` for the default namespace, see `set_default_namespace`."]
+            pub fn $n2(&mut self, $( $arg : $ty ),*) $(-> $ret)? {
+                let namespace = self.default_namespace.clone();
+                self.$n1(&namespace, $( $arg ),*)
             }
         }
     };
 
-    ($(#[$attrs:meta])* $fn1:ident $fn2:ident ($($arg:ident : $ty:ty),*)) => {
+    ($(#[$attrs:meta])* $fn1:ident $fn2:ident ($($arg:ident : $ty:ty),*) $(-> $ret:ty)?) => {
             paste::paste! {
         fwd_cbs! {
                 $(#[$attrs])*
                 [<$fn1 _namespace_ $fn2 _callback>]
                 [<$fn1 _ $fn2 _callback>]
                 [<$fn1 _ $fn2>]
-                stringify!( [<$fn1 _namespace_ $fn2 _callback>] ("/", $($arg),*) ),
-                ($($arg : $ty),*)
+                stringify!( [<$fn1 _namespace_ $fn2 _callback>] (namespace, $($arg),*) ),
+                ($($arg : $ty),*) $(-> $ret)?
             }
         }
     };
@@ -111,18 +213,129 @@ impl Client {
         F: Future<Output = Result<S, E>>,
         S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
         E: 'static + StdError + Send,
+    {
+        Client::connect_with_options(url, ClientOptions::default(), connect, spawn).await
+    }
+
+    /// Like `connect`, failing with `Error::Timeout` if opening the stream, or the session over
+    /// it, takes longer than `timeout`.  Equivalent to `connect_with_options` with
+    /// `ClientOptions::timeout`.
+    pub async fn connect_with_timeout<C, F, S, E>(
+        url: impl AsRef<str>,
+        connect: C,
+        timeout: Duration,
+        spawn: &impl Spawn,
+    ) -> Result<Client, Error>
+    where
+        C: 'static + Fn(Host, Port) -> F,
+        F: Future<Output = Result<S, E>>,
+        S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+        E: 'static + StdError + Send,
+    {
+        let options = ClientOptions::default().timeout(timeout);
+        Client::connect_with_options(url, options, connect, spawn).await
+    }
+
+    pub async fn connect_with_options<C, F, S, E>(
+        url: impl AsRef<str>,
+        options: ClientOptions,
+        connect: C,
+        spawn: &impl Spawn,
+    ) -> Result<Client, Error>
+    where
+        C: 'static + Fn(Host, Port) -> F,
+        F: Future<Output = Result<S, E>>,
+        S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+        E: 'static + StdError + Send,
+    {
+        let connect = |url: &Url| {
+            connect(
+                url.host_str().unwrap().into(),
+                url.port_or_known_default().unwrap(),
+            )
+        };
+        Client::connect_url_with_options(url, options, connect, spawn).await
+    }
+
+    /// Like `connect_with_options`, but `connect` is kept to open more streams later: to
+    /// reconnect if `options` enable reconnecting, and for the requests of the polling transport.
+    /// The connection task calls it, so it has to be `Send` and return `'static` futures.
+    pub async fn connect_reconnecting<C, F, S, E>(
+        url: impl AsRef<str>,
+        options: ClientOptions,
+        connect: C,
+        spawn: &impl Spawn,
+    ) -> Result<Client, Error>
+    where
+        C: 'static + Send + Fn(Host, Port) -> F,
+        F: 'static + Send + Future<Output = Result<S, E>>,
+        S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+        E: 'static + StdError + Send,
     {
         let url = url.as_ref();
         let url = parse_url(url).map_err(|e| Error::UrlError(url.to_string(), e))?;
 
-        let connection = connect(
-            url.host_str().unwrap().into(),
-            url.port_or_known_default().unwrap(),
-        )
-        .await
-        .map_err(|e| Error::ConnectionError(Box::new(e)))?;
+        let connect = Mutex::new(connect);
+        let connector: connection::Connector<S> = Arc::new(move |url: &Url| {
+            (connect.lock().unwrap())(
+                url.host_str().unwrap().into(),
+                url.port_or_known_default().unwrap(),
+            )
+            .map_err(|e| Error::ConnectionError(Box::new(e)))
+            .boxed()
+        });
+        let connection = select! {
+            connection = connector(&url).fuse() => connection?,
+            _ = Delay::new(options.get_timeout()).fuse() => return Err(Error::Timeout("connect")),
+        };
 
-        Client::new(url, connection, spawn).await
+        Client::new(url, options, connection, Some(connector), spawn).await
+    }
+
+    /// Like `connect`, but `connect` receives the parsed url, e.g. to dial an address that was
+    /// already resolved for its host rather than resolving the host again.
+    ///
+    /// The address dialed doesn't affect the handshake: the `Host` header, and for `wss` urls the
+    /// TLS server name (SNI) and the name the certificate is verified against, always come from
+    /// the url's host.  Dialing an IP address for a url with a hostname works as expected, but a
+    /// `wss` url whose host is an IP address needs the server's certificate to be valid for
+    /// that IP address.
+    pub async fn connect_url<C, F, S, E>(
+        url: impl AsRef<str>,
+        connect: C,
+        spawn: &impl Spawn,
+    ) -> Result<Client, Error>
+    where
+        C: FnOnce(&Url) -> F,
+        F: Future<Output = Result<S, E>>,
+        S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+        E: 'static + StdError + Send,
+    {
+        Client::connect_url_with_options(url, ClientOptions::default(), connect, spawn).await
+    }
+
+    pub async fn connect_url_with_options<C, F, S, E>(
+        url: impl AsRef<str>,
+        options: ClientOptions,
+        connect: C,
+        spawn: &impl Spawn,
+    ) -> Result<Client, Error>
+    where
+        C: FnOnce(&Url) -> F,
+        F: Future<Output = Result<S, E>>,
+        S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+        E: 'static + StdError + Send,
+    {
+        let url = url.as_ref();
+        let url = parse_url(url).map_err(|e| Error::UrlError(url.to_string(), e))?;
+
+        let connection = select! {
+            connection = Box::pin(connect(&url)).fuse() => connection
+                .map_err(|e| Error::ConnectionError(Box::new(e)))?,
+            _ = Delay::new(options.get_timeout()).fuse() => return Err(Error::Timeout("connect")),
+        };
+
+        Client::new(url, options, connection, None, spawn).await
     }
 
     pub async fn from_stream<S>(
@@ -130,29 +343,48 @@ impl Client {
         connection: S,
         spawn: &impl Spawn,
     ) -> Result<Client, Error>
+    where
+        S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        Client::from_stream_with_options(url, ClientOptions::default(), connection, spawn).await
+    }
+
+    pub async fn from_stream_with_options<S>(
+        url: impl AsRef<str>,
+        options: ClientOptions,
+        connection: S,
+        spawn: &impl Spawn,
+    ) -> Result<Client, Error>
     where
         S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
     {
         let url = url.as_ref();
         let url = parse_url(url).map_err(|e| Error::UrlError(url.to_string(), e))?;
 
-        Client::new(url, connection, spawn).await
+        Client::new(url, options, connection, None, spawn).await
     }
 
-    async fn new<S>(mut url: Url, connection: S, spawn: &impl Spawn) -> Result<Client, Error>
+    async fn new<S>(
+        url: Url,
+        options: ClientOptions,
+        connection: S,
+        connector: Option<connection::Connector<S>>,
+        spawn: &impl Spawn,
+    ) -> Result<Client, Error>
     where
         S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
     {
-        add_socketio_query_params(&mut url);
-
-        let callbacks = Arc::new(Mutex::new(Callbacks::new()));
+        let callbacks = Arc::new(Mutex::new(Callbacks::with_max_namespaces(
+            options.get_max_namespaces(),
+        )));
 
         let connection = Connection::new(
             url,
+            &options,
             connection,
+            connector,
             None,
             callbacks.clone(),
-            Duration::from_secs(10),
             spawn,
         )
         .await?;
@@ -163,13 +395,240 @@ impl Client {
             send,
             callbacks,
             next_id: 0,
+            context: Arc::new(Mutex::new(None)),
+            default_namespace: "/".to_string(),
         })
     }
 
-    pub async fn close(&mut self) -> Result<(), Error> {
+    /// Whether the connection is still open, i.e. the client hasn't been closed and the connection
+    /// hasn't ended.  `false` while reconnecting.
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_connected()
+    }
+
+    /// Returns a stream of the events received from now on that have no event callback, namespace
+    /// fallback or global fallback to handle them, as an alternative to setting callbacks.  Only
+    /// the receiver returned by the latest call gets events.  With event workers, events for
+    /// different namespaces and events may arrive out of order, as they would at callbacks.
+    pub fn events(&mut self) -> mpsc::UnboundedReceiver<IncomingEvent> {
+        self.callbacks.lock().unwrap().subscribe_events()
+    }
+
+    /// Returns a receiver for every change in the connection's status from now on, e.g. to
+    /// observe reconnect attempts.  See `ClientOptions::reconnect`.
+    pub fn status_changes(&mut self) -> mpsc::UnboundedReceiver<ConnectionStatus> {
+        self.callbacks.lock().unwrap().subscribe_status()
+    }
+
+    /// The transport the current session uses, which is `Transport::Websocket` once a session
+    /// started with `Transport::Polling` is upgraded.  See `ClientOptions::upgrade`.
+    pub fn transport(&self) -> Transport {
+        self.connection.transport()
+    }
+
+    /// The engine.io session id the server assigned the connection, e.g. to correlate it with
+    /// the server's logs.  Each reconnect starts a new session with a new id.
+    pub fn sid(&self) -> String {
+        self.connection.open().sid
+    }
+
+    /// Whether the client is connected to `namespace`.  Namespaces are only `Connecting` after
+    /// `connect_namespace`, so the default namespace of servers that connect clients to it
+    /// without a request is `Disconnected` until the server's connect packet is received.
+    pub fn namespace_state(&self, namespace: &str) -> NamespaceState {
+        self.callbacks.lock().unwrap().get_state(namespace)
+    }
+
+    /// The session id the server assigned the connection to `namespace`, if it's connected.  Only
+    /// sent by servers using v5 of the socket.io protocol.
+    pub fn namespace_sid(&self, namespace: &str) -> Option<String> {
+        self.callbacks.lock().unwrap().get_sid(namespace)
+    }
+
+    /// How often the server said it would send pings.
+    pub fn ping_interval(&self) -> Duration {
+        self.connection.open().ping_interval()
+    }
+
+    /// How long the server said it would wait for a pong before closing the connection.
+    pub fn ping_timeout(&self) -> Duration {
+        self.connection.open().ping_timeout()
+    }
+
+    /// Close the connection.  Events already emitted are sent first, followed by a disconnect
+    /// packet for each connected namespace.  Returns the close frame the server sent, if any,
+    /// which when the server ended the connection tells a clean shutdown (1000) from e.g. a
+    /// policy violation (1008).  There are no close frames when polling.
+    pub async fn close(&mut self) -> Result<Option<CloseReason>, Error> {
+        self.cancel_acks();
         self.connection.close().await
     }
 
+    /// The number of acks still waiting for a response, across all namespaces.  Acks that are
+    /// never answered stay pending until they time out, so a growing count can point to an
+    /// unresponsive server.  See `EventBuilder::ack_timeout`.
+    pub fn pending_acks(&self) -> usize {
+        self.callbacks.lock().unwrap().pending_acks()
+    }
+
+    /// The ids of the acks for the given namespace that are still waiting for a response, sorted.
+    pub fn pending_ack_ids(&self, namespace: &str) -> Vec<u64> {
+        self.callbacks.lock().unwrap().pending_ack_ids(namespace)
+    }
+
+    /// Cancel all acks that are still waiting for a response, returning the namespace and id of
+    /// each.  Ack callbacks are dropped without being called, and futures waiting on an ack
+    /// resolve to `Error::Disconnected`.
+    pub fn cancel_acks(&mut self) -> Vec<(String, u64)> {
+        let acks = self.callbacks.lock().unwrap().take_acks();
+        let mut ids: Vec<_> = acks.into_iter().map(|(ns, id, _)| (ns, id)).collect();
+        ids.sort();
+        ids
+    }
+
+    /// Request a connection to the given namespace.  Use `wait_connected` to wait for the server
+    /// to accept it.  Events emitted to the namespace until then are queued, and sent once it's
+    /// accepted, or dropped if it's refused.  Fails if the namespace would exceed the maximum
+    /// number of namespaces.
+    pub fn connect_namespace(&mut self, namespace: &str) -> Result<(), Error> {
+        {
+            let mut callbacks = self.callbacks.lock().unwrap();
+            callbacks.clear_connect_packet(namespace);
+            callbacks.set_connecting(namespace)?;
+        }
+        self.send.unbounded_send(vec![connect_packet(namespace)])
+    }
+
+    /// Leave the given namespace, keeping the connection open.  Events queued for the namespace
+    /// while it was connecting are dropped, and it isn't connected again when reconnecting.  Acks
+    /// still pending for the namespace are dropped, so futures waiting on them resolve to
+    /// `Error::Disconnected`, and any the server sent before leaving are ignored.  Its callbacks
+    /// are kept, for if it's connected again; use `clear_namespace` to remove them too.
+    pub fn disconnect_namespace(&mut self, namespace: &str) -> Result<(), Error> {
+        {
+            let mut callbacks = self.callbacks.lock().unwrap();
+            callbacks.set_disconnected(namespace);
+            callbacks.clear_connect_packet(namespace);
+            callbacks.clear_acks(namespace);
+        }
+        self.send.unbounded_send(vec![disconnect_packet(namespace)])
+    }
+
+    /// Remove all callbacks and transformers registered for the given namespace and drop its
+    /// pending acks, e.g. once it's been left for good.  The namespace is left first if it's
+    /// connected or connecting.
+    pub fn clear_namespace(&mut self, namespace: &str) -> Result<(), Error> {
+        let state = self.namespace_state(namespace);
+        self.callbacks.lock().unwrap().clear_namespace(namespace);
+        if state == NamespaceState::Disconnected {
+            Ok(())
+        } else {
+            self.send.unbounded_send(vec![disconnect_packet(namespace)])
+        }
+    }
+
+    /// Like `connect_namespace`, with `auth` as the connect packet's payload, for servers that
+    /// check credentials before accepting a connection (socket.io v3 and later).  The same auth
+    /// is sent if the client reconnects.  Fails if the namespace would exceed the maximum number
+    /// of namespaces.
+    pub fn connect_namespace_with_auth<T>(&mut self, namespace: &str, auth: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        let msg = match socket::serialize_connect_with_auth(namespace, auth)? {
+            EngineMessage::Text(text) => engine::package_message(text.to_string()),
+            EngineMessage::Binary(_) => unreachable!(),
+        };
+        {
+            let mut callbacks = self.callbacks.lock().unwrap();
+            callbacks.set_connect_packet(namespace, msg.clone())?;
+            callbacks.set_connecting(namespace)?;
+        }
+        self.send.unbounded_send(vec![msg])
+    }
+
+    /// Send an already serialized packet, e.g. one received on another connection, as is.  The
+    /// messages must be the complete packet, the text message with the engine.io and socket.io
+    /// headers followed by any binary attachments.  Only control frames are rejected, the caller
+    /// is responsible for the messages being a valid packet, and for any ack id it contains.
+    pub fn forward_raw(&mut self, msgs: Vec<WsMessage>) -> Result<(), Error> {
+        if let Some(msg) = msgs.iter().find(|msg| !(msg.is_text() || msg.is_binary())) {
+            return Err(Error::ControlFrame(msg.clone()));
+        }
+        self.send.unbounded_send(msgs)
+    }
+
+    /// Send `msgs` on the connection as is, after anything already emitted, first waiting for
+    /// room if `ClientOptions::send_buffer` limits the packets waiting to be sent.  Nothing is
+    /// checked: unlike `forward_raw` this also sends control frames, and the caller is
+    /// responsible for the messages being valid.  Fails with `Error::Disconnected` if the
+    /// connection has ended.
+    pub async fn send_raw(&self, msgs: Vec<WsMessage>) -> Result<(), Error> {
+        self.send.ready().await?;
+        self.send.unbounded_send(msgs)
+    }
+
+    /// Wait until the server accepts a connection to the given namespace, returning immediately if
+    /// it already has.  Fails with `Error::ConnectRefused` if the server refuses the connection,
+    /// `Error::Timeout` if `timeout` elapses first, or `Error::Disconnected` if the connection
+    /// closes.
+    pub async fn wait_connected(&self, namespace: &str, timeout: Duration) -> Result<(), Error> {
+        let waiter = self.callbacks.lock().unwrap().wait_connected(namespace)?;
+        select! {
+            result = waiter.fuse() => match result {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(message)) => Err(Error::ConnectRefused(message)),
+                Err(_) => Err(Error::Disconnected),
+            },
+            _ = Delay::new(timeout).fuse() => Err(Error::Timeout("namespace connect")),
+        }
+    }
+
+    /// Measure the round trip time to the server, e.g. as an on-demand liveness check, by sending
+    /// a websocket ping frame and waiting for the pong.  Engine.io pings can't be used: since
+    /// protocol v4 only the server sends them, and a server closes the connection if the client
+    /// does.  Websocket pings carry an id, so several can be in flight at once.
+    ///
+    /// The ping is sent straight away, after anything already emitted.  Fails with
+    /// `Error::Timeout` if the pong doesn't arrive within `timeout`, or `Error::Disconnected` if
+    /// the client isn't connected or the connection is lost first.  A session using
+    /// `Transport::Polling` has no websocket to ping until it's upgraded, so this fails straight
+    /// away with `Error::PingWhilePolling`.
+    pub fn ping(&self, timeout: Duration) -> impl Future<Output = Result<Duration, Error>> {
+        let callbacks = self.callbacks.clone();
+        let (id, pong) = callbacks.lock().unwrap().add_ping();
+        let start = Instant::now();
+        let ping = WsMessage::Ping(id.to_be_bytes().to_vec());
+        let polling = self.transport() == Transport::Polling;
+        let sent = !polling && self.is_connected() && self.send.unbounded_send(vec![ping]).is_ok();
+        async move {
+            let result = if polling {
+                Err(Error::PingWhilePolling)
+            } else if !sent {
+                Err(Error::Disconnected)
+            } else {
+                select! {
+                    result = pong.fuse() => return result
+                        .map(|()| start.elapsed())
+                        .map_err(|_| Error::Disconnected),
+                    _ = Delay::new(timeout).fuse() => Err(Error::Timeout("pong")),
+                }
+            };
+            callbacks.lock().unwrap().take_ping(id);
+            result
+        }
+    }
+
+    pub(crate) fn spawn_timer(&self, timer: impl Future<Output = ()> + Send + 'static) {
+        self.connection.spawn_timer(timer.boxed());
+    }
+
+    pub(crate) fn allocate_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
     /// Create an `EmitBuilder` to emit an event for the given namespace.
     pub fn namespace_emit<'a: 'd, 'b: 'd, 'c: 'd, 'd>(
         &'a mut self,
@@ -179,14 +638,45 @@ impl Client {
         EventBuilder::new(self, event, namespace)
     }
 
-    /// Equivalent to `namespace_emit("/", event)`.
+    /// Equivalent to `namespace_emit(namespace, event)` for the default namespace, see
+    /// `set_default_namespace`.
     pub fn emit<'a: 'c, 'b: 'c, 'c>(&'a mut self, event: &'b str) -> EventBuilder<'c> {
-        self.namespace_emit("/", event)
+        let namespace = self.default_namespace.clone();
+        EventBuilder::new(self, event, namespace)
+    }
+
+    /// Set the namespace that `emit`, `send_message`, `set_event_callback` and the other methods
+    /// without a namespace argument use, for servers whose main logic is on a namespace other
+    /// than "/".  The namespace still has to be connected with `connect_namespace`.  Defaults to
+    /// "/".
+    pub fn set_default_namespace(&mut self, namespace: &str) {
+        self.default_namespace = namespace.to_string();
+    }
+
+    /// The namespace set with `set_default_namespace`.
+    pub fn default_namespace(&self) -> &str {
+        &self.default_namespace
+    }
+
+    /// Emit `data` as the only argument of a "message" event to the default namespace, the event
+    /// reserved for `socket.send` in the JavaScript client and received with
+    /// `socket.on("message", ...)`.
+    pub async fn send_message<T>(&mut self, data: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.emit("message").emit_one(data).await
+    }
+
+    /// Start a `Batch` of events to send together, e.g. a burst of updates.
+    pub fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
     }
 
     fwd_cbs! {
-        /// Set the callback for messages received to this namespace and event.
-        set event(event: &str, callback: impl Into<EventCallback>)
+        /// Set the callback for messages received to this namespace and event.  Fails if the
+        /// namespace would exceed the maximum number of namespaces.
+        set event(event: &str, callback: impl Into<EventCallback>) -> Result<(), Error>
     }
     fwd_cbs! {
         /// Clears any callback set for messages received to this namespace and event,
@@ -195,13 +685,171 @@ impl Client {
     }
     fwd_cbs! {
         /// Set the fallback callback for this namespace, which will be called for messages for any
-        /// event without a callback set.
-        set fallback(callback: impl Into<EventCallback>)
+        /// event without a callback set.  Fails if the namespace would exceed the maximum number
+        /// of namespaces.
+        set fallback(callback: impl Into<EventCallback>) -> Result<(), Error>
     }
     fwd_cbs! {
         /// Clears the fallback callback for this namespace.
         clear fallback()
     }
+    fwd_cbs! {
+        /// Set the callback called with the namespace when the server accepts a connection to it.
+        /// Fails if the namespace would exceed the maximum number of namespaces.
+        set connect(callback: impl Into<ConnectCallback>) -> Result<(), Error>
+    }
+    fwd_cbs! {
+        /// Clears the connect callback for this namespace.
+        clear connect()
+    }
+    fwd_cbs! {
+        /// Set the callback called with the namespace and the server's error message when the
+        /// server refuses a connection to it, e.g. because authentication failed.  The connection
+        /// itself stays open.  Fails if the namespace would exceed the maximum number of
+        /// namespaces.
+        set connect_error(callback: impl Into<ConnectErrorCallback>) -> Result<(), Error>
+    }
+    fwd_cbs! {
+        /// Clears the connect error callback for this namespace.
+        clear connect_error()
+    }
+    fwd_cbs! {
+        /// Set the callback called with the namespace when the server disconnects the client from
+        /// it.  Not called when the whole connection is lost.  Fails if the namespace would exceed
+        /// the maximum number of namespaces.
+        set disconnect(callback: impl Into<DisconnectCallback>) -> Result<(), Error>
+    }
+    fwd_cbs! {
+        /// Clears the disconnect callback for this namespace.
+        clear disconnect()
+    }
+
+    fwd_cbs! {
+        /// Add a transformer for messages received to this namespace and event, which can modify
+        /// the arguments before they're passed to the callback.  Transformers run in the order
+        /// they were added, each seeing the changes made by the ones before it.  The first
+        /// argument is the event name, which is already used to find the transformers and
+        /// callback, so changing it doesn't reroute the message.  Fails if the namespace would
+        /// exceed the maximum number of namespaces.
+        add_namespace_event_transformer add_event_transformer add_transformer
+        "add_namespace_event_transformer(namespace, event, transformer)",
+        (event: &str, transformer: impl Into<EventTransformer>) -> Result<(), Error>
+    }
+    fwd_cbs! {
+        /// Clears all transformers added for messages received to this namespace and event.
+        clear_namespace_event_transformers clear_event_transformers clear_transformers
+        "clear_namespace_event_transformers(namespace, event)",
+        (event: &str)
+    }
+
+    /// Set the client's context, shared application state that callbacks added with
+    /// `set_event_callback_with_context` receive a reference to.  The context is shared with
+    /// callbacks that may be called from other threads, so it must be `Send + Sync`, and needs its
+    /// own synchronization (e.g. a `Mutex` or atomics) to be modified.  Replaces any context
+    /// already set, which callbacks see from the next event they handle.
+    pub fn set_context<C>(&mut self, context: C)
+    where
+        C: 'static + Send + Sync,
+    {
+        *self.context.lock().unwrap() = Some(Arc::new(context));
+    }
+
+    /// Like `set_context`, but returns the client for chaining.
+    pub fn with_context<C>(mut self, context: C) -> Self
+    where
+        C: 'static + Send + Sync,
+    {
+        self.set_context(context);
+        self
+    }
+
+    /// The client's context, or `None` if it isn't set or isn't a `C`.
+    pub fn context<C>(&self) -> Option<Arc<C>>
+    where
+        C: 'static + Send + Sync,
+    {
+        self.context.lock().unwrap().clone()?.downcast().ok()
+    }
+
+    /// Set the callback for messages received to this namespace and event, which also receives
+    /// the client's context.  If the context isn't set or isn't a `C` when a message is received,
+    /// the callback isn't called and a warning is logged.  Fails if the namespace would exceed the
+    /// maximum number of namespaces.
+    pub fn set_namespace_event_callback_with_context<C, F>(
+        &mut self,
+        namespace: &str,
+        event: &str,
+        mut callback: F,
+    ) -> Result<(), Error>
+    where
+        C: 'static + Send + Sync,
+        F: 'static + Send + FnMut(&C, &protocol::Args, Option<AckBuilder>),
+    {
+        let context = self.context.clone();
+        let name = event.to_string();
+        let callback = move |args: &protocol::Args, ack| {
+            let current = context.lock().unwrap().clone();
+            match current.and_then(|c| c.downcast::<C>().ok()) {
+                Some(c) => callback(&c, args, ack),
+                None => log::warn!(
+                    "Context for {} callback isn't set or has the wrong type",
+                    name
+                ),
+            }
+        };
+        self.set_namespace_event_callback(namespace, event, callback)
+    }
+
+    /// Equivalent to `set_namespace_event_callback_with_context(namespace, event, callback)` for
+    /// the default namespace, see `set_default_namespace`.
+    pub fn set_event_callback_with_context<C, F>(
+        &mut self,
+        event: &str,
+        callback: F,
+    ) -> Result<(), Error>
+    where
+        C: 'static + Send + Sync,
+        F: 'static + Send + FnMut(&C, &protocol::Args, Option<AckBuilder>),
+    {
+        let namespace = self.default_namespace.clone();
+        self.set_namespace_event_callback_with_context(&namespace, event, callback)
+    }
+
+    /// Set the fallback callback for all namespaces, which will be called with the namespace and
+    /// event name for messages without an event callback or namespace fallback callback set.
+    pub fn set_global_fallback_callback(&mut self, callback: impl Into<GlobalFallbackCallback>) {
+        self.callbacks.lock().unwrap().set_global_fallback(callback)
+    }
+
+    /// Clears the global fallback callback.
+    pub fn clear_global_fallback_callback(&mut self) {
+        self.callbacks.lock().unwrap().clear_global_fallback()
+    }
+
+    /// Set the callback for errors that occur while handling received data, but that don't close
+    /// the connection.
+    pub fn set_error_callback(&mut self, callback: impl Into<ErrorCallback>) {
+        self.callbacks.lock().unwrap().set_error(callback)
+    }
+
+    /// Clears the error callback, errors will be logged instead.
+    pub fn clear_error_callback(&mut self) {
+        self.callbacks.lock().unwrap().clear_error()
+    }
+}
+
+fn connect_packet(namespace: &str) -> WsMessage {
+    match socket::serialize_connect(namespace) {
+        EngineMessage::Text(text) => engine::package_message(text.to_string()),
+        EngineMessage::Binary(_) => unreachable!(),
+    }
+}
+
+fn disconnect_packet(namespace: &str) -> WsMessage {
+    match socket::serialize_disconnect(namespace) {
+        EngineMessage::Text(text) => engine::package_message(text.to_string()),
+        EngineMessage::Binary(_) => unreachable!(),
+    }
 }
 
 fn parse_url(url: &str) -> Result<Url, UrlError> {
@@ -219,15 +867,12 @@ fn parse_url(url: &str) -> Result<Url, UrlError> {
     Ok(url)
 }
 
-fn add_socketio_query_params(url: &mut Url) {
-    url.query_pairs_mut()
-        .append_pair("EIO", "4")
-        .append_pair("transport", "websocket");
-}
-
 #[cfg(test)]
 mod tests {
+    use futures::{future, sink::SinkExt, stream::StreamExt};
+
     use super::*;
+    use crate::spawn::TokioSpawner;
 
     #[test]
     fn test_parse_url() {
@@ -239,4 +884,677 @@ mod tests {
         let p = parse_url("localhost:8000");
         assert_eq!(format!("{:?}", p), "Err(InvalidScheme(\"localhost\"))");
     }
+
+    #[tokio::test]
+    async fn test_global_fallback() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let (tx, mut rx) = mpsc::unbounded();
+        let event_tx = tx.clone();
+        client
+            .set_event_callback("event", move |_args: &protocol::Args, _ack| {
+                event_tx.unbounded_send("event".to_string()).unwrap()
+            })
+            .unwrap();
+        let fallback_tx = tx.clone();
+        client
+            .set_namespace_fallback_callback("/nsp", move |_args: &protocol::Args, _ack| {
+                fallback_tx.unbounded_send("fallback".to_string()).unwrap()
+            })
+            .unwrap();
+        client.set_global_fallback_callback(
+            move |namespace: &str, event: &str, args: &protocol::Args, ack: Option<AckBuilder>| {
+                let msg = format!("global {} {} {} {}", namespace, event, args, ack.is_some());
+                tx.unbounded_send(msg).unwrap()
+            },
+        );
+
+        server.send(r#"42["event"]"#).await;
+        server.send(r#"42/nsp,["event"]"#).await;
+        server.send(r#"421["other",1]"#).await;
+        server.send(r#"42/other,["event"]"#).await;
+        let received: Vec<_> = rx.by_ref().take(4).collect().await;
+        assert_eq!(
+            received,
+            vec![
+                "event",
+                "fallback",
+                r#"global / other ["other", 1] true"#,
+                r#"global /other event ["event"] false"#,
+            ]
+        );
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_events_stream() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let mut events = client.events();
+        let (tx, mut rx) = mpsc::unbounded();
+        client
+            .set_event_callback("handled", move |_args: &protocol::Args, _ack| {
+                tx.unbounded_send(()).unwrap()
+            })
+            .unwrap();
+
+        server.send(r#"42["handled"]"#).await;
+        server.send(r#"42/nsp,3["event",1]"#).await;
+        rx.next().await.unwrap();
+        let event = events.next().await.unwrap();
+        assert_eq!(event.namespace, "/nsp");
+        assert_eq!(event.event, "event");
+        assert_eq!(event.args.to_string(), r#"["event", 1]"#);
+        event.ack.unwrap().args().arg("ok").unwrap().send().unwrap();
+        assert_eq!(server.recv_text().await, r#"43/nsp,3["ok"]"#);
+
+        // Only the latest receiver gets events
+        let mut latest = client.events();
+        assert_eq!(events.next().await.map(|event| event.event), None);
+        server.send(r#"42["other"]"#).await;
+        assert_eq!(latest.next().await.unwrap().event, "other");
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_event_transformers() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let (tx, mut rx) = mpsc::unbounded();
+        client
+            .set_event_callback("event", move |args: &protocol::Args, _ack| {
+                tx.unbounded_send(args.to_string()).unwrap()
+            })
+            .unwrap();
+        client
+            .add_event_transformer("event", |args: &mut protocol::OwnedArgs| {
+                args.push("added").unwrap()
+            })
+            .unwrap();
+        client
+            .add_event_transformer("event", |args: &mut protocol::OwnedArgs| {
+                let len = args.len();
+                args.set(1, &len).unwrap()
+            })
+            .unwrap();
+
+        server.send(r#"42["event",0]"#).await;
+        assert_eq!(rx.next().await.unwrap(), r#"["event", 3, "added"]"#);
+
+        client.clear_event_transformers("event");
+        server.send(r#"42["event",0]"#).await;
+        assert_eq!(rx.next().await.unwrap(), r#"["event", 0]"#);
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_closed_during_handshake() {
+        use async_tungstenite::WebSocketStream;
+
+        // The server accepts the upgrade, then does something other than send the open packet
+        async fn connect<F, Fut>(server: F) -> Result<Client, Error>
+        where
+            F: FnOnce(WebSocketStream<harness::MemStream>) -> Fut,
+            Fut: Future<Output = ()>,
+        {
+            let (client, server_stream) = harness::duplex();
+            let server = async move {
+                server(
+                    async_tungstenite::accept_async(server_stream)
+                        .await
+                        .unwrap(),
+                )
+                .await
+            };
+            let (result, _) = future::join(
+                Client::from_stream("http://localhost/", client, &TokioSpawner),
+                server,
+            )
+            .await;
+            result
+        }
+
+        // Server restarted
+        let result = connect(|ws| async move { drop(ws) }).await;
+        match result {
+            Err(Error::ClosedDuringHandshake(Some(e))) => {
+                assert!(matches!(*e, Error::WebsocketError(_)))
+            }
+            _ => panic!("Expected closed during handshake"),
+        }
+
+        let result = connect(|mut ws| async move {
+            ws.close(None).await.unwrap();
+            while ws.next().await.is_some() {}
+        })
+        .await;
+        assert!(matches!(result, Err(Error::ClosedDuringHandshake(None))));
+
+        let result = connect(|mut ws| async move {
+            ws.send(WsMessage::Text("x".to_string())).await.unwrap();
+            while ws.next().await.is_some() {}
+        })
+        .await;
+        match result {
+            Err(Error::ClosedDuringHandshake(Some(e))) => {
+                assert!(matches!(*e, Error::ProcessingError(_)))
+            }
+            _ => panic!("Expected closed during handshake"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_timeout() {
+        // The server accepts the upgrade but never sends the open packet
+        let (client, server_stream) = harness::duplex();
+        let server = async move {
+            let mut ws = async_tungstenite::accept_async(server_stream)
+                .await
+                .unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        };
+        let options = ClientOptions::new().timeout(Duration::from_millis(50));
+        let (result, _) = future::join(
+            Client::from_stream_with_options("http://localhost/", options, client, &TokioSpawner),
+            server,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(Error::Timeout("engine.io protocol Open message"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let connect = |_, _| future::pending::<Result<harness::MemStream, std::io::Error>>();
+        let result = Client::connect_with_timeout(
+            "http://localhost/",
+            connect,
+            Duration::from_millis(50),
+            &TokioSpawner,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Timeout("connect"))));
+
+        let connect = |_: &Url| future::pending::<Result<harness::MemStream, std::io::Error>>();
+        let options = ClientOptions::new().timeout(Duration::from_millis(50));
+        let result =
+            Client::connect_url_with_options("http://localhost/", options, connect, &TokioSpawner)
+                .await;
+        assert!(matches!(result, Err(Error::Timeout("connect"))));
+    }
+
+    #[tokio::test]
+    async fn test_connect_url() {
+        let (client, server) = harness::duplex();
+        let connect = |url: &Url| {
+            assert_eq!(url.as_str(), "ws://example.com:8000/socket.io/");
+            future::ready(Ok::<_, std::io::Error>(client))
+        };
+        let (client, server) = future::join(
+            Client::connect_url("http://example.com:8000/socket.io/", connect, &TokioSpawner),
+            harness::Server::accept(server),
+        )
+        .await;
+        harness::close(client.unwrap(), server).await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_not_send() {
+        use std::{cell::RefCell, rc::Rc};
+
+        // Neither the closure nor its future are `Send`, which `connect` allows as it only calls
+        // the closure once.
+        let (client, server) = harness::duplex();
+        let stream = Rc::new(RefCell::new(Some(client)));
+        let connect = move |host: Host, port: Port| {
+            assert_eq!((host.as_str(), port), ("example.com", 8000));
+            let stream = stream.clone();
+            async move { Ok::<_, std::io::Error>(stream.borrow_mut().take().unwrap()) }
+        };
+        let (client, server) = future::join(
+            Client::connect("http://example.com:8000/socket.io/", connect, &TokioSpawner),
+            harness::Server::accept(server),
+        )
+        .await;
+        harness::close(client.unwrap(), server).await;
+    }
+
+    #[tokio::test]
+    async fn test_context() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Default)]
+        struct Counter(AtomicUsize);
+
+        let (client, mut server) = harness::connect().await;
+        let mut client = client.with_context(Counter::default());
+
+        let (tx, mut rx) = mpsc::unbounded();
+        for event in &["a", "b"] {
+            let tx = tx.clone();
+            client
+                .set_event_callback_with_context(
+                    event,
+                    move |counter: &Counter, _args: &protocol::Args, _ack| {
+                        let count = counter.0.fetch_add(1, Ordering::SeqCst) + 1;
+                        tx.unbounded_send(count).unwrap();
+                    },
+                )
+                .unwrap();
+        }
+
+        server.send(r#"42["a"]"#).await;
+        server.send(r#"42["b"]"#).await;
+        assert_eq!(rx.by_ref().take(2).collect::<Vec<_>>().await, vec![1, 2]);
+        assert_eq!(
+            client
+                .context::<Counter>()
+                .unwrap()
+                .0
+                .load(Ordering::SeqCst),
+            2
+        );
+        assert!(client.context::<String>().is_none());
+
+        // With a context of the wrong type the callbacks aren't called
+        client.set_context("wrong".to_string());
+        server.send(r#"42["a"]"#).await;
+        client.set_context(Counter::default());
+        server.send(r#"42["a"]"#).await;
+        assert_eq!(rx.next().await.unwrap(), 1);
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_open() {
+        let (client, server) = harness::connect().await;
+        assert_eq!(client.sid(), "harness");
+        assert_eq!(client.ping_interval(), Duration::from_secs(25));
+        assert_eq!(client.ping_timeout(), Duration::from_secs(5));
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_http_handshake_error() {
+        use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, mut server) = harness::duplex();
+        let server = async move {
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = server.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            let response = "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 30\r\n\r\n";
+            server.write_all(response.as_bytes()).await.unwrap();
+            server
+        };
+        let (result, _server) = future::join(
+            Client::from_stream("http://localhost/", client, &TokioSpawner),
+            server,
+        )
+        .await;
+        match result {
+            Err(Error::HttpHandshake {
+                status,
+                retry_after,
+            }) => {
+                assert_eq!(status, 429);
+                assert_eq!(retry_after, Some(Duration::from_secs(30)));
+            }
+            _ => panic!("Expected handshake error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_raw() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let packet = vec![
+            WsMessage::Text(r#"451-["event",{"_placeholder":true,"num":0}]"#.to_string()),
+            WsMessage::Binary(vec![4, 1, 2]),
+        ];
+        client.forward_raw(packet.clone()).unwrap();
+        assert!(matches!(
+            client.forward_raw(vec![WsMessage::Ping(Vec::new())]),
+            Err(Error::ControlFrame(WsMessage::Ping(_)))
+        ));
+        assert_eq!(server.recv().await.as_ref(), Some(&packet[0]));
+        assert_eq!(server.recv().await.as_ref(), Some(&packet[1]));
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_send_raw() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let msg = WsMessage::Text("40/nsp,".to_string());
+        client.send_raw(vec![msg.clone()]).await.unwrap();
+        assert_eq!(server.recv().await, Some(msg));
+
+        harness::close_client(&mut client, server).await;
+        assert!(matches!(
+            client
+                .send_raw(vec![WsMessage::Text("2".to_string())])
+                .await,
+            Err(Error::Disconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_wait_connected() {
+        let (mut client, mut server) = harness::connect().await;
+
+        client.connect_namespace("/nsp").unwrap();
+        assert_eq!(server.recv_text().await, "40/nsp,");
+        let timeout = Duration::from_secs(5);
+        let (result, _) = future::join(
+            client.wait_connected("/nsp", timeout),
+            server.send(r#"40/nsp,{"sid":"abc"}"#),
+        )
+        .await;
+        result.unwrap();
+        // Already connected, so resolves immediately
+        client.wait_connected("/nsp", timeout).await.unwrap();
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_disconnect_callbacks() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let (tx, mut rx) = mpsc::unbounded();
+        let connect_tx = tx.clone();
+        client
+            .set_namespace_connect_callback("/nsp", move |namespace: &str| {
+                connect_tx
+                    .unbounded_send(format!("connect {}", namespace))
+                    .unwrap()
+            })
+            .unwrap();
+        client
+            .set_namespace_disconnect_callback("/nsp", move |namespace: &str| {
+                tx.unbounded_send(format!("disconnect {}", namespace))
+                    .unwrap()
+            })
+            .unwrap();
+
+        server.send(r#"40/nsp,{"sid":"abc"}"#).await;
+        assert_eq!(rx.next().await.unwrap(), "connect /nsp");
+        assert_eq!(client.namespace_sid("/nsp"), Some("abc".to_string()));
+        server.send("41/nsp,").await;
+        assert_eq!(rx.next().await.unwrap(), "disconnect /nsp");
+        assert_eq!(client.namespace_sid("/nsp"), None);
+
+        // Other namespaces don't call the callbacks, and cleared callbacks aren't called
+        client.clear_namespace_connect_callback("/nsp");
+        server.send(r#"40/other,{"sid":"abc"}"#).await;
+        server.send(r#"40/nsp,{"sid":"abc"}"#).await;
+        server.send("41/nsp,").await;
+        assert_eq!(rx.next().await.unwrap(), "disconnect /nsp");
+
+        harness::close(client, server).await;
+        assert_eq!(rx.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let (mut client, mut server) = harness::connect().await;
+
+        // The server answers pings while it reads
+        let timeout = Duration::from_secs(5);
+        let pings = future::join(client.ping(timeout), client.ping(timeout));
+        let reading = async {
+            loop {
+                server.recv().await;
+            }
+        };
+        let (rtts, _) = match future::select(Box::pin(pings), Box::pin(reading)).await {
+            future::Either::Left(result) => result,
+            future::Either::Right(_) => unreachable!(),
+        };
+        assert!(rtts.0.unwrap() < timeout);
+        assert!(rtts.1.unwrap() < timeout);
+
+        // Without a pong the ping times out
+        let result = client.ping(Duration::from_millis(10)).await;
+        assert!(matches!(result, Err(Error::Timeout("pong"))));
+
+        harness::close_client(&mut client, server).await;
+        let result = client.ping(timeout).await;
+        assert!(matches!(result, Err(Error::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn test_clear_namespace() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let (tx, mut rx) = mpsc::unbounded();
+        client
+            .set_namespace_event_callback("/nsp", "event", move |_args: &protocol::Args, _ack| {
+                tx.unbounded_send(()).unwrap()
+            })
+            .unwrap();
+        client.connect_namespace("/nsp").unwrap();
+        assert_eq!(server.recv_text().await, "40/nsp,");
+        server.send(r#"40/nsp,{"sid":"abc"}"#).await;
+        let ack = |client: &mut Client| {
+            client
+                .namespace_emit("/nsp", "ask")
+                .args()
+                .send_with_typed_ack::<String>()
+        };
+
+        // Leaving drops pending acks but keeps callbacks
+        let pending = ack(&mut client);
+        assert_eq!(server.recv_text().await, r#"42/nsp,0["ask"]"#);
+        assert_eq!(client.pending_acks(), 1);
+        assert_eq!(client.pending_ack_ids("/nsp"), vec![0]);
+        client.disconnect_namespace("/nsp").unwrap();
+        assert_eq!(client.pending_acks(), 0);
+        assert_eq!(server.recv_text().await, "41/nsp,");
+        assert!(matches!(pending.await, Err(Error::Disconnected)));
+        // The server answered before it saw the namespace was left, which is ignored
+        server.send(r#"43/nsp,0["late"]"#).await;
+        client.connect_namespace("/nsp").unwrap();
+        assert_eq!(server.recv_text().await, "40/nsp,");
+        server.send(r#"40/nsp,{"sid":"abc"}"#).await;
+        server.send(r#"42/nsp,["event"]"#).await;
+        rx.next().await.unwrap();
+
+        // Clearing leaves the namespace and removes everything
+        let pending = ack(&mut client);
+        assert_eq!(server.recv_text().await, r#"42/nsp,1["ask"]"#);
+        client.clear_namespace("/nsp").unwrap();
+        assert_eq!(server.recv_text().await, "41/nsp,");
+        assert!(matches!(pending.await, Err(Error::Disconnected)));
+        server.send(r#"43/nsp,1["late"]"#).await;
+        assert_eq!(client.namespace_state("/nsp"), NamespaceState::Disconnected);
+        client.clear_namespace("/nsp").unwrap();
+        // The connection survived the late ack
+        client.emit("event").args().send().await.unwrap();
+        assert_eq!(server.recv_text().await, r#"42["event"]"#);
+
+        harness::close(client, server).await;
+        assert_eq!(rx.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_wait_connected_timeout() {
+        let (client, server) = harness::connect().await;
+
+        let result = client
+            .wait_connected("/nsp", Duration::from_millis(10))
+            .await;
+        assert!(matches!(result, Err(Error::Timeout(_))));
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_namespace_queues_emits() {
+        let (mut client, mut server) = harness::connect().await;
+
+        client.connect_namespace("/admin").unwrap();
+        assert_eq!(client.namespace_state("/admin"), NamespaceState::Connecting);
+        let ack = client
+            .namespace_emit("/admin", "queued")
+            .args()
+            .send_with_typed_ack::<String>();
+        client.emit("sent").args().send().await.unwrap();
+        assert_eq!(server.recv_text().await, "40/admin,");
+        assert_eq!(server.recv_text().await, r#"42["sent"]"#);
+
+        server.send(r#"40/admin,{"sid":"abc"}"#).await;
+        assert_eq!(server.recv_text().await, r#"42/admin,0["queued"]"#);
+        assert_eq!(client.namespace_state("/admin"), NamespaceState::Connected);
+        server.send(r#"43/admin,0["ok"]"#).await;
+        assert_eq!(ack.await.unwrap(), "ok");
+
+        // Events queued for a refused namespace are dropped
+        client.connect_namespace("/chat").unwrap();
+        let ack = client
+            .namespace_emit("/chat", "dropped")
+            .args()
+            .send_with_typed_ack::<String>();
+        assert_eq!(server.recv_text().await, "40/chat,");
+        server.send(r#"44/chat,{"message":"Not authorized"}"#).await;
+        assert!(matches!(ack.await, Err(Error::Disconnected)));
+        assert_eq!(
+            client.namespace_state("/chat"),
+            NamespaceState::Disconnected
+        );
+
+        let closed = harness::close(client, server).await;
+        assert!(closed
+            .iter()
+            .all(|msg| !msg.to_string().contains("dropped")));
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let (mut client, mut server) = harness::connect().await;
+
+        client.send_message("hello").await.unwrap();
+        assert_eq!(server.recv_text().await, r#"42["message","hello"]"#);
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_default_namespace() {
+        let (mut client, mut server) = harness::connect().await;
+        let (events_tx, mut events) = mpsc::unbounded();
+
+        client.set_default_namespace("/app");
+        assert_eq!(client.default_namespace(), "/app");
+        client.connect_namespace("/app").unwrap();
+        assert_eq!(server.recv_text().await, "40/app,");
+        server.send(r#"40/app,{"sid":"abc"}"#).await;
+        client
+            .set_event_callback("hello", move |args: &protocol::Args, _ack| {
+                events_tx.unbounded_send(args.to_string()).unwrap()
+            })
+            .unwrap();
+        client.emit("event").args().send().await.unwrap();
+        assert_eq!(server.recv_text().await, r#"42/app,["event"]"#);
+        client.send_message(&1).await.unwrap();
+        assert_eq!(server.recv_text().await, r#"42/app,["message",1]"#);
+
+        server.send(r#"42/app,["hello",1]"#).await;
+        assert_eq!(events.next().await.unwrap(), r#"["hello", 1]"#);
+        // Messages to "/" no longer reach the callback
+        assert!(client
+            .callbacks
+            .lock()
+            .unwrap()
+            .get_event("/", "hello")
+            .is_none());
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_namespaces() {
+        let (mut client, mut server) = harness::connect().await;
+
+        client.connect_namespace("/a").unwrap();
+        client.connect_namespace("/b").unwrap();
+        assert_eq!(server.recv_text().await, "40/a,");
+        assert_eq!(server.recv_text().await, "40/b,");
+        server.send("40/a,").await;
+        server.send("40/b,").await;
+        client
+            .wait_connected("/b", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        client.disconnect_namespace("/b").unwrap();
+        assert_eq!(server.recv_text().await, "41/b,");
+        assert_eq!(client.namespace_state("/b"), NamespaceState::Disconnected);
+
+        // Closing sends what was already emitted, then leaves the connected namespaces
+        client.emit("last").args().send().await.unwrap();
+        let closed = harness::close(client, server).await;
+        let closed: Vec<_> = closed.iter().map(|msg| msg.to_string()).collect();
+        assert_eq!(closed, vec![r#"42["last"]"#, "41/a,", "1"]);
+    }
+
+    #[tokio::test]
+    async fn test_wait_connected_error() {
+        let (client, mut server) = harness::connect().await;
+
+        let (result, _) = future::join(
+            client.wait_connected("/nsp", Duration::from_secs(5)),
+            server.send(r#"44/nsp,{"message":"Not authorized"}"#),
+        )
+        .await;
+        match result {
+            Err(Error::ConnectRefused(message)) => assert_eq!(message, "Not authorized"),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // Dropping the server ends the connection, cancelling anything waiting on it
+        let (result, _) = future::join(
+            client.wait_connected("/other", Duration::from_secs(5)),
+            async move { drop(server) },
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn test_connect_error_callback() {
+        let (mut client, mut server) = harness::connect().await;
+
+        let (tx, mut rx) = mpsc::unbounded();
+        client
+            .set_namespace_connect_error_callback("/nsp", move |namespace: &str, message: &str| {
+                tx.unbounded_send(format!("{}: {}", namespace, message))
+                    .unwrap()
+            })
+            .unwrap();
+
+        server
+            .send(r#"44/nsp,{"message":"Not authorized","data":{"code":1}}"#)
+            .await;
+        assert_eq!(rx.next().await.unwrap(), "/nsp: Not authorized");
+        // The connection is still usable after a refused namespace
+        assert!(client.is_connected());
+        server.send(r#"44/nsp,"Invalid namespace""#).await;
+        assert_eq!(rx.next().await.unwrap(), "/nsp: Invalid namespace");
+
+        client.clear_namespace_connect_error_callback("/nsp");
+        harness::close(client, server).await;
+        assert_eq!(rx.next().await, None);
+    }
 }
@@ -0,0 +1,655 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_tungstenite::tungstenite::{
+    handshake::client::Request,
+    http::{header, HeaderMap, HeaderValue, Uri},
+    Error as WsError,
+};
+use url::Url;
+
+use socket_io_protocol::{engine::EngineVersion, socket};
+
+use super::{ReconnectConfig, DEFAULT_MAX_NAMESPACES};
+
+/// The path socket.io servers are served at by default.
+const DEFAULT_SOCKET_IO_PATH: &str = "/socket.io/";
+
+/// How long connecting and closing can take by default, the same as the JavaScript client's
+/// default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How packets are sent to and received from the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// A websocket.
+    Websocket,
+    /// HTTP long-polling, for networks where websockets are blocked, e.g. by a proxy.  Each
+    /// request is sent on a stream from the `connect` closure, so clients using this transport
+    /// must be created with `Client::connect_reconnecting`.  The session is upgraded to a
+    /// websocket if the server offers it, unless disabled with `ClientOptions::upgrade`.
+    /// `Client::ping` fails with `Error::PingWhilePolling` until it's upgraded.
+    Polling,
+}
+
+impl Transport {
+    fn query_param(self) -> &'static str {
+        match self {
+            Transport::Websocket => "websocket",
+            Transport::Polling => "polling",
+        }
+    }
+}
+
+/// Options controlling how a `Client` connects to the server.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    user_agent: Option<String>,
+    headers: Vec<(String, String)>,
+    path: Option<String>,
+    socket_io_path: Option<String>,
+    transport: Transport,
+    upgrade: bool,
+    query: Vec<(String, String)>,
+    max_namespaces: usize,
+    max_attachments: u64,
+    send_close_packet: bool,
+    event_workers: usize,
+    send_buffer: Option<usize>,
+    engine_version: EngineVersion,
+    eio_param: bool,
+    transport_param: bool,
+    cache_buster: bool,
+    b64_param: bool,
+    reconnect: Option<ReconnectConfig>,
+    timeout: Duration,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            user_agent: None,
+            headers: Vec::new(),
+            path: None,
+            socket_io_path: None,
+            transport: Transport::Websocket,
+            upgrade: true,
+            query: Vec::new(),
+            max_namespaces: DEFAULT_MAX_NAMESPACES,
+            max_attachments: socket::DEFAULT_MAX_ATTACHMENTS,
+            send_close_packet: true,
+            event_workers: 0,
+            send_buffer: None,
+            engine_version: EngineVersion::V4,
+            eio_param: true,
+            transport_param: true,
+            cache_buster: false,
+            b64_param: false,
+            reconnect: None,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl ClientOptions {
+    pub fn new() -> Self {
+        ClientOptions::default()
+    }
+
+    /// Set the maximum number of namespaces the client stores callbacks and connection state
+    /// for, which bounds the memory a misbehaving server can make the client use.  Defaults to
+    /// `DEFAULT_MAX_NAMESPACES`.
+    pub fn max_namespaces(mut self, max_namespaces: usize) -> Self {
+        self.max_namespaces = max_namespaces;
+        self
+    }
+
+    pub(crate) fn get_max_namespaces(&self) -> usize {
+        self.max_namespaces
+    }
+
+    /// Set the most attachments a binary packet from the server can have.  A packet with more is
+    /// a protocol error, rather than the client waiting for attachments that may never come.
+    /// Defaults to `socket::DEFAULT_MAX_ATTACHMENTS`.
+    pub fn max_attachments(mut self, max_attachments: u64) -> Self {
+        self.max_attachments = max_attachments;
+        self
+    }
+
+    pub(crate) fn get_max_attachments(&self) -> u64 {
+        self.max_attachments
+    }
+
+    /// Set whether closing the client sends an engine.io close packet before the websocket close
+    /// frame, telling the server the client is leaving rather than the transport being lost.
+    /// Defaults to `true`.
+    pub fn send_close_packet(mut self, send: bool) -> Self {
+        self.send_close_packet = send;
+        self
+    }
+
+    pub(crate) fn get_send_close_packet(&self) -> bool {
+        self.send_close_packet
+    }
+
+    /// Set the number of tasks event callbacks are called on.  By default they're called on the
+    /// connection's task, so a slow callback delays handling everything after it, including
+    /// responding to the server's pings, which can make the server time the connection out.  With
+    /// workers, event callbacks (and transformers) are spawned on the executor instead, so they
+    /// only run in parallel on a multithreaded executor.
+    ///
+    /// Each event (by namespace and name) is always handled by the same worker, so an event's
+    /// callbacks are called in the order the events were received, while different events can
+    /// be handled concurrently and out of order relative to each other.  Ack callbacks and
+    /// namespace connection updates are still handled on the connection's task, so can be
+    /// handled before an event received earlier.  Defaults to 0, calling event callbacks on the
+    /// connection's task.
+    pub fn event_workers(mut self, workers: usize) -> Self {
+        self.event_workers = workers;
+        self
+    }
+
+    pub(crate) fn get_event_workers(&self) -> usize {
+        self.event_workers
+    }
+
+    /// Limit the number of emitted packets waiting to be sent to `capacity`, so a producer
+    /// emitting faster than the connection can write waits in `EventArgsBuilder::send`, or in the
+    /// future from `EventArgsBuilder::send_with_ack`, instead of queueing packets without bound.
+    /// Nothing is sent while reconnecting, so once the buffer fills emitting waits until the
+    /// connection is back.  Defaults to no limit.
+    pub fn send_buffer(mut self, capacity: usize) -> Self {
+        self.send_buffer = Some(capacity);
+        self
+    }
+
+    pub(crate) fn get_send_buffer(&self) -> Option<usize> {
+        self.send_buffer
+    }
+
+    /// Fail with `Error::Timeout` if the connection doesn't open within `timeout`, i.e. if the
+    /// handshake or the server's engine.io open packet takes longer, e.g. because the server
+    /// accepted the websocket but never sends the open packet.  Opening the stream with the
+    /// `connect` closure is bounded separately by the same duration, while for reconnect
+    /// attempts the whole attempt is.  Also bounds how long closing the client waits for the
+    /// connection to close.  Defaults to `DEFAULT_TIMEOUT`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub(crate) fn get_timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Set the engine.io protocol version to speak, which must match the server's: v3 for
+    /// socket.io 2 servers, v4 for socket.io 3 and later.  Defaults to `EngineVersion::V4`.
+    pub fn engine_version(mut self, version: EngineVersion) -> Self {
+        self.engine_version = version;
+        self
+    }
+
+    pub(crate) fn get_engine_version(&self) -> EngineVersion {
+        self.engine_version
+    }
+
+    /// Set whether the `EIO` query parameter, the engine.io protocol version, is sent.  Defaults
+    /// to `true`.
+    pub fn eio_query_param(mut self, send: bool) -> Self {
+        self.eio_param = send;
+        self
+    }
+
+    /// Set whether the `transport` query parameter, e.g. `transport=websocket`, is sent.  Defaults
+    /// to `true`.
+    pub fn transport_query_param(mut self, send: bool) -> Self {
+        self.transport_param = send;
+        self
+    }
+
+    /// Set whether the `t` query parameter is sent, a timestamp that's unique to each connect so
+    /// caching proxies and CDNs don't serve a stale handshake response.  Defaults to `false`.
+    pub fn cache_buster(mut self, send: bool) -> Self {
+        self.cache_buster = send;
+        self
+    }
+
+    /// Set whether the `b64=1` query parameter is sent, which asks the server to base64 encode
+    /// binary data.  The client can't decode base64 binary packets, so this should only be set
+    /// for servers that require it and don't send binary data.  Defaults to `false`.
+    pub fn b64_query_param(mut self, send: bool) -> Self {
+        self.b64_param = send;
+        self
+    }
+
+    /// Reconnect with `config` when the connection is lost, rather than the client staying
+    /// disconnected.  Each attempt calls the `connect` closure again and starts a new engine.io
+    /// session, callbacks stay set, and namespaces that were connected are connected again.
+    /// Events emitted while reconnecting are sent once a new session opens, but acks still
+    /// waiting when the connection is lost are cancelled.  Only clients created with
+    /// `Client::connect_reconnecting` can reconnect, as the others have no way to open a new
+    /// stream.  Defaults to not reconnecting.
+    pub fn reconnect(mut self, config: ReconnectConfig) -> Self {
+        self.reconnect = Some(config);
+        self
+    }
+
+    pub(crate) fn get_reconnect(&self) -> Option<&ReconnectConfig> {
+        self.reconnect.as_ref()
+    }
+
+    /// Set the path socket.io is served at, e.g. `/ws/` for a server configured with
+    /// `path: "/ws/"`, in place of the url's path.  By default the url's path is used, or
+    /// `/socket.io/`, the default socket.io path, if the url has no path.  Not used when the
+    /// request's path is set with `path`.
+    pub fn socket_io_path(mut self, path: impl Into<String>) -> Self {
+        self.socket_io_path = Some(path.into());
+        self
+    }
+
+    /// Set the path of `url` to the socket.io path.
+    pub(crate) fn set_socket_io_path(&self, url: &mut Url) {
+        match &self.socket_io_path {
+            Some(path) => url.set_path(path),
+            None if url.path() == "/" => url.set_path(DEFAULT_SOCKET_IO_PATH),
+            None => (),
+        }
+    }
+
+    /// Add a query parameter to the handshake url, e.g. an auth token the server reads during
+    /// the handshake.  A parameter with the same name already in the url is replaced rather than
+    /// sent twice.  Parameters are added in the order they're set, after those in the url and
+    /// before the engine.io parameters.  Not added when the request's path is set with `path`.
+    pub fn query_param(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.query.push((name.into(), value.into()));
+        self
+    }
+
+    /// Add the query parameters set with `query_param`, then the engine.io query parameters to
+    /// `url`, in the same order as the reference javascript client: `EIO`, `transport`, `sid`,
+    /// `t`, then `b64`.  Parameters already in `url` come first.
+    pub(crate) fn add_query_params(&self, url: &mut Url, sid: Option<&str>) {
+        let t = if self.cache_buster {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis());
+            Some(next_cache_buster(&CACHE_BUSTER, now))
+        } else {
+            None
+        };
+        self.add_query_params_with(url, sid, t.as_deref())
+    }
+
+    fn add_query_params_with(&self, url: &mut Url, sid: Option<&str>, t: Option<&str>) {
+        let mut pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        for (name, value) in &self.query {
+            match pairs.iter_mut().find(|(n, _)| n == name) {
+                Some(pair) => pair.1 = value.clone(),
+                None => pairs.push((name.clone(), value.clone())),
+            }
+        }
+        let mut query = url.query_pairs_mut();
+        if !self.query.is_empty() {
+            query.clear().extend_pairs(&pairs);
+        }
+        if self.eio_param {
+            query.append_pair("EIO", self.engine_version.query_param());
+        }
+        if self.transport_param {
+            query.append_pair("transport", self.transport.query_param());
+        }
+        if let Some(sid) = sid {
+            query.append_pair("sid", sid);
+        }
+        if let Some(t) = t {
+            query.append_pair("t", t);
+        }
+        // v3 polling payloads can only hold binary packets as base64
+        let v3_polling =
+            self.engine_version == EngineVersion::V3 && self.transport == Transport::Polling;
+        if self.b64_param || v3_polling {
+            query.append_pair("b64", "1");
+        }
+        drop(query);
+        // Appending nothing to a url without a query leaves an empty one, e.g. `/?`
+        if url.query() == Some("") {
+            url.set_query(None);
+        }
+    }
+
+    /// Set the `User-Agent` header sent with the websocket upgrade request, or with each request
+    /// when polling.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Add a header to the websocket upgrade request, or to each request when polling, e.g.
+    /// `Authorization`, `Cookie` or `Origin`.  Adding a header more than once sends each value.
+    /// Invalid names or values fail the connection.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// The headers set with `header` and `user_agent`.
+    pub(crate) fn headers(&self) -> Result<HeaderMap, WsError> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.append(
+                header::HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        if let Some(user_agent) = &self.user_agent {
+            headers.insert(header::USER_AGENT, HeaderValue::from_str(user_agent)?);
+        }
+        Ok(headers)
+    }
+
+    /// Set the path and query of the websocket upgrade request, e.g.
+    /// `/socket.io/?token=abc&EIO=4&transport=websocket`.  This is sent exactly as given instead of
+    /// the path and query of the url, so the `EIO` and `transport` query parameters aren't added
+    /// and must be included if the server expects them.  With `Transport::Polling` it's the path
+    /// and query of each request, with the `sid` query parameter appended after the handshake.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub(crate) fn get_path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Set the transport used to connect.  Defaults to `Transport::Websocket`.
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub(crate) fn get_transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Set whether a session started with `Transport::Polling` is upgraded to a websocket when
+    /// the server lists `websocket` in its open packet's `upgrades`, like the JavaScript client.
+    /// If the server doesn't answer the upgrade probe the session stays on polling.  Sessions
+    /// whose requests are sent to a `path` aren't upgraded, as its query can't be changed to ask
+    /// for a websocket.  Defaults to `true`.
+    pub fn upgrade(mut self, upgrade: bool) -> Self {
+        self.upgrade = upgrade;
+        self
+    }
+
+    pub(crate) fn get_upgrade(&self) -> bool {
+        self.upgrade && self.path.is_none()
+    }
+
+    /// Build the websocket upgrade request for `url`.
+    pub(crate) fn request(&self, url: &Url) -> Result<Request, WsError> {
+        let uri: Uri = match &self.path {
+            Some(path) => {
+                let mut base = url.clone();
+                base.set_path("");
+                base.set_query(None);
+                format!("{}{}", base.as_str().trim_end_matches('/'), path).parse()?
+            }
+            None => url.as_str().parse()?,
+        };
+        let mut request = Request::get(uri).body(())?;
+        *request.headers_mut() = self.headers()?;
+        Ok(request)
+    }
+}
+
+/// The last timestamp used for a cache buster, and how many times it's been reused.
+static CACHE_BUSTER: Mutex<(u128, u128)> = Mutex::new((0, 0));
+
+/// Generate a cache buster from the current time in milliseconds like the `yeast` module the
+/// javascript client uses, which is unique even when called more than once per millisecond.
+fn next_cache_buster(state: &Mutex<(u128, u128)>, now: u128) -> String {
+    let mut state = state.lock().unwrap();
+    let (prev, seed) = &mut *state;
+    let encoded = encode(now);
+    if now != *prev {
+        *prev = now;
+        *seed = 0;
+        encoded
+    } else {
+        let suffix = encode(*seed);
+        *seed += 1;
+        format!("{}.{}", encoded, suffix)
+    }
+}
+
+fn encode(mut timestamp: u128) -> String {
+    const ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz-_";
+    let mut encoded = Vec::new();
+    loop {
+        encoded.push(ALPHABET[(timestamp % 64) as usize]);
+        timestamp /= 64;
+        if timestamp == 0 {
+            break;
+        }
+    }
+    encoded.reverse();
+    String::from_utf8(encoded).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use async_tungstenite::tungstenite::Message as WsMessage;
+
+    use super::*;
+    use crate::harness;
+
+    #[test]
+    fn test_default_request() {
+        let url = Url::parse("ws://localhost:8000/socket.io/?EIO=4").unwrap();
+        let request = ClientOptions::new().request(&url).unwrap();
+        assert_eq!(
+            request.uri().to_string(),
+            "ws://localhost:8000/socket.io/?EIO=4"
+        );
+        assert!(request.headers().is_empty());
+    }
+
+    #[test]
+    fn test_request() {
+        let url = Url::parse("wss://example.com/socket.io/?EIO=4&transport=websocket").unwrap();
+        let request = ClientOptions::new()
+            .user_agent("agent/1.0")
+            .path("/custom/?b=2&a=1&EIO=4&transport=websocket")
+            .request(&url)
+            .unwrap();
+        assert_eq!(
+            request.uri().path_and_query().unwrap().as_str(),
+            "/custom/?b=2&a=1&EIO=4&transport=websocket"
+        );
+        assert_eq!(request.uri().host(), Some("example.com"));
+        assert_eq!(request.headers()[header::USER_AGENT], "agent/1.0");
+
+        assert!(ClientOptions::new()
+            .user_agent("bad\nagent")
+            .request(&url)
+            .is_err());
+        assert!(ClientOptions::new()
+            .header("bad name", "value")
+            .request(&url)
+            .is_err());
+    }
+
+    #[test]
+    fn test_query_params() {
+        let query = |options: ClientOptions, sid, t| {
+            let mut url = Url::parse("ws://localhost/socket.io/?token=abc").unwrap();
+            options.add_query_params_with(&mut url, sid, t);
+            url.query().map(str::to_string)
+        };
+        assert_eq!(
+            query(ClientOptions::new(), None, None).unwrap(),
+            "token=abc&EIO=4&transport=websocket"
+        );
+        let options = ClientOptions::new().b64_query_param(true);
+        assert_eq!(
+            query(options, Some("xyz"), Some("NI7Re00")).unwrap(),
+            "token=abc&EIO=4&transport=websocket&sid=xyz&t=NI7Re00&b64=1"
+        );
+        let options = ClientOptions::new()
+            .eio_query_param(false)
+            .transport_query_param(false);
+        assert_eq!(query(options, None, None).unwrap(), "token=abc");
+        let options = ClientOptions::new()
+            .engine_version(EngineVersion::V3)
+            .transport(Transport::Polling);
+        assert_eq!(
+            query(options, None, None).unwrap(),
+            "token=abc&EIO=3&transport=polling&b64=1"
+        );
+        let options = ClientOptions::new()
+            .query_param("key", "a b")
+            .query_param("token", "def");
+        assert_eq!(
+            query(options, None, None).unwrap(),
+            "token=def&key=a+b&EIO=4&transport=websocket"
+        );
+
+        let mut url = Url::parse("ws://localhost/").unwrap();
+        ClientOptions::new()
+            .eio_query_param(false)
+            .transport_query_param(false)
+            .add_query_params(&mut url, None);
+        assert_eq!(url.as_str(), "ws://localhost/");
+    }
+
+    #[test]
+    fn test_socket_io_path() {
+        let path = |options: ClientOptions, url| {
+            let mut url = Url::parse(url).unwrap();
+            options.set_socket_io_path(&mut url);
+            url.to_string()
+        };
+        assert_eq!(
+            path(ClientOptions::new(), "ws://localhost"),
+            "ws://localhost/socket.io/"
+        );
+        assert_eq!(
+            path(ClientOptions::new(), "ws://localhost/ws/?token=abc"),
+            "ws://localhost/ws/?token=abc"
+        );
+        let options = ClientOptions::new().socket_io_path("/ws/");
+        assert_eq!(
+            path(options, "ws://localhost/socket.io/?token=abc"),
+            "ws://localhost/ws/?token=abc"
+        );
+    }
+
+    #[test]
+    fn test_next_cache_buster() {
+        let state = Mutex::new((0, 0));
+        assert_eq!(next_cache_buster(&state, 1_600_000_000_000), "NI7Re00");
+        assert_eq!(next_cache_buster(&state, 1_600_000_000_000), "NI7Re00.0");
+        assert_eq!(next_cache_buster(&state, 1_600_000_000_000), "NI7Re00.1");
+        assert_eq!(next_cache_buster(&state, 1_600_000_000_001), "NI7Re01");
+    }
+
+    #[tokio::test]
+    async fn test_cache_buster() {
+        let mut ts = Vec::new();
+        for _ in 0..2 {
+            let options = ClientOptions::new().cache_buster(true);
+            let (client, server) = harness::connect_with_options(options).await;
+            let url = Url::parse(&format!("ws://localhost{}", server.path)).unwrap();
+            let (_, t) = url.query_pairs().find(|(k, _)| k == "t").unwrap();
+            ts.push(t.into_owned());
+            harness::close(client, server).await;
+        }
+        assert_ne!(ts[0], ts[1]);
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (client, server) = harness::connect().await;
+        assert_eq!(server.path, "/socket.io/?EIO=4&transport=websocket");
+        assert!(server.headers.get(header::USER_AGENT).is_none());
+        harness::close(client, server).await;
+
+        let options = ClientOptions::new()
+            .user_agent("agent/1.0")
+            .header("Cookie", "a=1")
+            .header("Cookie", "b=2")
+            .header("Authorization", "Bearer abc")
+            .path("/custom/?b=2&a=1&EIO=4&transport=websocket");
+        let (client, server) = harness::connect_with_options(options).await;
+        assert_eq!(server.path, "/custom/?b=2&a=1&EIO=4&transport=websocket");
+        assert_eq!(server.headers[header::USER_AGENT], "agent/1.0");
+        let cookies: Vec<_> = server.headers.get_all(header::COOKIE).iter().collect();
+        assert_eq!(cookies, vec!["a=1", "b=2"]);
+        assert_eq!(server.headers[header::AUTHORIZATION], "Bearer abc");
+        assert_eq!(server.headers[header::HOST], "localhost");
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test(core_threads = 2)]
+    async fn test_event_workers() {
+        use futures::{channel::mpsc, stream::StreamExt};
+        use std::sync::mpsc as std_mpsc;
+
+        use crate::protocol::Args;
+
+        let options = ClientOptions::new().event_workers(2);
+        let (mut client, mut server) = harness::connect_with_options(options).await;
+
+        // The slow callback blocks its worker until released
+        let (release_tx, release_rx) = std_mpsc::channel::<()>();
+        let release_rx = std::sync::Mutex::new(release_rx);
+        let (tx, mut rx) = mpsc::unbounded();
+        let slow_tx = tx.clone();
+        client
+            .set_event_callback("slow", move |args: &Args, _ack| {
+                release_rx.lock().unwrap().recv().unwrap();
+                slow_tx.unbounded_send(args.to_string()).unwrap();
+            })
+            .unwrap();
+        client
+            .set_fallback_callback(move |args: &Args, _ack| {
+                tx.unbounded_send(args.to_string()).unwrap();
+            })
+            .unwrap();
+        let fast = (0..)
+            .map(|i| format!("fast{}", i))
+            .find(|event| {
+                crate::receiver::worker_index("/", event, 2)
+                    != crate::receiver::worker_index("/", "slow", 2)
+            })
+            .unwrap();
+
+        server.send(r#"42["slow",1]"#).await;
+        server.send(r#"42["slow",2]"#).await;
+        server.send(&format!(r#"42["{}"]"#, fast)).await;
+        // Pings are answered while the slow callback is blocked
+        server.send("2").await;
+        assert_eq!(server.recv_text().await, "3");
+        assert_eq!(rx.next().await.unwrap(), format!(r#"["{}"]"#, fast));
+
+        release_tx.send(()).unwrap();
+        release_tx.send(()).unwrap();
+        assert_eq!(rx.next().await.unwrap(), r#"["slow", 1]"#);
+        assert_eq!(rx.next().await.unwrap(), r#"["slow", 2]"#);
+
+        harness::close(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn test_close_packet() {
+        let (client, server) = harness::connect().await;
+        let msgs = harness::close(client, server).await;
+        assert_eq!(msgs, vec![WsMessage::Text("1".to_string())]);
+
+        let options = ClientOptions::new().send_close_packet(false);
+        let (client, server) = harness::connect_with_options(options).await;
+        assert!(harness::close(client, server).await.is_empty());
+    }
+}
@@ -0,0 +1,694 @@
+//! The HTTP long-polling transport, for when websockets are blocked, e.g. by a proxy.  Packets
+//! from the server are received by a loop of GET requests that the server holds open until it
+//! has something to send, and packets to the server are sent as the bodies of POST requests.
+
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use async_tungstenite::{
+    stream::Stream as TlsSwitch,
+    tungstenite::{http::HeaderMap, Message as WsMessage},
+};
+use futures::{
+    future::{BoxFuture, FutureExt},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    ready,
+    sink::Sink,
+    stream::Stream,
+};
+use url::Url;
+
+use socket_io_protocol::engine::{self, Decoder, Packet};
+
+use super::{
+    connection::{retry_after, Connector},
+    receiver, ClientOptions, Error,
+};
+
+/// The most bytes of response headers that are read.
+const MAX_HEAD: usize = 16 * 1024;
+/// The most headers a response can have.
+const MAX_HEADERS: usize = 64;
+/// The most bytes of response body that are read, the same as tungstenite's default limit on
+/// websocket messages.
+const MAX_BODY: usize = 64 << 20;
+
+/// A connection to the server, encrypted if the url is `wss`.
+type Conn<S> = BufReader<TlsSwitch<S, async_tls::client::TlsStream<S>>>;
+
+/// The result of a request: the connection if it can be reused, and the response's payload.
+type Request<S> = BoxFuture<'static, Result<(Option<Conn<S>>, String), Error>>;
+
+/// The packets of an engine.io session over HTTP long-polling, as a stream and sink of the
+/// messages they'd be sent as over a websocket.  Websocket control frames can't be sent, so are
+/// dropped.
+pub(crate) struct Polling<S> {
+    http: Arc<Http<S>>,
+    sid: String,
+    // The transports the server offered to upgrade to in its open packet
+    upgrades: Vec<String>,
+    received: VecDeque<WsMessage>,
+    get: Option<Request<S>>,
+    // Kept alive between requests, GETs and POSTs are sent on separate connections as a GET is
+    // usually waiting for the server
+    get_conn: Option<Conn<S>>,
+    post: Option<Request<S>>,
+    post_conn: Option<Conn<S>>,
+    outgoing: Vec<WsMessage>,
+    // Set once the server sends a close packet, or the client closes the sink
+    ended: bool,
+}
+
+/// Start an engine.io session over HTTP long-polling, sending the handshake request over
+/// `connection`.  `connector` opens the connections for later requests.
+pub(crate) async fn handshake<S>(
+    url: &Url,
+    options: &ClientOptions,
+    connection: S,
+    connector: Connector<S>,
+    sid: Option<&str>,
+) -> Result<Polling<S>, Error>
+where
+    S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let http = Arc::new(Http {
+        url: url.clone(),
+        options: options.clone(),
+        headers: options.headers()?,
+        connector,
+    });
+    let request = http.request_bytes(sid, None);
+    let conn = wrap(connection, url).await?;
+    let (conn, payload) = exchange(conn, &request)
+        .await
+        .map_err(|e| Error::PollingError(e.error))?
+        .into_result()?;
+    let received = decode_payload(options, &payload)?;
+
+    let first = received.front().ok_or(Error::ClosedDuringHandshake(None))?;
+    let open = match Decoder::new().decode(first.clone()) {
+        Ok(Packet::Open(open)) => open,
+        Ok(_) => return Err(receiver::Error::from(engine::Error::MessageBeforeOpen).into()),
+        Err(e) => return Err(receiver::Error::from(e).into()),
+    };
+    Ok(Polling {
+        http,
+        sid: open.sid,
+        upgrades: open.upgrades,
+        received,
+        get: None,
+        get_conn: conn,
+        post: None,
+        post_conn: None,
+        outgoing: Vec::new(),
+        ended: false,
+    })
+}
+
+impl<S> Polling<S> {
+    /// The session's id.
+    pub(crate) fn sid(&self) -> &str {
+        &self.sid
+    }
+
+    /// Whether the server offered to upgrade the session to a websocket.
+    pub(crate) fn can_upgrade(&self) -> bool {
+        self.upgrades.iter().any(|u| u == "websocket")
+    }
+
+    /// End polling to upgrade the session, returning the messages received but not yet read,
+    /// starting with the open packet.  Only valid straight after the handshake, when no requests
+    /// are in flight.
+    pub(crate) fn into_received(self) -> VecDeque<WsMessage> {
+        self.received
+    }
+}
+
+impl<S> Stream for Polling<S>
+where
+    S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+{
+    type Item = Result<WsMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(msg) = this.received.pop_front() {
+                if msg == engine::encode_close() {
+                    this.ended = true;
+                    this.get = None;
+                }
+                return Poll::Ready(Some(Ok(msg)));
+            }
+            if this.ended {
+                return Poll::Ready(None);
+            }
+            let get = match &mut this.get {
+                Some(get) => get,
+                None => {
+                    let request = this.http.request_bytes(Some(&this.sid), None);
+                    let conn = this.get_conn.take();
+                    this.get
+                        .get_or_insert(this.http.clone().request(conn, request))
+                }
+            };
+            let result = ready!(get.poll_unpin(cx));
+            this.get = None;
+            let options = &this.http.options;
+            match result.and_then(|(conn, payload)| Ok((conn, decode_payload(options, &payload)?)))
+            {
+                Ok((conn, msgs)) => {
+                    this.get_conn = conn;
+                    this.received.extend(msgs);
+                }
+                Err(e) => {
+                    this.ended = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
+
+impl<S> Sink<WsMessage> for Polling<S>
+where
+    S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+{
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: WsMessage) -> Result<(), Error> {
+        match msg {
+            WsMessage::Text(_) | WsMessage::Binary(_) => self.outgoing.push(msg),
+            msg => log::debug!("Dropping control frame sent over polling: {:?}", msg),
+        }
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = &mut *self;
+        loop {
+            if let Some(post) = &mut this.post {
+                let result = ready!(post.poll_unpin(cx));
+                this.post = None;
+                this.post_conn = result?.0;
+            }
+            if this.outgoing.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            let version = this.http.options.get_engine_version();
+            let payload = engine::encode_payload(version, &this.outgoing);
+            this.outgoing.clear();
+            let request = this.http.request_bytes(Some(&this.sid), Some(&payload));
+            let conn = this.post_conn.take();
+            this.post = Some(this.http.clone().request(conn, request));
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        self.ended = true;
+        self.get = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn decode_payload(options: &ClientOptions, payload: &str) -> Result<VecDeque<WsMessage>, Error> {
+    let msgs = engine::decode_payload(options.get_engine_version(), payload)
+        .map_err(receiver::Error::from)?;
+    // Noop packets are only sent while upgrading the transport
+    Ok(msgs
+        .into_iter()
+        .filter(|msg| !matches!(msg, WsMessage::Text(text) if text.is_empty() || text == "6"))
+        .collect())
+}
+
+/// What requests are sent with.
+struct Http<S> {
+    url: Url,
+    options: ClientOptions,
+    headers: HeaderMap,
+    connector: Connector<S>,
+}
+
+impl<S> Http<S>
+where
+    S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+{
+    /// Build a GET request, or a POST request if there's a `body`.
+    fn request_bytes(&self, sid: Option<&str>, body: Option<&str>) -> Vec<u8> {
+        let target = match &self.options.get_path() {
+            Some(path) => match sid {
+                Some(sid) => {
+                    let separator = if path.contains('?') { '&' } else { '?' };
+                    let sid: String =
+                        url::form_urlencoded::byte_serialize(sid.as_bytes()).collect();
+                    format!("{}{}sid={}", path, separator, sid)
+                }
+                None => path.to_string(),
+            },
+            None => {
+                let mut url = self.url.clone();
+                self.options.set_socket_io_path(&mut url);
+                self.options.add_query_params(&mut url, sid);
+                match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_string(),
+                }
+            }
+        };
+        let host = self.url.host_str().unwrap();
+        let host = match self.url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+
+        let method = if body.is_some() { "POST" } else { "GET" };
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\n",
+            method, target, host
+        );
+        for (name, value) in &self.headers {
+            // Values were checked to be visible ASCII when they were parsed
+            let value = value.to_str().unwrap_or_default();
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if let Some(body) = body {
+            request.push_str(&format!(
+                "Content-Type: text/plain;charset=UTF-8\r\nContent-Length: {}\r\n",
+                body.len()
+            ));
+        }
+        request.push_str("\r\n");
+        request.push_str(body.unwrap_or_default());
+        request.into_bytes()
+    }
+
+    /// Send `request` on `conn`, or on a new connection if there's no connection to reuse or the
+    /// server has closed it.
+    fn request(self: Arc<Self>, conn: Option<Conn<S>>, request: Vec<u8>) -> Request<S> {
+        async move {
+            let response = match conn {
+                Some(conn) => match exchange(conn, &request).await {
+                    Err(e) if e.stale => {
+                        log::debug!("Kept alive connection failed, reconnecting: {}", e.error);
+                        exchange(self.open().await?, &request).await
+                    }
+                    response => response,
+                },
+                None => exchange(self.open().await?, &request).await,
+            };
+            response
+                .map_err(|e| Error::PollingError(e.error))?
+                .into_result()
+        }
+        .boxed()
+    }
+
+    async fn open(&self) -> Result<Conn<S>, Error> {
+        let stream = (self.connector)(&self.url).await?;
+        wrap(stream, &self.url).await
+    }
+}
+
+async fn wrap<S>(stream: S, url: &Url) -> Result<Conn<S>, Error>
+where
+    S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let stream = if url.scheme() == "wss" {
+        let domain = url.host_str().unwrap();
+        let stream = async_tls::TlsConnector::default()
+            .connect(domain, stream)
+            .await
+            .map_err(Error::PollingError)?;
+        TlsSwitch::Tls(stream)
+    } else {
+        TlsSwitch::Plain(stream)
+    };
+    Ok(BufReader::new(stream))
+}
+
+struct Response<S> {
+    status: u16,
+    retry_after: Option<std::time::Duration>,
+    body: String,
+    // The connection, if the server keeps it alive
+    conn: Option<Conn<S>>,
+}
+
+impl<S> Response<S> {
+    fn into_result(self) -> Result<(Option<Conn<S>>, String), Error> {
+        if (200..300).contains(&self.status) {
+            Ok((self.conn, self.body))
+        } else {
+            Err(Error::HttpHandshake {
+                status: self.status,
+                retry_after: self.retry_after,
+            })
+        }
+    }
+}
+
+struct ExchangeError {
+    error: io::Error,
+    // Whether the connection failed before any of the response was read, as happens when
+    // reusing a connection the server closed
+    stale: bool,
+}
+
+impl From<io::Error> for ExchangeError {
+    fn from(error: io::Error) -> Self {
+        ExchangeError {
+            error,
+            stale: false,
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Send `request` on `conn` and read the response.
+async fn exchange<S>(mut conn: Conn<S>, request: &[u8]) -> Result<Response<S>, ExchangeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stale = |error| ExchangeError { error, stale: true };
+    conn.write_all(request).await.map_err(stale)?;
+    conn.flush().await.map_err(stale)?;
+
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        let len = (&mut conn)
+            .take((MAX_HEAD - start) as u64)
+            .read_until(b'\n', &mut head)
+            .await
+            .map_err(|e| if start == 0 { stale(e) } else { e.into() })?;
+        if len == 0 {
+            let error = io::Error::from(io::ErrorKind::UnexpectedEof);
+            return Err(if start == 0 {
+                stale(error)
+            } else {
+                error.into()
+            });
+        }
+        if !head.ends_with(b"\n") {
+            return Err(invalid_data("response headers too long").into());
+        }
+        if head[start..] == b"\r\n"[..] || head[start..] == b"\n"[..] {
+            break;
+        }
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    match response.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => (),
+        _ => return Err(invalid_data("invalid response headers").into()),
+    }
+    let status = response.code.unwrap_or_default();
+    let header = |name: &str| {
+        response
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+            .map(str::trim)
+    };
+    let chunked =
+        matches!(header("transfer-encoding"), Some(v) if v.eq_ignore_ascii_case("chunked"));
+    let length = header("content-length").map(str::parse::<usize>);
+    let mut keep_alive = match header("connection") {
+        Some(v) => !v.eq_ignore_ascii_case("close"),
+        None => response.version == Some(1),
+    };
+
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            let mut line = String::new();
+            conn.read_line(&mut line).await?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size =
+                usize::from_str_radix(size, 16).map_err(|_| invalid_data("invalid chunk size"))?;
+            if size == 0 {
+                // Skip the trailer
+                while line != "\r\n" && line != "\n" {
+                    line.clear();
+                    if conn.read_line(&mut line).await? == 0 {
+                        break;
+                    }
+                }
+                break;
+            }
+            let start = body.len();
+            let end = start
+                .checked_add(size)
+                .filter(|&end| end <= MAX_BODY)
+                .ok_or_else(|| invalid_data("response body too large"))?;
+            body.resize(end, 0);
+            conn.read_exact(&mut body[start..]).await?;
+            let mut crlf = [0; 2];
+            conn.read_exact(&mut crlf).await?;
+        }
+    } else if let Some(length) = length {
+        let length = length.map_err(|_| invalid_data("invalid content length"))?;
+        if length > MAX_BODY {
+            return Err(invalid_data("response body too large").into());
+        }
+        body.resize(length, 0);
+        conn.read_exact(&mut body).await?;
+    } else {
+        (&mut conn)
+            .take(MAX_BODY as u64 + 1)
+            .read_to_end(&mut body)
+            .await?;
+        if body.len() > MAX_BODY {
+            return Err(invalid_data("response body too large").into());
+        }
+        keep_alive = false;
+    }
+
+    Ok(Response {
+        status,
+        retry_after: retry_after(&head),
+        body: String::from_utf8(body).map_err(|_| invalid_data("response isn't UTF-8"))?,
+        conn: if keep_alive { Some(conn) } else { None },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures::{channel::mpsc, future, stream::StreamExt};
+
+    use super::*;
+    use crate::{
+        harness::{duplex, HttpServer, Server, OPEN},
+        protocol,
+        spawn::TokioSpawner,
+        Client, Transport,
+    };
+
+    #[tokio::test]
+    async fn test_polling() {
+        let (conns_tx, mut conns) = mpsc::unbounded();
+        let connect = move |_, _| {
+            let (client, server) = duplex();
+            conns_tx.unbounded_send(server).unwrap();
+            future::ok::<_, io::Error>(client)
+        };
+        let options = ClientOptions::new().transport(Transport::Polling);
+        let server = async {
+            let mut get = HttpServer::new(conns.next().await.unwrap());
+            let request = get.recv().await;
+            assert_eq!(request.method, "GET");
+            assert_eq!(request.target, "/socket.io/?EIO=4&transport=polling");
+            get.respond(OPEN).await;
+            get
+        };
+        let (client, mut get) = future::join(
+            Client::connect_reconnecting(
+                "http://localhost/socket.io/",
+                options,
+                connect,
+                &TokioSpawner,
+            ),
+            server,
+        )
+        .await;
+        let mut client = client.unwrap();
+        let (events_tx, mut events) = mpsc::unbounded();
+        client
+            .set_event_callback("hello", move |args: &protocol::Args, _ack| {
+                events_tx.unbounded_send(args.to_string()).unwrap()
+            })
+            .unwrap();
+
+        // The next GET is sent on the kept alive connection and waits for packets
+        let request = get.recv().await;
+        assert_eq!(
+            request.target,
+            "/socket.io/?EIO=4&transport=polling&sid=harness"
+        );
+
+        // Emits are posted on a second connection
+        client.emit("event").args().send().await.unwrap();
+        let mut post = HttpServer::new(conns.next().await.unwrap());
+        let request = post.recv().await;
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.body, r#"42["event"]"#);
+        post.respond("ok").await;
+
+        // The pong is posted before or after the event is handled, so both are waited for at once
+        get.respond("2\x1e42[\"hello\",1]").await;
+        let (event, request) = future::join(events.next(), async {
+            let request = post.recv().await;
+            post.respond("ok").await;
+            request
+        })
+        .await;
+        assert_eq!(event.unwrap(), r#"["hello", 1]"#);
+        assert_eq!(request.body, "3");
+
+        // There's no websocket to ping, so pinging fails rather than waiting for the timeout
+        match client.ping(std::time::Duration::from_secs(60)).await {
+            Err(Error::PingWhilePolling) => (),
+            r => panic!("Unexpected ping result: {:?}", r),
+        }
+
+        // Closing posts the close packet
+        let (result, request) = future::join(client.close(), async {
+            let request = post.recv().await;
+            post.respond("ok").await;
+            request
+        })
+        .await;
+        result.unwrap();
+        assert_eq!(request.body, "1");
+    }
+
+    #[tokio::test]
+    async fn test_upgrade() {
+        let (conns_tx, mut conns) = mpsc::unbounded();
+        let connect = move |_, _| {
+            let (client, server) = duplex();
+            conns_tx.unbounded_send(server).unwrap();
+            future::ok::<_, io::Error>(client)
+        };
+        let options = ClientOptions::new().transport(Transport::Polling);
+        let server = async {
+            let mut get = HttpServer::new(conns.next().await.unwrap());
+            get.recv().await;
+            get.respond(
+                r#"0{"sid":"harness","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":5000}"#,
+            )
+            .await;
+            let mut ws = Server::accept_upgrade(conns.next().await.unwrap()).await;
+            assert_eq!(ws.path, "/socket.io/?EIO=4&transport=websocket&sid=harness");
+            assert_eq!(ws.recv_text().await, "2probe");
+            ws.send("3probe").await;
+            assert_eq!(ws.recv_text().await, "5");
+            (get, ws)
+        };
+        let (client, (_get, mut ws)) = future::join(
+            Client::connect_reconnecting(
+                "http://localhost/socket.io/",
+                options,
+                connect,
+                &TokioSpawner,
+            ),
+            server,
+        )
+        .await;
+        let mut client = client.unwrap();
+        assert_eq!(client.transport(), Transport::Websocket);
+        assert_eq!(client.sid(), "harness");
+
+        // Packets are sent and received over the websocket from then on
+        ws.send(r#"40{"sid":"abc"}"#).await;
+        client
+            .wait_connected("/", std::time::Duration::from_secs(5))
+            .await
+            .unwrap();
+        client.emit("event").args().send().await.unwrap();
+        assert_eq!(ws.recv_text().await, r#"42["event"]"#);
+        assert_eq!(
+            crate::harness::close(client, ws).await,
+            vec![WsMessage::Text("41".into()), WsMessage::Text("1".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_probe_failed() {
+        let (conns_tx, mut conns) = mpsc::unbounded();
+        let connect = move |_, _| {
+            let (client, server) = duplex();
+            conns_tx.unbounded_send(server).unwrap();
+            future::ok::<_, io::Error>(client)
+        };
+        let options = ClientOptions::new().transport(Transport::Polling);
+        let server = async {
+            let mut get = HttpServer::new(conns.next().await.unwrap());
+            get.recv().await;
+            get.respond(
+                r#"0{"sid":"harness","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":5000}"#,
+            )
+            .await;
+            let mut ws = Server::accept_upgrade(conns.next().await.unwrap()).await;
+            assert_eq!(ws.recv_text().await, "2probe");
+            ws.send("6").await;
+            get
+        };
+        let (client, mut get) = future::join(
+            Client::connect_reconnecting(
+                "http://localhost/socket.io/",
+                options,
+                connect,
+                &TokioSpawner,
+            ),
+            server,
+        )
+        .await;
+        let client = client.unwrap();
+        assert_eq!(client.transport(), Transport::Polling);
+
+        // The session carries on polling
+        let request = get.recv().await;
+        assert_eq!(
+            request.target,
+            "/socket.io/?EIO=4&transport=polling&sid=harness"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_body_too_large() {
+        let responses = [
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4000001\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 18446744073709551615\r\n\r\n",
+        ];
+        for response in responses.iter() {
+            let (client, mut server) = duplex();
+            server.write_all(response.as_bytes()).await.unwrap();
+            let conn = BufReader::new(TlsSwitch::Plain(client));
+            match exchange(conn, b"GET / HTTP/1.1\r\n\r\n").await {
+                Err(ExchangeError { error, .. }) => {
+                    assert_eq!(error.kind(), io::ErrorKind::InvalidData)
+                }
+                Ok(_) => panic!("Accepted a body that's too large"),
+            }
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::time::Duration;
+
+use futures::{
+    future::{self, FutureExt},
+    select,
+};
+use futures_timer::Delay;
+use serde::de::DeserializeOwned;
+
+use super::{protocol::ArgsError, Client, CloseReason, Error, EventArgsBuilder, EventBuilder};
+
+/// A group of clients, e.g. connected to different shards of a backend, which events can be
+/// spread across or broadcast to.
+///
+/// Emits are load balanced round-robin across the clients that are still connected, clients
+/// whose connection has ended are skipped until they're removed.
+pub struct ClientPool {
+    clients: Vec<Client>,
+    next: usize,
+}
+
+impl ClientPool {
+    pub fn new(clients: Vec<Client>) -> Self {
+        ClientPool { clients, next: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    pub fn push(&mut self, client: Client) {
+        self.clients.push(client);
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Client {
+        self.clients.remove(idx)
+    }
+
+    pub fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+
+    pub fn clients_mut(&mut self) -> &mut [Client] {
+        &mut self.clients
+    }
+
+    /// The next connected client in round-robin order, or `None` if no clients are connected.
+    pub fn next_client(&mut self) -> Option<&mut Client> {
+        let len = self.clients.len();
+        let idx = (0..len)
+            .map(|i| (self.next + i) % len)
+            .find(|&idx| self.clients[idx].is_connected())?;
+        self.next = (idx + 1) % len;
+        Some(&mut self.clients[idx])
+    }
+
+    /// Create an `EventBuilder` for the given namespace on the next connected client, or `None` if
+    /// no clients are connected.
+    pub fn namespace_emit<'a>(
+        &'a mut self,
+        namespace: &'a str,
+        event: &'a str,
+    ) -> Option<EventBuilder<'a>> {
+        Some(self.next_client()?.namespace_emit(namespace, event))
+    }
+
+    /// Equivalent to `namespace_emit("/", event)`.
+    pub fn emit<'a>(&'a mut self, event: &'a str) -> Option<EventBuilder<'a>> {
+        self.namespace_emit("/", event)
+    }
+
+    /// Emit an event to every client, with arguments added by `args`, and wait for each of their
+    /// acks, deserialized as by `EventBuilder::callback_typed`.  Returns one result per client, in
+    /// the order of `clients()`, so some clients can fail while others succeed: a client that
+    /// isn't connected fails with `Error::Disconnected`, and a client whose ack doesn't arrive
+    /// within `timeout` fails with `Error::Timeout`.
+    pub async fn namespace_broadcast_with_ack<R, F>(
+        &mut self,
+        namespace: &str,
+        event: &str,
+        timeout: Duration,
+        args: F,
+    ) -> Vec<Result<R, Error>>
+    where
+        R: 'static + DeserializeOwned + Send,
+        F: for<'b> Fn(EventArgsBuilder<'b>) -> Result<EventArgsBuilder<'b>, ArgsError>,
+    {
+        let acks = self.clients.iter_mut().map(|client| {
+            let ack = if !client.is_connected() {
+                Err(Error::Disconnected)
+            } else {
+                args(client.namespace_emit(namespace, event).args())
+                    .map(EventArgsBuilder::send_with_typed_ack::<R>)
+                    .map_err(Error::from)
+            };
+            async move {
+                let ack = ack?;
+                select! {
+                    result = ack.fuse() => result,
+                    _ = Delay::new(timeout).fuse() => Err(Error::Timeout("ack")),
+                }
+            }
+        });
+        future::join_all(acks).await
+    }
+
+    /// Equivalent to `namespace_broadcast_with_ack("/", event, timeout, args)`.
+    pub async fn broadcast_with_ack<R, F>(
+        &mut self,
+        event: &str,
+        timeout: Duration,
+        args: F,
+    ) -> Vec<Result<R, Error>>
+    where
+        R: 'static + DeserializeOwned + Send,
+        F: for<'b> Fn(EventArgsBuilder<'b>) -> Result<EventArgsBuilder<'b>, ArgsError>,
+    {
+        self.namespace_broadcast_with_ack("/", event, timeout, args)
+            .await
+    }
+
+    /// Close every client, returning the result of closing each.
+    pub async fn close(&mut self) -> Vec<Result<Option<CloseReason>, Error>> {
+        future::join_all(self.clients.iter_mut().map(Client::close)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{self, Server};
+
+    async fn pool(n: usize) -> (ClientPool, Vec<Server>) {
+        let mut pool = ClientPool::new(Vec::new());
+        let mut servers = Vec::new();
+        for _ in 0..n {
+            let (client, server) = harness::connect().await;
+            pool.push(client);
+            servers.push(server);
+        }
+        (pool, servers)
+    }
+
+    async fn close(pool: ClientPool, servers: Vec<Server>) {
+        let closes = pool
+            .clients
+            .into_iter()
+            .zip(servers)
+            .map(|(client, server)| harness::close(client, server));
+        future::join_all(closes).await;
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let (mut pool, mut servers) = pool(3).await;
+
+        for i in 0..4 {
+            pool.emit("event")
+                .unwrap()
+                .args()
+                .arg(&i)
+                .unwrap()
+                .send()
+                .await
+                .unwrap();
+        }
+        assert_eq!(servers[0].recv_text().await, r#"42["event",0]"#);
+        assert_eq!(servers[1].recv_text().await, r#"42["event",1]"#);
+        assert_eq!(servers[2].recv_text().await, r#"42["event",2]"#);
+        assert_eq!(servers[0].recv_text().await, r#"42["event",3]"#);
+
+        // Disconnected clients are skipped
+        harness::close_client(&mut pool.clients_mut()[1], servers.remove(1)).await;
+        assert!(!pool.clients()[1].is_connected());
+        pool.emit("event")
+            .unwrap()
+            .args()
+            .arg(&4)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        pool.emit("event")
+            .unwrap()
+            .args()
+            .arg(&5)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(servers[1].recv_text().await, r#"42["event",4]"#);
+        assert_eq!(servers[0].recv_text().await, r#"42["event",5]"#);
+
+        pool.remove(1);
+        close(pool, servers).await;
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_with_ack() {
+        let (mut pool, servers) = pool(3).await;
+        let mut servers = servers.into_iter();
+        let (mut s0, mut s1, s2) = (
+            servers.next().unwrap(),
+            servers.next().unwrap(),
+            servers.next().unwrap(),
+        );
+        harness::close_client(&mut pool.clients_mut()[2], s2).await;
+
+        let servers = async {
+            assert_eq!(s0.recv_text().await, r#"420["event","arg"]"#);
+            s0.send(r#"430[1]"#).await;
+            // The second server never responds
+            assert_eq!(s1.recv_text().await, r#"420["event","arg"]"#);
+        };
+        let (results, _) = future::join(
+            pool.broadcast_with_ack::<u32, _>("event", Duration::from_millis(100), |args| {
+                args.arg("arg")
+            }),
+            servers,
+        )
+        .await;
+        let mut results = results.into_iter();
+        assert_eq!(results.next().unwrap().unwrap(), 1);
+        assert!(matches!(results.next(), Some(Err(Error::Timeout(_)))));
+        assert!(matches!(results.next(), Some(Err(Error::Disconnected))));
+
+        pool.remove(2);
+        close(pool, vec![s0, s1]).await;
+    }
+}
@@ -1,3 +1,5 @@
 use socket_io_protocol as protocol;
 
-pub use protocol::socket::{Arg, Args, ArgsError};
+pub use protocol::socket::{
+    no_binary, Arg, Args, ArgsError, BinaryMode, NoBinary, OwnedArgs, PacketPreview,
+};
@@ -1,19 +1,35 @@
 use std::{
     borrow::Cow,
+    collections::hash_map::DefaultHasher,
+    convert::TryFrom,
+    hash::{Hash, Hasher},
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use async_tungstenite::tungstenite::Message as WsMessage;
-use futures::channel::{mpsc, oneshot};
+use futures::{
+    channel::{mpsc, oneshot},
+    future,
+    stream::StreamExt,
+    task::{Spawn, SpawnError, SpawnExt},
+};
 
 use socket_io_protocol::{
     engine::{
         self, Decoder, Error as EngineError, Message as EngineMessage, Packet as EnginePacket,
     },
-    socket::{self, ArgsError, Data, DeserializeResult, Error as SocketError, Packet, Partial},
+    socket::{
+        self, ArgsError, Data, DeserializeResult, Error as SocketError, OwnedArgs, Packet, Partial,
+    },
 };
 
-use super::{AckBuilder, Callbacks};
+use super::{
+    callbacks::{self, IncomingEvent, TakenAck},
+    connection::Sender,
+    trace, AckBuilder, Callbacks, CloseReason, EventCallback, EventTransformer,
+    GlobalFallbackCallback,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -24,17 +40,34 @@ pub enum Error {
     #[error("Error deserializing argument: {0}")]
     ArgsError(#[from] ArgsError),
     #[error("Event packet with no arguments: {0:?}")]
-    EventNoArgs(Packet),
+    EventNoArgs(Box<Packet>),
     #[error("Unexpected ack received: {0:?}")]
-    UnexpectedAck(Packet),
+    UnexpectedAck(Box<Packet>),
 }
 
 pub struct Receiver {
     decoder: Decoder,
     in_progress: Option<InProgress>,
-    sender: mpsc::UnboundedSender<Vec<WsMessage>>,
+    sender: Sender,
     callbacks: Arc<Mutex<Callbacks>>,
     open: Option<oneshot::Sender<engine::Open>>,
+    // Event callbacks are called inline if there are no workers
+    workers: Vec<mpsc::UnboundedSender<Job>>,
+    max_attachments: u64,
+    close_reason: Option<CloseReason>,
+    // The time allowed between pings, from the open packet
+    ping_window: Option<Duration>,
+    heartbeat: bool,
+}
+
+pub(crate) type Job = Box<dyn FnOnce() + Send>;
+
+/// The handlers found for a received event, which are called together.
+struct EventHandlers {
+    transformers: Vec<EventTransformer>,
+    callback: Option<EventCallback>,
+    global: Option<GlobalFallbackCallback>,
+    events: Option<mpsc::UnboundedSender<IncomingEvent>>,
 }
 
 struct InProgress {
@@ -44,43 +77,94 @@ struct InProgress {
 
 impl Receiver {
     pub fn new(
-        sender: mpsc::UnboundedSender<Vec<WsMessage>>,
+        sender: Sender,
         callbacks: Arc<Mutex<Callbacks>>,
         open: oneshot::Sender<engine::Open>,
+        workers: Vec<mpsc::UnboundedSender<Job>>,
+        max_attachments: u64,
     ) -> Receiver {
         Receiver {
+            max_attachments,
+            close_reason: None,
             decoder: Decoder::new(),
             in_progress: None,
             sender,
             callbacks,
             open: Some(open),
+            workers,
+            ping_window: None,
+            heartbeat: false,
         }
     }
 
+    /// If a heartbeat (the open packet, a ping or a pong) has been received since the last call,
+    /// returns how long to wait for the next one before considering the connection dead.
+    pub fn take_heartbeat(&mut self) -> Option<Duration> {
+        if std::mem::take(&mut self.heartbeat) {
+            self.ping_window
+        } else {
+            None
+        }
+    }
+
+    /// The close frame received from the server, if any.
+    pub fn take_close_reason(&mut self) -> Option<CloseReason> {
+        self.close_reason.take()
+    }
+
     pub fn process_websocket_packet(&mut self, msg: WsMessage) -> Result<(), Error> {
         log::trace!("Received WebSocket packet: {:?}", msg);
         match msg {
             WsMessage::Close(frame) => {
                 log::debug!("Closed with close frame {:?}", frame);
+                self.close_reason = frame.map(|frame| CloseReason {
+                    code: frame.code.into(),
+                    reason: frame.reason.into_owned(),
+                });
+                Ok(())
+            }
+            WsMessage::Ping(_) => Ok(()), // already answered by tungstenite
+            WsMessage::Pong(data) => {
+                self.process_pong(&data);
                 Ok(())
             }
-            WsMessage::Ping(_) | WsMessage::Pong(_) => Ok(()), // already handled by tungstenite
             WsMessage::Text(text) => self.process_message(WsMessage::Text(text)),
             WsMessage::Binary(data) => self.process_message(WsMessage::Binary(data)),
         }
     }
 
+    /// Resolve the `Client::ping` the pong answers.  Pongs can also be unsolicited, or answer a
+    /// ping that already timed out, so unknown ones are ignored.
+    fn process_pong(&mut self, data: &[u8]) {
+        let id = match <[u8; 8]>::try_from(data) {
+            Ok(id) => u64::from_be_bytes(id),
+            Err(_) => return,
+        };
+        if let Some(pong) = self.callbacks.lock().unwrap().take_ping(id) {
+            let _ = pong.send(());
+        }
+    }
+
     fn process_message(&mut self, msg: WsMessage) -> Result<(), Error> {
         let packet = self.decoder.decode(msg)?;
         match packet {
             EnginePacket::Open(open) => {
-                // TODO: forward this info to the client
                 log::trace!("Received open engine packet: {:?}", open);
-                if let Some(send) = self.open.take() {
-                    let _ = send.send(open);
-                } else {
-                    log::warn!("Received second open engine packet: {:?}", open);
+                // Each receiver handles a single engine.io session, and the decoder rejects a
+                // second open in a session, so this only fails if the two get out of sync.  A
+                // reconnect creates a new session with a new receiver and decoder.
+                let send = self.open.take().ok_or(EngineError::SecondOpen)?;
+                self.ping_window = Some(open.ping_interval() + open.ping_timeout());
+                self.heartbeat = true;
+                // The server's limit on what it receives, which it's expected to keep to as well
+                let limit = open
+                    .max_payload
+                    .map(|max_payload| usize::try_from(max_payload).unwrap_or(usize::MAX));
+                if limit.is_some() {
+                    self.decoder.set_max_packet_size(limit);
                 }
+                self.sender.set_max_payload(limit);
+                let _ = send.send(open);
                 Ok(())
             }
             EnginePacket::Close => {
@@ -90,19 +174,19 @@ impl Receiver {
             EnginePacket::Ping => {
                 log::trace!("Received engine ping packet");
                 let _ = self.sender.unbounded_send(vec![engine::encode_pong()]);
-                // TODO: send message to timer task to reset the timeout
+                self.heartbeat = true;
                 Ok(())
             }
             EnginePacket::Pong => {
-                log::trace!("Received engine ping packet");
-                // TODO: send message to timer task to reset the timeout
+                log::trace!("Received engine pong packet");
+                self.heartbeat = true;
                 Ok(())
             }
             EnginePacket::Message(msg) => {
                 log::trace!("Received message engine packet: {:?}", msg);
                 match self.in_progress.take() {
                     Some(mut ip) => {
-                        ip.add(msg);
+                        ip.add(msg)?;
                         if ip.ready() {
                             let packet = ip.deserialize()?;
                             self.process_packet(packet)?;
@@ -111,13 +195,15 @@ impl Receiver {
                         }
                         Ok(())
                     }
-                    None => match socket::deserialize(msg)? {
-                        DeserializeResult::Packet(packet) => self.process_packet(packet),
-                        DeserializeResult::DataNeeded(partial) => {
-                            self.in_progress = Some(InProgress::new(partial));
-                            Ok(())
+                    None => {
+                        match socket::deserialize_with_max_attachments(msg, self.max_attachments)? {
+                            DeserializeResult::Packet(packet) => self.process_packet(packet),
+                            DeserializeResult::DataNeeded(partial) => {
+                                self.in_progress = Some(InProgress::new(partial));
+                                Ok(())
+                            }
                         }
-                    },
+                    }
                 }
             }
         }
@@ -125,42 +211,182 @@ impl Receiver {
 
     fn process_packet(&mut self, packet: Packet) -> Result<(), Error> {
         log::info!("Received socket packet: {}", packet);
+        let ack_id = match packet.data() {
+            Data::Event { .. } => return self.process_event(packet),
+            Data::Ack { id, .. } => Some(id),
+            _ => None,
+        };
+        trace::packet(packet.namespace(), None, ack_id).in_scope(|| self.process_control(&packet))
+    }
+
+    /// Process any packet other than an event.
+    fn process_control(&mut self, packet: &Packet) -> Result<(), Error> {
         let namespace = packet.namespace();
         match packet.data() {
-            Data::Connect => {
+            Data::Connect { sid } => {
                 log::info!("Received connect for {}", namespace);
-                // TODO: Call connect callback
+                let result = {
+                    let mut callbacks = self.callbacks.lock().unwrap();
+                    // Sent while locked so events emitted meanwhile aren't sent before these
+                    callbacks.set_connected(namespace, sid).map(|queued| {
+                        for packets in queued {
+                            let _ = self.sender.unbounded_send(packets);
+                        }
+                    })
+                };
+                if let Err(e) = result {
+                    callbacks::report_error(&self.callbacks, e);
+                }
+                let cb = self.callbacks.lock().unwrap().get_connect(namespace);
+                if let Some(mut cb) = cb {
+                    cb.call(namespace);
+                }
             }
             Data::Disconnect => {
                 log::info!("Received disconnect for {}", namespace);
-                // TODO: Call disconnect callback
+                let cb = {
+                    let mut callbacks = self.callbacks.lock().unwrap();
+                    callbacks.set_disconnected(namespace);
+                    callbacks.get_disconnect(namespace)
+                };
+                if let Some(mut cb) = cb {
+                    cb.call(namespace);
+                }
             }
-            Data::Event { args, id } => {
-                let event = args
-                    .get(0)
-                    .ok_or_else(|| Error::EventNoArgs(packet.clone()))?;
-                let event: Cow<'_, str> = event.deserialize()?;
-                let ack = id.map(|id| AckBuilder::new(self.sender.clone(), namespace, id));
-                // TODO: Use id to create ack callback
-                if let Some(mut cb) = self.callbacks.lock().unwrap().get_event(namespace, &*event) {
-                    cb.call(&args, ack);
+            Data::ConnectError { message, .. } => {
+                log::info!("Received connect error for {}: {}", namespace, message);
+                let cb = {
+                    let mut callbacks = self.callbacks.lock().unwrap();
+                    callbacks.set_connect_refused(namespace, &message);
+                    callbacks.get_connect_error(namespace)
+                };
+                if let Some(mut cb) = cb {
+                    cb.call(namespace, &message);
                 }
             }
+            Data::Event { .. } => unreachable!(),
             Data::Ack { id, args } => {
-                if let Some(cb) = self
+                let cb = self
                     .callbacks
                     .lock()
                     .unwrap()
-                    .get_and_clear_ack(namespace, id)
-                {
-                    cb.call(&args);
-                } else {
-                    return Err(Error::UnexpectedAck(packet.clone()));
+                    .get_and_clear_ack(namespace, id);
+                match cb {
+                    TakenAck::Pending(cb) => cb.call(&args),
+                    TakenAck::Expired => {
+                        log::debug!(
+                            "Ignoring ack {} for {} received after it was dropped",
+                            id,
+                            namespace
+                        )
+                    }
+                    TakenAck::Unknown => {
+                        return Err(Error::UnexpectedAck(Box::new(packet.clone())));
+                    }
                 }
             }
         };
         Ok(())
     }
+
+    fn process_event(&mut self, packet: Packet) -> Result<(), Error> {
+        let (event, id) = match packet.data() {
+            Data::Event { args, id } => {
+                let event = args
+                    .get(0)
+                    .ok_or_else(|| Error::EventNoArgs(Box::new(packet.clone())))?;
+                let event: Cow<'_, str> = event.deserialize()?;
+                (event.into_owned(), id)
+            }
+            _ => unreachable!(),
+        };
+        let namespace = packet.namespace();
+        let ack = id.map(|id| AckBuilder::new(self.sender.clone(), namespace, id));
+        let span = trace::packet(namespace, Some(&event), id);
+        let handlers = {
+            let callbacks = self.callbacks.lock().unwrap();
+            EventHandlers {
+                transformers: callbacks.get_transformers(namespace, &event),
+                callback: callbacks.get_event(namespace, &event),
+                global: callbacks.get_global_fallback(),
+                events: callbacks.get_events(),
+            }
+        };
+        if self.workers.is_empty() {
+            span.in_scope(|| handlers.call(&packet, &event, ack));
+        } else {
+            let worker = &self.workers[worker_index(namespace, &event, self.workers.len())];
+            let job = move || span.in_scope(|| handlers.call(&packet, &event, ack));
+            // Workers only stop once the receiver is dropped
+            let _ = worker.unbounded_send(Box::new(job));
+        }
+        Ok(())
+    }
+}
+
+impl EventHandlers {
+    fn call(self, packet: &Packet, event: &str, ack: Option<AckBuilder>) {
+        let args = match packet.data() {
+            Data::Event { args, .. } => args,
+            _ => unreachable!(),
+        };
+        let transformed = if self.transformers.is_empty() {
+            None
+        } else {
+            let mut owned = args.to_owned_args();
+            for mut transformer in self.transformers {
+                transformer.call(&mut owned);
+            }
+            Some(owned)
+        };
+        match (self.callback, self.global, self.events) {
+            (Some(mut cb), _, _) => {
+                cb.call(&transformed.as_ref().map_or(args, OwnedArgs::args), ack)
+            }
+            (None, Some(mut global), _) => global.call(
+                packet.namespace(),
+                event,
+                &transformed.as_ref().map_or(args, OwnedArgs::args),
+                ack,
+            ),
+            (None, None, Some(events)) => {
+                // Nothing is listening if the receiver was dropped, so the event is dropped too
+                let _ = events.unbounded_send(IncomingEvent {
+                    namespace: packet.namespace().to_string(),
+                    event: event.to_string(),
+                    args: transformed.unwrap_or_else(|| args.to_owned_args()),
+                    ack,
+                });
+            }
+            (None, None, None) => (),
+        }
+    }
+}
+
+/// Spawn `count` tasks to call event callbacks on, returning the senders to give them jobs with.
+/// Each task stops once its sender is dropped.
+pub fn spawn_workers(
+    count: usize,
+    spawn: &impl Spawn,
+) -> Result<Vec<mpsc::UnboundedSender<Job>>, SpawnError> {
+    (0..count)
+        .map(|_| {
+            let (tx, rx) = mpsc::unbounded::<Job>();
+            spawn.spawn(rx.for_each(|job| {
+                job();
+                future::ready(())
+            }))?;
+            Ok(tx)
+        })
+        .collect()
+}
+
+/// The worker that handles an event, so each event's callbacks are called in the order the events
+/// were received.
+pub(crate) fn worker_index(namespace: &str, event: &str, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    (namespace, event).hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
 }
 
 impl InProgress {
@@ -171,8 +397,17 @@ impl InProgress {
         }
     }
 
-    fn add(&mut self, msg: EngineMessage) {
-        self.attachments.push(msg);
+    /// Attachment frames carry no index, so the `num` of a placeholder is the position its
+    /// attachment arrives in, and the server must send them in order.  Anything other than a
+    /// binary message fails the packet straight away, rather than once enough messages arrived.
+    fn add(&mut self, msg: EngineMessage) -> Result<(), SocketError> {
+        match msg {
+            EngineMessage::Text(text) => Err(SocketError::TextAttachment(text.to_string())),
+            msg => {
+                self.attachments.push(msg);
+                Ok(())
+            }
+        }
     }
 
     fn ready(&self) -> bool {
@@ -183,3 +418,91 @@ impl InProgress {
         socket::deserialize_partial(self.partial, self.attachments)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{connection, harness::OPEN};
+
+    #[test]
+    fn test_second_open() {
+        let (sender, _) = connection::send_channel(None);
+        let (open_tx, mut open_rx) = oneshot::channel();
+        let callbacks = Arc::new(Mutex::new(Callbacks::new()));
+        let mut receiver = Receiver::new(
+            sender,
+            callbacks,
+            open_tx,
+            Vec::new(),
+            socket::DEFAULT_MAX_ATTACHMENTS,
+        );
+
+        receiver
+            .process_websocket_packet(WsMessage::Text(OPEN.to_string()))
+            .unwrap();
+        assert_eq!(open_rx.try_recv().unwrap().unwrap().sid, "harness");
+        assert!(matches!(
+            receiver.process_websocket_packet(WsMessage::Text(OPEN.to_string())),
+            Err(Error::EngineError(EngineError::SecondOpen))
+        ));
+    }
+
+    #[test]
+    fn test_max_payload() {
+        let (sender, _) = connection::send_channel(None);
+        let (open_tx, _open_rx) = oneshot::channel();
+        let callbacks = Arc::new(Mutex::new(Callbacks::new()));
+        let mut receiver = Receiver::new(
+            sender,
+            callbacks,
+            open_tx,
+            Vec::new(),
+            socket::DEFAULT_MAX_ATTACHMENTS,
+        );
+
+        let open = r#"0{"sid":"abc","pingInterval":25000,"pingTimeout":5000,"maxPayload":16}"#;
+        receiver
+            .process_websocket_packet(WsMessage::Text(open.to_string()))
+            .unwrap();
+        receiver
+            .process_websocket_packet(WsMessage::Text(r#"42["short"]"#.to_string()))
+            .unwrap();
+        assert!(matches!(
+            receiver
+                .process_websocket_packet(WsMessage::Text(r#"42["much too long"]"#.to_string())),
+            Err(Error::EngineError(EngineError::PacketTooLarge {
+                size: 19,
+                limit: 16,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_text_attachment() {
+        let (sender, _) = connection::send_channel(None);
+        let (open_tx, _open_rx) = oneshot::channel();
+        let callbacks = Arc::new(Mutex::new(Callbacks::new()));
+        let mut receiver = Receiver::new(
+            sender,
+            callbacks,
+            open_tx,
+            Vec::new(),
+            socket::DEFAULT_MAX_ATTACHMENTS,
+        );
+        let mut process = |msg| receiver.process_websocket_packet(msg);
+
+        process(WsMessage::Text(OPEN.to_string())).unwrap();
+        let event = r#"452-["event",{"_placeholder":true,"num":0},{"_placeholder":true,"num":1}]"#;
+        process(WsMessage::Text(event.to_string())).unwrap();
+        process(WsMessage::Binary(vec![4, 1])).unwrap();
+        // Fails before the second attachment arrives
+        assert!(matches!(
+            process(WsMessage::Text(r#"42["other"]"#.to_string())),
+            Err(Error::SocketError(SocketError::TextAttachment(_)))
+        ));
+        // and the next message starts a new packet
+        process(WsMessage::Text(r#"42["other"]"#.to_string())).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use super::{CloseReason, Error};
+
+/// How a `Client` reconnects after its connection is lost, set with `ClientOptions::reconnect`.
+///
+/// The delay before each attempt doubles from `base_delay`, up to `max_delay`, and is then
+/// randomly moved up to `jitter` (a fraction of the delay, between 0 and 1) earlier or later so
+/// many clients disconnected at once don't all reconnect at the same time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectConfig {
+    /// The most attempts made after a connection is lost before giving up, or `None` to keep
+    /// trying forever.  The count starts again once an attempt succeeds.
+    pub max_retries: Option<u32>,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: f64,
+}
+
+/// The defaults match the reference javascript client: unlimited retries, starting at 1 second
+/// and doubling up to 5 seconds, with a jitter of 0.5.
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            max_retries: None,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// The delay before reconnect attempt `attempt`, counting from 1.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        self.delay_with(attempt, random())
+    }
+
+    /// `random` is uniform in `[0, 1)`.
+    fn delay_with(&self, attempt: u32, random: f64) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self
+            .base_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+        let jitter = self.jitter.clamp(0.0, 1.0) * (2.0 * random - 1.0);
+        delay.mul_f64(1.0 + jitter).min(self.max_delay)
+    }
+}
+
+/// A change in a `Client`'s connection, received from `Client::status_changes`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionStatus {
+    /// The connection was lost, rather than closed by the client.  Followed by `Reconnecting` if
+    /// the client reconnects.
+    Disconnected(DisconnectReason),
+    /// Waiting `delay` before reconnect attempt `attempt`, counting from 1.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// A reconnect attempt succeeded with a new engine.io session.  Namespaces that were
+    /// connected when the connection was lost are connected again.
+    Reconnected,
+    /// The client gave up after `ReconnectConfig::max_retries` failed attempts, and stays
+    /// disconnected.
+    ReconnectFailed,
+}
+
+/// Why a connection was lost.  Unlike `Error` it can be cloned, so each subscriber to
+/// `Client::status_changes` gets a copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The server closed the connection, or the stream ended, with the close frame the server
+    /// sent, if any.
+    Closed(Option<CloseReason>),
+    /// The server didn't send a ping within the ping interval and timeout it gave.
+    PingTimeout,
+    /// The connection failed with an error, e.g. a protocol or transport error, described by its
+    /// message.
+    Error(String),
+}
+
+impl DisconnectReason {
+    /// The reason for a session that ended with `result`.
+    pub(crate) fn new(result: &Result<Option<CloseReason>, Error>) -> Self {
+        match result {
+            Ok(reason) => DisconnectReason::Closed(reason.clone()),
+            Err(Error::PingTimeout) => DisconnectReason::PingTimeout,
+            Err(e) => DisconnectReason::Error(e.to_string()),
+        }
+    }
+}
+
+/// A number uniform in `[0, 1)`.  Each `RandomState` is seeded differently, which is random
+/// enough for jitter without depending on a random number crate.
+fn random() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let config = ReconnectConfig {
+            max_retries: None,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            jitter: 0.5,
+        };
+        let delays: Vec<_> = (1..6).map(|i| config.delay_with(i, 0.5)).collect();
+        let expected: Vec<_> = [100, 200, 400, 800, 1000]
+            .iter()
+            .map(|&ms| Duration::from_millis(ms))
+            .collect();
+        assert_eq!(delays, expected);
+        assert_eq!(config.delay_with(u32::MAX, 0.5), config.max_delay);
+
+        assert_eq!(config.delay_with(2, 0.0), Duration::from_millis(100));
+        assert_eq!(config.delay_with(2, 0.75), Duration::from_millis(250));
+        // The jitter doesn't go past the maximum
+        assert_eq!(config.delay_with(4, 0.99), config.max_delay);
+
+        for _ in 0..100 {
+            let delay = config.delay(2);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(300));
+        }
+    }
+}
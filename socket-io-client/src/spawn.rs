@@ -0,0 +1,66 @@
+//! `Spawn` implementations for running a `Client` on common runtimes, each enabled by the
+//! runtime's feature.
+
+use futures::task::{FutureObj, Spawn, SpawnError};
+
+/// Spawns the client's tasks with `tokio::spawn`, so the client must be created from within a
+/// tokio runtime, e.g. `Client::connect(url, connect, &TokioSpawner).await`.  Enabled by the
+/// `tokio` feature.
+#[cfg(any(test, feature = "tokio"))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioSpawner;
+
+#[cfg(any(test, feature = "tokio"))]
+impl Spawn for TokioSpawner {
+    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        tokio::spawn(future);
+        Ok(())
+    }
+}
+
+/// Spawns the client's tasks with `async_std::task::spawn`, e.g.
+/// `Client::connect(url, connect, &AsyncStdSpawner).await`.  Enabled by the `async-std` feature.
+#[cfg(feature = "async-std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStdSpawner;
+
+#[cfg(feature = "async-std")]
+impl Spawn for AsyncStdSpawner {
+    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        async_std::task::spawn(future);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+
+    use super::*;
+    use crate::{harness, Client};
+
+    #[tokio::test]
+    async fn test_tokio_spawner() {
+        let (client, server) = harness::duplex();
+        let (client, server) = future::join(
+            Client::from_stream("http://localhost/", client, &TokioSpawner),
+            harness::Server::accept(server),
+        )
+        .await;
+        harness::close(client.unwrap(), server).await;
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_async_std_spawner() {
+        async_std::task::block_on(async {
+            let (client, server) = harness::duplex();
+            let (client, server) = future::join(
+                Client::from_stream("http://localhost/", client, &AsyncStdSpawner),
+                harness::Server::accept(server),
+            )
+            .await;
+            harness::close(client.unwrap(), server).await;
+        });
+    }
+}
@@ -0,0 +1,85 @@
+//! Instrumentation with `tracing` spans, enabled by the `tracing` feature.  Without the feature
+//! the spans are no-ops, so callers don't need their own `cfg`s.
+//!
+//! The spans are nested as:
+//!
+//! - `socket_io.connection` (`host`, `path`): connecting, and the connection's task for as long as
+//!   it runs, including reconnects.  The query isn't recorded as it may contain credentials.
+//!   - `socket_io.session` (`sid`): one engine.io session, with `sid` recorded once the open
+//!     packet arrives.
+//!     - `socket_io.packet` (`namespace`, `event`, `ack_id`): handling a received socket.io packet,
+//!       including calling its callbacks, also when they're called on an event worker.  `event` is
+//!       only set for events, and `ack_id` for events expecting an ack and acks.
+//!
+//! Emitted events and acks are recorded as `socket_io.emit` events, with `namespace`, `event`
+//! (for events) and `ack_id` fields, in the span of the code that sent them.
+
+pub use imp::*;
+
+#[cfg(feature = "tracing")]
+mod imp {
+    pub use tracing::{Instrument, Span};
+    use url::Url;
+
+    pub fn connection(url: &Url) -> Span {
+        tracing::info_span!(
+            "socket_io.connection",
+            host = url.host_str().unwrap_or_default(),
+            path = url.path()
+        )
+    }
+
+    pub fn session() -> Span {
+        tracing::info_span!("socket_io.session", sid = tracing::field::Empty)
+    }
+
+    pub fn record_sid(span: &Span, sid: &str) {
+        span.record("sid", sid);
+    }
+
+    pub fn packet(namespace: &str, event: Option<&str>, ack_id: Option<u64>) -> Span {
+        tracing::debug_span!("socket_io.packet", namespace, event, ack_id)
+    }
+
+    pub fn emit(namespace: &str, event: Option<&str>, ack_id: Option<u64>) {
+        tracing::debug!(namespace, event, ack_id, "socket_io.emit");
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use url::Url;
+
+    #[derive(Debug, Clone)]
+    pub struct Span;
+
+    impl Span {
+        pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+            f()
+        }
+    }
+
+    pub trait Instrument: Sized {
+        fn instrument(self, _span: Span) -> Self {
+            self
+        }
+    }
+
+    impl<T> Instrument for T {}
+
+    pub fn connection(_url: &Url) -> Span {
+        Span
+    }
+
+    pub fn session() -> Span {
+        Span
+    }
+
+    pub fn record_sid(_span: &Span, _sid: &str) {}
+
+    pub fn packet(_namespace: &str, _event: Option<&str>, _ack_id: Option<u64>) -> Span {
+        Span
+    }
+
+    pub fn emit(_namespace: &str, _event: Option<&str>, _ack_id: Option<u64>) {}
+}
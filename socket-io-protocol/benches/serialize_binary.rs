@@ -0,0 +1,48 @@
+//! Times serializing a 1MB `Vec<u8>` as a binary attachment, which serde serializes one `u8` at
+//! a time, against the same data as a byte string.  Run with `cargo bench -p socket-io-protocol`.
+
+use std::time::Instant;
+
+use socket_io_protocol::socket::PacketBuilder;
+
+const SIZE: usize = 1024 * 1024;
+const ITERATIONS: u32 = 50;
+
+fn main() {
+    let data = vec![0xabu8; SIZE];
+    let bytes: &[u8] = &data;
+    bench("Vec<u8>", || serialize(&data));
+    bench("&[u8]", || serialize(&bytes));
+    bench("bytes", || serialize(&ByteString(bytes)));
+}
+
+fn serialize(arg: &impl serde::Serialize) {
+    let mut builder = PacketBuilder::new_event("data", "/", None, true);
+    builder.serialize_arg(arg).unwrap();
+    let packets = builder.finish();
+    assert_eq!(packets.len(), 2);
+}
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    // Warm up
+    f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let per_iter = start.elapsed() / ITERATIONS;
+    let throughput = SIZE as f64 / per_iter.as_secs_f64() / (1024.0 * 1024.0);
+    println!(
+        "{:>8}: {:>10.3?} per 1MB arg, {:.0} MB/s",
+        name, per_iter, throughput
+    );
+}
+
+/// Serialized with `serialize_bytes`, like `serde_bytes::Bytes`.
+struct ByteString<'a>(&'a [u8]);
+
+impl serde::Serialize for ByteString<'_> {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_bytes(self.0)
+    }
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 use serde_json::error::Error as JsonError;
 use tungstenite::Message as WsMessage;
@@ -6,8 +8,33 @@ use owned_subslice::OwnedSubslice;
 
 pub const MESSAGE_HEADER: char = '4';
 pub const BINARY_HEADER: u8 = 4;
+/// Separates the packets of an HTTP long-polling payload.
+pub const PAYLOAD_SEPARATOR: char = '\x1e';
+/// Replaces the packet type of base64 encoded binary packets in an HTTP long-polling payload.
+pub const BASE64_HEADER: char = 'b';
+
+/// The engine.io protocol version.  Websocket messages are the same in both, but in v3 the client
+/// sends pings and the server answers with pongs, where in v4 the server pings, and HTTP
+/// long-polling payloads are framed differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EngineVersion {
+    V3,
+    #[default]
+    V4,
+}
+
+impl EngineVersion {
+    /// The value of the `EIO` query parameter.
+    pub fn query_param(self) -> &'static str {
+        match self {
+            EngineVersion::V3 => "3",
+            EngineVersion::V4 => "4",
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
 pub enum Packet {
     Open(Open),
     Close,
@@ -22,6 +49,28 @@ pub struct Open {
     pub sid: String,
     pub ping_timeout: u64,
     pub ping_interval: u64,
+    /// The transports the connection can be upgraded to.
+    #[serde(default)]
+    pub upgrades: Vec<String>,
+    /// The most bytes the server accepts in a single HTTP long-polling request, v4 only.
+    #[serde(default)]
+    pub max_payload: Option<u64>,
+}
+
+impl Open {
+    pub fn sid(&self) -> &str {
+        &self.sid
+    }
+
+    /// How often the server sends pings.
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_millis(self.ping_interval)
+    }
+
+    /// How long the server waits for a pong after sending a ping before closing the connection.
+    pub fn ping_timeout(&self) -> Duration {
+        Duration::from_millis(self.ping_timeout)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -30,10 +79,42 @@ pub enum Message {
     Binary(OwnedSubslice<Vec<u8>>),
 }
 
+/// Binary messages are serialized as base64.
+#[cfg(feature = "serialize")]
+impl Serialize for Message {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Message::Text(text) => {
+                serializer.serialize_newtype_variant("Message", 0, "Text", &**text)
+            }
+            Message::Binary(data) => serializer.serialize_newtype_variant(
+                "Message",
+                1,
+                "Binary",
+                &base64::encode(&**data),
+            ),
+        }
+    }
+}
+
+/// The most bytes of an invalid message kept in `Error::InvalidMessage`.
+const PREVIEW_LEN: usize = 64;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameKind {
+    Text,
+    Binary,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("Failed to parse websocket message: {0:?}")]
-    InvalidMessage(WsMessage),
+    /// `preview` is the start of the message, the whole message isn't kept as it may be large.
+    #[error("Failed to parse {kind:?} websocket message of length {len}: {preview}")]
+    InvalidMessage {
+        preview: String,
+        len: usize,
+        kind: FrameKind,
+    },
     #[error("Received non-Text, non-Binary websocket message: {0:?}")]
     WrongMessageType(WsMessage),
     #[error("Received message before open")]
@@ -42,26 +123,45 @@ pub enum Error {
     MessageAfterClose,
     #[error("Received second open")]
     SecondOpen,
-    #[error("Failed to parse json in message: {0:?}")]
-    JsonError(#[from] JsonError),
+    #[error("Received {kind:?} message of {size} bytes, more than the limit of {limit}")]
+    PacketTooLarge {
+        size: usize,
+        limit: usize,
+        kind: FrameKind,
+    },
+    /// `preview` is the start of the packet's json.
+    #[error("Failed to parse open packet {preview}: {error}")]
+    InvalidOpen {
+        preview: String,
+        #[source]
+        error: JsonError,
+    },
 }
 
-#[derive(Debug, PartialEq)]
-enum State {
+/// How far a `Decoder` is through its session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecoderState {
+    /// Waiting for the open packet.  Anything other than a message packet is rejected.
     Initial,
+    /// The session is open.
     Active,
+    /// A close packet was received, and everything after it is rejected.
     Closed,
 }
 
+/// Decodes the engine.io packets of a single session.  A session starts with an open packet and a
+/// second open is rejected, so the decoder must be `reset` or a new one used for each connection.
 #[derive(Debug)]
 pub struct Decoder {
-    state: State,
+    state: DecoderState,
+    max_packet_size: Option<usize>,
 }
 
 impl Default for Decoder {
     fn default() -> Self {
         Decoder {
-            state: State::Initial,
+            state: DecoderState::Initial,
+            max_packet_size: None,
         }
     }
 }
@@ -71,11 +171,45 @@ impl Decoder {
         Default::default()
     }
 
+    /// A decoder that rejects messages longer than `limit` bytes with `Error::PacketTooLarge`.
+    pub fn with_max_packet_size(limit: usize) -> Decoder {
+        let mut decoder = Decoder::new();
+        decoder.set_max_packet_size(Some(limit));
+        decoder
+    }
+
+    /// Set the longest message accepted, in bytes, or `None` for no limit, which is the default.
+    /// Can be changed during a session, e.g. once the open packet gives the server's
+    /// `maxPayload`.
+    pub fn set_max_packet_size(&mut self, limit: Option<usize>) {
+        self.max_packet_size = limit;
+    }
+
+    pub fn state(&self) -> DecoderState {
+        self.state
+    }
+
+    /// Go back to waiting for an open packet, so the decoder can be used for a new session.  The
+    /// packet size limit is kept.
+    pub fn reset(&mut self) {
+        self.state = DecoderState::Initial;
+    }
+
     pub fn decode(&mut self, msg: WsMessage) -> Result<Packet, Error> {
         use WsMessage::*;
-        if self.state == State::Closed {
+        if self.state == DecoderState::Closed {
             return Err(Error::MessageAfterClose);
         }
+        let (size, kind) = match &msg {
+            Text(text) => (text.len(), FrameKind::Text),
+            Binary(data) => (data.len(), FrameKind::Binary),
+            _ => (0, FrameKind::Text),
+        };
+        if let Some(limit) = self.max_packet_size {
+            if size > limit {
+                return Err(Error::PacketTooLarge { size, limit, kind });
+            }
+        }
         match msg {
             Ping(_) | Pong(_) | Close(_) => Err(Error::WrongMessageType(msg.clone())),
             Text(text) => self.decode_text(text),
@@ -84,35 +218,35 @@ impl Decoder {
     }
 
     fn decode_text(&mut self, text: String) -> Result<Packet, Error> {
-        let invalid_msg = || Error::InvalidMessage(WsMessage::Text(text.to_string()));
+        let invalid_msg = || invalid_text(&text);
         let typ = text.as_bytes().first().ok_or_else(invalid_msg)?;
         match *typ as char {
             '0' => {
-                if self.state != State::Initial {
+                if self.state != DecoderState::Initial {
                     Err(Error::SecondOpen)
                 } else {
                     let result = parse_open(&text[1..])?;
-                    self.state = State::Active;
+                    self.state = DecoderState::Active;
                     Ok(Packet::Open(result))
                 }
             }
             '1' => {
-                if self.state == State::Initial {
+                if self.state == DecoderState::Initial {
                     Err(Error::MessageBeforeOpen)
                 } else {
-                    self.state = State::Closed;
+                    self.state = DecoderState::Closed;
                     Ok(Packet::Close)
                 }
             }
             '2' => {
-                if self.state == State::Initial {
+                if self.state == DecoderState::Initial {
                     Err(Error::MessageBeforeOpen)
                 } else {
                     Ok(Packet::Ping)
                 }
             }
             '3' => {
-                if self.state == State::Initial {
+                if self.state == DecoderState::Initial {
                     Err(Error::MessageBeforeOpen)
                 } else {
                     Ok(Packet::Pong)
@@ -130,10 +264,14 @@ impl Decoder {
     }
 
     fn decode_binary(&mut self, data: Vec<u8>) -> Result<Packet, Error> {
-        let invalid_msg = || Error::InvalidMessage(WsMessage::Binary(data.to_vec()));
-        if self.state == State::Initial {
+        let invalid_msg = || Error::InvalidMessage {
+            preview: format!("{:?}", &data[..std::cmp::min(data.len(), PREVIEW_LEN)]),
+            len: data.len(),
+            kind: FrameKind::Binary,
+        };
+        if self.state == DecoderState::Initial {
             Err(Error::MessageBeforeOpen)
-        } else if *data.get(0).ok_or_else(invalid_msg)? != 4 {
+        } else if *data.first().ok_or_else(invalid_msg)? != 4 {
             Err(invalid_msg())
         } else {
             let len = data.len();
@@ -145,8 +283,28 @@ impl Decoder {
     }
 }
 
+/// The start of `text`, at most `PREVIEW_LEN` bytes.
+fn preview(text: &str) -> String {
+    let mut end = std::cmp::min(text.len(), PREVIEW_LEN);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].to_string()
+}
+
+fn invalid_text(text: &str) -> Error {
+    Error::InvalidMessage {
+        preview: preview(text),
+        len: text.len(),
+        kind: FrameKind::Text,
+    }
+}
+
 fn parse_open(text: &str) -> Result<Open, Error> {
-    Ok(serde_json::from_str(text)?)
+    serde_json::from_str(text).map_err(|error| Error::InvalidOpen {
+        preview: preview(text),
+        error,
+    })
 }
 
 /// Creates a Message packet from the given text
@@ -174,6 +332,10 @@ pub fn encode_binary(data: &[u8]) -> WsMessage {
     package_binary(vec)
 }
 
+pub fn encode_close() -> WsMessage {
+    WsMessage::Text("1".into())
+}
+
 pub fn encode_ping() -> WsMessage {
     WsMessage::Text("2".into())
 }
@@ -194,10 +356,134 @@ pub fn package_binary(data: Vec<u8>) -> WsMessage {
     WsMessage::Binary(data)
 }
 
+/// Split an HTTP long-polling payload into its packets, as the messages they'd be received as over
+/// a websocket, so they can be passed to a `Decoder`.  Binary packets are base64 encoded in the
+/// payload, and are decoded to binary messages starting with `BINARY_HEADER`.
+///
+/// v4 payloads separate packets with `PAYLOAD_SEPARATOR`, and v3 payloads prefix each packet
+/// with its length.  v3 binary packets must be base64 encoded, as requested by the `b64` query
+/// parameter.
+pub fn decode_payload(version: EngineVersion, payload: &str) -> Result<Vec<WsMessage>, Error> {
+    match version {
+        EngineVersion::V4 => payload
+            .split(PAYLOAD_SEPARATOR)
+            .map(decode_v4_packet)
+            .collect(),
+        EngineVersion::V3 => {
+            let mut msgs = Vec::new();
+            let mut rest = payload;
+            while !rest.is_empty() {
+                let colon = rest.find(':').ok_or_else(|| invalid_text(rest))?;
+                let len: usize = rest[..colon].parse().map_err(|_| invalid_text(rest))?;
+                rest = &rest[colon + 1..];
+                // The length is of the javascript string, in UTF-16 code units
+                let mut units = 0;
+                let mut end = 0;
+                for c in rest.chars() {
+                    if units >= len {
+                        break;
+                    }
+                    units += c.len_utf16();
+                    end += c.len_utf8();
+                }
+                if units != len {
+                    return Err(invalid_text(rest));
+                }
+                msgs.push(decode_v3_packet(&rest[..end])?);
+                rest = &rest[end..];
+            }
+            Ok(msgs)
+        }
+    }
+}
+
+fn decode_v4_packet(packet: &str) -> Result<WsMessage, Error> {
+    match packet.strip_prefix(BASE64_HEADER) {
+        Some(encoded) => decode_base64(packet, encoded),
+        None => Ok(WsMessage::Text(packet.to_string())),
+    }
+}
+
+fn decode_v3_packet(packet: &str) -> Result<WsMessage, Error> {
+    // The packet type follows the base64 header, and is always a message
+    match packet.strip_prefix(BASE64_HEADER) {
+        Some(encoded) => match encoded.strip_prefix(MESSAGE_HEADER) {
+            Some(encoded) => decode_base64(packet, encoded),
+            None => Err(invalid_text(packet)),
+        },
+        None => Ok(WsMessage::Text(packet.to_string())),
+    }
+}
+
+fn decode_base64(packet: &str, encoded: &str) -> Result<WsMessage, Error> {
+    let mut data = vec![BINARY_HEADER];
+    base64::decode_config_buf(encoded, base64::STANDARD, &mut data)
+        .map_err(|_| invalid_text(packet))?;
+    Ok(WsMessage::Binary(data))
+}
+
+/// Join messages into an HTTP long-polling payload for `version`, base64 encoding binary
+/// messages.  Websocket control frames have no equivalent in a payload and are skipped.
+pub fn encode_payload<'a>(
+    version: EngineVersion,
+    msgs: impl IntoIterator<Item = &'a WsMessage>,
+) -> String {
+    let mut payload = String::new();
+    for msg in msgs {
+        let packet = match msg {
+            WsMessage::Text(text) => text.clone(),
+            WsMessage::Binary(data) => {
+                let mut packet = BASE64_HEADER.to_string();
+                if version == EngineVersion::V3 {
+                    packet.push(MESSAGE_HEADER);
+                }
+                let data = data.strip_prefix(&[BINARY_HEADER]).unwrap_or(data);
+                base64::encode_config_buf(data, base64::STANDARD, &mut packet);
+                packet
+            }
+            _ => continue,
+        };
+        match version {
+            EngineVersion::V4 => {
+                if !payload.is_empty() {
+                    payload.push(PAYLOAD_SEPARATOR);
+                }
+            }
+            EngineVersion::V3 => {
+                let len = packet.encode_utf16().count();
+                payload.push_str(&format!("{}:", len));
+            }
+        }
+        payload.push_str(&packet);
+    }
+    payload
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn serialize_packets() {
+        let to_json = |packet: &Packet| serde_json::to_string(packet).unwrap();
+        assert_eq!(to_json(&Packet::Ping), r#""Ping""#);
+        assert_eq!(
+            to_json(&Packet::Message(Message::Text(OwnedSubslice::new(
+                "42[]".to_string(),
+                1..4
+            )))),
+            r#"{"Message":{"Text":"2[]"}}"#
+        );
+        assert_eq!(
+            to_json(&Packet::Message(Message::Binary(OwnedSubslice::new(
+                vec![4, 1, 2, 3],
+                1..4
+            )))),
+            r#"{"Message":{"Binary":"AQID"}}"#
+        );
+    }
+
     #[test]
     fn decode_close() {
         let mut decoder = Decoder::new();
@@ -216,9 +502,230 @@ mod tests {
             sid: String::from("0vtWsEAcESDOoPs8AAAA"),
             ping_interval: 25000,
             ping_timeout: 5000,
+            upgrades: Vec::new(),
+            max_payload: None,
         });
         assert_eq!(packet, expected);
+        if let Packet::Open(open) = packet {
+            assert_eq!(open.sid(), "0vtWsEAcESDOoPs8AAAA");
+            assert_eq!(open.ping_interval(), Duration::from_secs(25));
+            assert_eq!(open.ping_timeout(), Duration::from_secs(5));
+        }
         let result = decoder.decode(msg);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn decode_open_v4() {
+        let mut decoder = Decoder::new();
+
+        // Captured from a socket.io 4 server
+        let msg = WsMessage::Text(
+            r#"0{"sid":"lv_VI97HAXpY6yYWAAAC","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":20000,"maxPayload":1000000}"#.to_string());
+        match decoder.decode(msg).unwrap() {
+            Packet::Open(open) => {
+                assert_eq!(open.sid(), "lv_VI97HAXpY6yYWAAAC");
+                assert_eq!(open.upgrades, vec!["websocket"]);
+                assert_eq!(open.max_payload, Some(1_000_000));
+            }
+            p => panic!("Unexpected packet {:?}", p),
+        }
+
+        let mut decoder = Decoder::new();
+        let msg = WsMessage::Text(r#"0{"sid":"abc","pingInterval":"25000"}"#.to_string());
+        match decoder.decode(msg) {
+            Err(e @ Error::InvalidOpen { .. }) => {
+                let e = e.to_string();
+                assert!(
+                    e.contains(r#"{"sid":"abc","pingInterval":"25000"}"#),
+                    "{}",
+                    e
+                );
+                assert!(e.contains("pingInterval"), "{}", e);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn decode_reset() {
+        let mut decoder = Decoder::with_max_packet_size(64);
+        let open = || WsMessage::Text(r#"0{"sid":"abc","pingInterval":1,"pingTimeout":1}"#.into());
+        assert_eq!(decoder.state(), DecoderState::Initial);
+        decoder.decode(open()).unwrap();
+        assert_eq!(decoder.state(), DecoderState::Active);
+        decoder.decode(WsMessage::Text("2".into())).unwrap();
+        assert_eq!(decoder.state(), DecoderState::Active);
+        assert_eq!(
+            decoder.decode(WsMessage::Text("1".into())).unwrap(),
+            Packet::Close
+        );
+        assert_eq!(decoder.state(), DecoderState::Closed);
+        assert!(matches!(
+            decoder.decode(open()),
+            Err(Error::MessageAfterClose)
+        ));
+
+        decoder.reset();
+        assert_eq!(decoder.state(), DecoderState::Initial);
+        assert!(matches!(
+            decoder.decode(WsMessage::Text("2".into())),
+            Err(Error::MessageBeforeOpen)
+        ));
+        decoder.decode(open()).unwrap();
+        assert_eq!(decoder.state(), DecoderState::Active);
+        // The size limit survives the reset
+        assert!(matches!(
+            decoder.decode(WsMessage::Text(format!("4{}", "a".repeat(64)))),
+            Err(Error::PacketTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn decode_too_large() {
+        let mut decoder = Decoder::with_max_packet_size(1024 * 1024);
+        decoder
+            .decode(WsMessage::Text(
+                r#"0{"sid":"abc","pingInterval":1,"pingTimeout":1}"#.into(),
+            ))
+            .unwrap();
+
+        let text = format!("4{}", "a".repeat(10 * 1024 * 1024));
+        match decoder.decode(WsMessage::Text(text)) {
+            Err(Error::PacketTooLarge { size, limit, kind }) => {
+                assert_eq!(size, 10 * 1024 * 1024 + 1);
+                assert_eq!(limit, 1024 * 1024);
+                assert_eq!(kind, FrameKind::Text);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(matches!(
+            decoder.decode(WsMessage::Binary(vec![4; 1024 * 1024 + 1])),
+            Err(Error::PacketTooLarge {
+                kind: FrameKind::Binary,
+                ..
+            })
+        ));
+        assert!(decoder
+            .decode(WsMessage::Binary(vec![4; 1024 * 1024]))
+            .is_ok());
+
+        decoder.set_max_packet_size(None);
+        assert!(decoder
+            .decode(WsMessage::Binary(vec![4; 1024 * 1024 + 1]))
+            .is_ok());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let mut decoder = Decoder::new();
+
+        let text = format!("x{}", "é".repeat(100_000));
+        match decoder.decode(WsMessage::Text(text)) {
+            Err(Error::InvalidMessage { preview, len, kind }) => {
+                assert_eq!(preview, format!("x{}", "é".repeat(31)));
+                assert_eq!(len, 200_001);
+                assert_eq!(kind, FrameKind::Text);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(matches!(
+            decoder.decode(WsMessage::Text(String::new())),
+            Err(Error::InvalidMessage { len: 0, .. })
+        ));
+
+        decoder
+            .decode(WsMessage::Text(
+                r#"0{"sid":"abc","pingInterval":1,"pingTimeout":1}"#.into(),
+            ))
+            .unwrap();
+        match decoder.decode(WsMessage::Binary(vec![5; 1000])) {
+            Err(Error::InvalidMessage { preview, len, kind }) => {
+                assert_eq!(preview, format!("{:?}", [5u8; 64]));
+                assert_eq!(len, 1000);
+                assert_eq!(kind, FrameKind::Binary);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn decode_new_session() {
+        let mut decoder = Decoder::new();
+
+        let open = r#"0{"sid":"abc","upgrades":[],"pingInterval":25000,"pingTimeout":5000}"#;
+        let msg = WsMessage::Text(open.to_string());
+        assert!(matches!(
+            decoder.decode(WsMessage::Text("2".to_string())),
+            Err(Error::MessageBeforeOpen)
+        ));
+        assert!(decoder.decode(msg.clone()).is_ok());
+        assert!(matches!(
+            decoder.decode(msg.clone()),
+            Err(Error::SecondOpen)
+        ));
+        // The second open doesn't affect the session
+        assert_eq!(
+            decoder.decode(WsMessage::Text("2".to_string())).unwrap(),
+            Packet::Ping
+        );
+
+        // A reconnect uses a new decoder for the new session
+        let mut decoder = Decoder::new();
+        assert!(decoder.decode(msg).is_ok());
+    }
+
+    #[test]
+    fn payload() {
+        let msgs = vec![
+            WsMessage::Text("2".into()),
+            WsMessage::Ping(vec![]),
+            WsMessage::Text(r#"42["event",1]"#.into()),
+            encode_binary(&[1, 2, 3]),
+        ];
+        let payload = encode_payload(EngineVersion::V4, &msgs);
+        assert_eq!(payload, "2\x1e42[\"event\",1]\x1ebAQID");
+
+        let decoded = decode_payload(EngineVersion::V4, &payload).unwrap();
+        assert_eq!(
+            decoded,
+            vec![msgs[0].clone(), msgs[2].clone(), msgs[3].clone()]
+        );
+        let mut decoder = Decoder::new();
+        decoder
+            .decode(WsMessage::Text(
+                r#"0{"sid":"abc","pingInterval":1,"pingTimeout":1}"#.into(),
+            ))
+            .unwrap();
+        assert_eq!(
+            decoder.decode(decoded[2].clone()).unwrap(),
+            Packet::Message(Message::Binary(OwnedSubslice::new(vec![4, 1, 2, 3], 1..4)))
+        );
+
+        assert!(matches!(
+            decode_payload(EngineVersion::V4, "2\x1eb!!"),
+            Err(Error::InvalidMessage { len: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn payload_v3() {
+        let msgs = vec![
+            WsMessage::Text("2".into()),
+            WsMessage::Text(r#"42["é😀"]"#.into()),
+            encode_binary(&[1, 2, 3]),
+        ];
+        let payload = encode_payload(EngineVersion::V3, &msgs);
+        // The emoji is two UTF-16 code units
+        assert_eq!(payload, "1:29:42[\"é😀\"]6:b4AQID");
+        assert_eq!(decode_payload(EngineVersion::V3, &payload).unwrap(), msgs);
+
+        for invalid in &["2", "x:2", "3:2", "3:b3AQID"] {
+            assert!(
+                decode_payload(EngineVersion::V3, invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
 }
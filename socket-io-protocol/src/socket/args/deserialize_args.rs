@@ -0,0 +1,85 @@
+use serde::{
+    de::{DeserializeSeed, Error as DeError, SeqAccess, Visitor},
+    forward_to_deserialize_any, Deserializer,
+};
+use serde_json::Error as JsonError;
+
+use super::Args;
+
+/// Presents the arguments of a packet as a sequence, so they can be deserialized into a tuple,
+/// `Vec` or tuple struct.
+pub(super) struct ArgsDeserializer<'b, 'de> {
+    args: &'b Args<'de>,
+}
+
+struct ArgsSeqAccess<'b, 'de> {
+    args: &'b Args<'de>,
+    idx: usize,
+}
+
+impl<'b, 'de> ArgsDeserializer<'b, 'de> {
+    pub(super) fn new(args: &'b Args<'de>) -> Self {
+        ArgsDeserializer { args }
+    }
+}
+
+impl<'b, 'de> Deserializer<'de> for ArgsDeserializer<'b, 'de> {
+    type Error = JsonError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, JsonError>
+    where
+        V: Visitor<'de>,
+    {
+        let mut seq = ArgsSeqAccess {
+            args: self.args,
+            idx: 0,
+        };
+        let value = visitor.visit_seq(&mut seq)?;
+        if seq.idx != self.args.len() {
+            Err(JsonError::invalid_length(
+                self.args.len(),
+                &format!("{} arguments", seq.idx).as_str(),
+            ))
+        } else {
+            Ok(value)
+        }
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value, JsonError>
+    where
+        V: Visitor<'de>,
+    {
+        if self.args.is_empty() {
+            visitor.visit_unit()
+        } else {
+            Err(JsonError::invalid_length(self.args.len(), &"0 arguments"))
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier
+        ignored_any
+    }
+}
+
+impl<'b, 'de> SeqAccess<'de> for ArgsSeqAccess<'b, 'de> {
+    type Error = JsonError;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, JsonError>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.args.get(self.idx) {
+            Some(arg) => {
+                self.idx += 1;
+                arg.deserialize_seed(seed).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.args.len() - self.idx)
+    }
+}
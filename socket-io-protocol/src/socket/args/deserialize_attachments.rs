@@ -1,4 +1,4 @@
-use std::fmt;
+use std::{collections::VecDeque, fmt};
 
 use owned_subslice::OwnedSubslice;
 use paste::paste;
@@ -8,32 +8,30 @@ use serde::{
         DeserializeSeed, EnumAccess, Error as DeError, MapAccess, SeqAccess, VariantAccess,
         Visitor,
     },
-    Deserialize, Deserializer,
+    Deserializer,
 };
-use serde_json::{Deserializer as JsonDeserializer, Error as JsonError};
-
-#[derive(Debug, thiserror::Error)]
-#[error("{0}")]
-pub struct Error(String);
-
-impl DeError for Error {
-    fn custom<T>(msg: T) -> Self
-    where
-        T: fmt::Display,
-    {
-        Error(msg.to_string())
-    }
-}
+use serde_json::{value::RawValue, Deserializer as JsonDeserializer, Error as JsonError};
 
 type Buffers<'a> = &'a [OwnedSubslice<Vec<u8>>];
 
-pub(super) fn deserialize<'a, T>(arg: &'a str, buffers: Buffers<'a>) -> Result<T, JsonError>
+/// Deserialize `arg`, replacing placeholders with the attachments in `buffers`.  Fails if
+/// arrays, objects and enums are nested more than `max_depth` levels deep.
+pub(super) fn deserialize_seed<'a, T>(
+    seed: T,
+    arg: &'a str,
+    buffers: Buffers<'a>,
+    max_depth: usize,
+) -> Result<T::Value, JsonError>
 where
-    T: Deserialize<'a>,
+    T: DeserializeSeed<'a>,
 {
     let mut d = JsonDeserializer::from_str(arg);
-    let deserializer = BinaryDeserializer { d: &mut d, buffers };
-    T::deserialize(deserializer)
+    let deserializer = BinaryDeserializer {
+        d: &mut d,
+        buffers,
+        depth: max_depth,
+    };
+    seed.deserialize(deserializer)
 }
 
 enum AccessType {
@@ -48,6 +46,7 @@ where
 {
     d: D,
     buffers: Buffers<'de>,
+    depth: usize,
 }
 
 struct BinaryVisitor<'de, V>
@@ -56,6 +55,7 @@ where
 {
     visitor: V,
     buffers: Buffers<'de>,
+    depth: usize,
     access_type: AccessType,
 }
 
@@ -65,6 +65,7 @@ where
 {
     seq: S,
     buffers: Buffers<'de>,
+    depth: usize,
 }
 
 struct BinaryEnumAccess<'de, E>
@@ -73,6 +74,7 @@ where
 {
     data: E,
     buffers: Buffers<'de>,
+    depth: usize,
 }
 
 struct BinaryVariantAccess<'de, V>
@@ -81,6 +83,7 @@ where
 {
     variant: V,
     buffers: Buffers<'de>,
+    depth: usize,
 }
 
 struct BinarySeed<'a, T>
@@ -89,6 +92,7 @@ where
 {
     seed: T,
     buffers: Buffers<'a>,
+    depth: usize,
 }
 
 struct BinaryMapAccess<'a, M>
@@ -97,7 +101,15 @@ where
 {
     map: M,
     buffers: Buffers<'a>,
-    first_key: Option<Option<&'a str>>,
+    depth: usize,
+    // Keys and values already read from `map` while checking for a placeholder, which are
+    // returned before reading any more
+    replay: VecDeque<Replay<'a>>,
+}
+
+enum Replay<'a> {
+    Key(Option<&'a str>),
+    Value(&'a RawValue),
 }
 
 macro_rules! deserialize_forward {
@@ -113,6 +125,7 @@ macro_rules! deserialize_forward {
                             BinaryVisitor {
                                 visitor,
                                 buffers: self.buffers,
+                depth: self.depth,
                                 access_type: AccessType::Neither,
                             }
                         )
@@ -135,6 +148,7 @@ macro_rules! deserialize_forward_any {
                         BinaryVisitor {
                             visitor,
                             buffers: self.buffers,
+                depth: self.depth,
                             access_type: $at,
                         })
                 }
@@ -247,6 +261,7 @@ where
         let wrapped = BinaryDeserializer {
             d,
             buffers: self.buffers,
+            depth: self.depth,
         };
         self.visitor.visit_some(wrapped)
     }
@@ -258,6 +273,7 @@ where
         let wrapped = BinaryDeserializer {
             d,
             buffers: self.buffers,
+            depth: self.depth,
         };
         self.visitor.visit_newtype_struct(wrapped)
     }
@@ -269,6 +285,7 @@ where
         let wrapped = BinarySeqAccess {
             seq,
             buffers: self.buffers,
+            depth: nested_depth(self.depth)?,
         };
         self.visitor.visit_seq(wrapped)
     }
@@ -280,6 +297,7 @@ where
         let wrapped = BinaryEnumAccess {
             data,
             buffers: self.buffers,
+            depth: nested_depth(self.depth)?,
         };
         self.visitor.visit_enum(wrapped)
     }
@@ -288,38 +306,101 @@ where
     where
         A: MapAccess<'de>,
     {
+        let depth = nested_depth(self.depth)?;
+        // Placeholders are `{"_placeholder":true,"num":0}`, but the keys can be in either order.
+        // An object with `"_placeholder": false` isn't a placeholder, so is replayed as is.
         let key: Option<&'de str> = map.next_key()?;
-        if key == Some("_placeholder") {
-            let _: bool = map.next_value()?;
-            let next_key: Option<&'de str> = map.next_key()?;
-            if next_key != Some("num") {
-                return Err(A::Error::custom("_placeholder key present without num key"));
+        let num = match key {
+            Some("_placeholder") => {
+                let flag: &'de RawValue = map.next_value()?;
+                if !placeholder_flag::<A::Error>(flag)? {
+                    Err(vec![Replay::Key(key), Replay::Value(flag)])
+                } else {
+                    let next_key: Option<&'de str> = map.next_key()?;
+                    if next_key != Some("num") {
+                        return Err(A::Error::custom("_placeholder key present without num key"));
+                    }
+                    let num: u64 = map.next_value()?;
+                    Ok(num)
+                }
             }
-            let num: u64 = map.next_value()?;
-            let buffer = self.buffers.get(num as usize).ok_or_else(|| {
-                A::Error::custom(format!(
-                    "Placeholder num out of range: {}/{}",
-                    num,
-                    self.buffers.len()
-                ))
-            })?;
-            match self.access_type {
-                AccessType::Bytes => self.visitor.visit_borrowed_bytes(&*buffer),
-                AccessType::Seq | AccessType::Neither => self
-                    .visitor
-                    .visit_seq(SeqDeserializer::new(buffer.iter().copied())),
+            Some("num") => {
+                // This might not be a placeholder, so keep the value to replay if it isn't
+                let value: &'de RawValue = map.next_value()?;
+                let next_key: Option<&'de str> = map.next_key()?;
+                if next_key == Some("_placeholder") {
+                    let flag: &'de RawValue = map.next_value()?;
+                    if placeholder_flag::<A::Error>(flag)? {
+                        let num: u64 =
+                            serde_json::from_str(value.get()).map_err(A::Error::custom)?;
+                        Ok(num)
+                    } else {
+                        Err(vec![
+                            Replay::Key(key),
+                            Replay::Value(value),
+                            Replay::Key(next_key),
+                            Replay::Value(flag),
+                        ])
+                    }
+                } else {
+                    Err(vec![
+                        Replay::Key(key),
+                        Replay::Value(value),
+                        Replay::Key(next_key),
+                    ])
+                }
             }
-        } else {
-            let map = BinaryMapAccess {
-                map,
-                buffers: self.buffers,
-                first_key: Some(key),
-            };
-            self.visitor.visit_map(map)
+            _ => Err(vec![Replay::Key(key)]),
+        };
+        match num {
+            Ok(num) => self.visit_placeholder::<A::Error>(num),
+            Err(replay) => {
+                let map = BinaryMapAccess {
+                    map,
+                    buffers: self.buffers,
+                    depth,
+                    replay: replay.into(),
+                };
+                self.visitor.visit_map(map)
+            }
+        }
+    }
+}
+
+/// Whether the value of a `_placeholder` key marks the object as a placeholder.
+fn placeholder_flag<E: DeError>(flag: &RawValue) -> Result<bool, E> {
+    serde_json::from_str(flag.get())
+        .map_err(|_| E::custom(format!("_placeholder isn't a boolean: {}", flag.get())))
+}
+
+impl<'de, V> BinaryVisitor<'de, V>
+where
+    V: Visitor<'de>,
+{
+    fn visit_placeholder<E: DeError>(self, num: u64) -> Result<V::Value, E> {
+        let buffer = self.buffers.get(num as usize).ok_or_else(|| {
+            E::custom(format!(
+                "Placeholder num out of range: {}/{}",
+                num,
+                self.buffers.len()
+            ))
+        })?;
+        match self.access_type {
+            AccessType::Bytes => self.visitor.visit_borrowed_bytes(buffer),
+            AccessType::Seq | AccessType::Neither => self
+                .visitor
+                .visit_seq(SeqDeserializer::new(buffer.iter().copied())),
         }
     }
 }
 
+/// The depth left for the values in a container opened with `depth` left.
+fn nested_depth<E: DeError>(depth: usize) -> Result<usize, E> {
+    depth
+        .checked_sub(1)
+        .ok_or_else(|| E::custom("Exceeded maximum nesting depth"))
+}
+
 impl<'de, S> SeqAccess<'de> for BinarySeqAccess<'de, S>
 where
     S: SeqAccess<'de>,
@@ -333,6 +414,7 @@ where
         self.seq.next_element_seed(BinarySeed {
             seed,
             buffers: self.buffers,
+            depth: self.depth,
         })
     }
 }
@@ -354,6 +436,7 @@ where
             BinaryVariantAccess {
                 variant,
                 buffers: self.buffers,
+                depth: self.depth,
             },
         ))
     }
@@ -376,6 +459,7 @@ where
         self.variant.newtype_variant_seed(BinarySeed {
             seed,
             buffers: self.buffers,
+            depth: self.depth,
         })
     }
 
@@ -388,6 +472,7 @@ where
             BinaryVisitor {
                 visitor,
                 buffers: self.buffers,
+                depth: self.depth,
                 access_type: AccessType::Seq,
             },
         )
@@ -406,6 +491,7 @@ where
             BinaryVisitor {
                 visitor,
                 buffers: self.buffers,
+                depth: self.depth,
                 access_type: AccessType::Seq,
             },
         )
@@ -422,7 +508,9 @@ where
     where
         K: DeserializeSeed<'de>,
     {
-        if let Some(key) = self.first_key.take() {
+        if let Some(Replay::Key(key)) = self.replay.front() {
+            let key = *key;
+            self.replay.pop_front();
             if let Some(key) = key {
                 let deserializer = BorrowedStrDeserializer::new(key);
                 seed.deserialize(deserializer).map(Some)
@@ -440,10 +528,19 @@ where
     where
         V: DeserializeSeed<'de>,
     {
-        self.map.next_value_seed(BinarySeed {
+        let seed = BinarySeed {
             seed,
             buffers: self.buffers,
-        })
+            depth: self.depth,
+        };
+        if let Some(Replay::Value(value)) = self.replay.front() {
+            let value = *value;
+            self.replay.pop_front();
+            seed.deserialize(&mut JsonDeserializer::from_str(value.get()))
+                .map_err(M::Error::custom)
+        } else {
+            self.map.next_value_seed(seed)
+        }
     }
 }
 
@@ -460,6 +557,7 @@ where
         let wrapper = BinaryDeserializer {
             d,
             buffers: self.buffers,
+            depth: self.depth,
         };
         self.seed.deserialize(wrapper)
     }
@@ -469,12 +567,25 @@ where
 mod tests {
     use super::*;
 
+    use std::marker::PhantomData;
+
+    use serde::Deserialize;
+
+    fn deserialize<'a, T>(arg: &'a str, buffers: Buffers<'a>) -> Result<T, JsonError>
+    where
+        T: Deserialize<'a>,
+    {
+        deserialize_seed(PhantomData, arg, buffers, 128)
+    }
+
     #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
     struct BinaryNoTranslate {
         array: Placeholder,
     }
 
     #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
     struct Placeholder {
         _placeholder: bool,
         num: u64,
@@ -485,7 +596,7 @@ mod tests {
         let attachment = [222, 173, 190, 239];
         let attachments = [attachment.to_vec().into()];
         let json = "{\"array\": {\"_placeholder\":true,\"num\":0}}";
-        let _ = deserialize::<BinaryNoTranslate>(&json, &attachments[..])
+        let _ = deserialize::<BinaryNoTranslate>(json, &attachments[..])
             .expect_err("Deserialization succeeded");
     }
 
@@ -499,7 +610,7 @@ mod tests {
         let attachment = [222, 173, 190, 239];
         let attachments = [attachment.to_vec().into()];
         let json = "{\"array\": {\"_placeholder\":true,\"num\":0}}";
-        let res: BinaryBorrowed = deserialize(&json, &attachments[..]).unwrap();
+        let res: BinaryBorrowed = deserialize(json, &attachments[..]).unwrap();
         assert_eq!(res.array, &attachment[..]);
     }
 
@@ -513,16 +624,71 @@ mod tests {
         let attachment = [222, 173, 190, 239];
         let attachments = [attachment.to_vec().into()];
         let json = "{\"array\": {\"_placeholder\":true,\"num\":0}}";
-        let res: BinaryOwned = deserialize(&json, &attachments[..]).unwrap();
+        let res: BinaryOwned = deserialize(json, &attachments[..]).unwrap();
+        assert_eq!(res.array, attachment.to_vec());
+    }
+
+    #[test]
+    fn test_reversed_keys() {
+        let attachment = [222, 173, 190, 239];
+        let attachments = [attachment.to_vec().into()];
+        let json = "{\"array\": {\"num\":0,\"_placeholder\":true}}";
+        let res: BinaryBorrowed = deserialize(json, &attachments[..]).unwrap();
+        assert_eq!(res.array, &attachment[..]);
+        let res: BinaryOwned = deserialize(json, &attachments[..]).unwrap();
         assert_eq!(res.array, attachment.to_vec());
     }
 
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct NumFirst {
+        num: Vec<u8>,
+        other: u32,
+    }
+
+    #[test]
+    fn test_num_key_passthrough() {
+        let attachments = [];
+        let json = "{\"num\": [1, 2], \"other\": 3}";
+        let res: NumFirst = deserialize(json, &attachments[..]).unwrap();
+        assert_eq!(
+            res,
+            NumFirst {
+                num: vec![1, 2],
+                other: 3
+            }
+        );
+        let json = "{\"num\": 1}";
+        let res: std::collections::HashMap<String, u32> =
+            deserialize(json, &attachments[..]).unwrap();
+        assert_eq!(res["num"], 1);
+    }
+
+    #[test]
+    fn test_placeholder_false() {
+        let attachments = [vec![1u8].into()];
+        let json = r#"{"_placeholder":false,"num":0}"#;
+        let res: std::collections::HashMap<String, serde_json::Value> =
+            deserialize(json, &attachments[..]).unwrap();
+        assert_eq!(res["_placeholder"], false);
+        assert_eq!(res["num"], 0);
+        let json = r#"{"num":0,"_placeholder":false,"other":1}"#;
+        let res: std::collections::HashMap<String, serde_json::Value> =
+            deserialize(json, &attachments[..]).unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(res["_placeholder"], false);
+
+        let json = r#"{"_placeholder":"yes","num":0}"#;
+        assert!(deserialize::<BinaryOwned>(json, &attachments[..]).is_err());
+        let json = r#"{"num":0,"_placeholder":1}"#;
+        assert!(deserialize::<BinaryOwned>(json, &attachments[..]).is_err());
+    }
+
     #[test]
     fn test_passthrough() {
         let attachment = [222, 173, 190, 239];
         let attachments = [];
         let json = "{\"array\": [222, 173, 190, 239]}";
-        let res: BinaryOwned = deserialize(&json, &attachments[..]).unwrap();
+        let res: BinaryOwned = deserialize(json, &attachments[..]).unwrap();
         assert_eq!(res.array, attachment.to_vec());
     }
 
@@ -537,7 +703,7 @@ mod tests {
     fn test_enum_passthrough() {
         let attachments = [];
         let json = "{\"B\": 23}";
-        let res: Enum = deserialize(&json, &attachments[..]).unwrap();
+        let res: Enum = deserialize(json, &attachments[..]).unwrap();
         assert_eq!(res, Enum::B(23));
     }
 }
@@ -1,43 +1,75 @@
 use std::{fmt, io::Write};
 
 use owned_subslice::OwnedSubslice;
-use serde::{Deserialize, Serialize};
-use serde_json::{value::Value, Error as JsonError};
+use serde::{de::DeserializeSeed, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{value::Value, Deserializer as JsonDeserializer, Error as JsonError};
 use tungstenite::Message as WsMessage;
 
 use super::*;
 
+mod deserialize_args;
 mod deserialize_attachments;
+mod owned;
 mod serialize_attachments;
 
+pub use owned::OwnedArgs;
+
+/// The default limit on how deeply arrays and objects in an argument can be nested, the same as
+/// `serde_json`'s own limit.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+pub use serialize_attachments::BinaryMode;
+
 #[derive(Debug, Clone)]
 pub struct Args<'a> {
     pub(super) message: &'a str,
     pub(super) args: &'a [Range<usize>],
     pub(super) attachments: &'a [OwnedSubslice<Vec<u8>>],
+    pub(super) max_depth: usize,
 }
 
 #[derive(Debug, Clone)]
 pub struct Arg<'a> {
     arg: &'a str,
     attachments: &'a [OwnedSubslice<Vec<u8>>],
+    max_depth: usize,
 }
 
+/// Wrapping a value in `NoBinary` stops it from being sent as binary attachments when it's
+/// serialized into a binary packet, so it's always sent as plain JSON.
+///
+/// By default, binary packets send any sequence consisting entirely of `u8`s (e.g. a `Vec<u8>` or
+/// `[u8; 4]`) as an attachment, which the receiver sees as binary data (e.g. a `Buffer` in
+/// node.js).  This is unwanted when the sequence is really a JSON array of small integers, in
+/// which case it can be wrapped in `NoBinary` or the field annotated with
+/// `#[serde(serialize_with = "no_binary")]`.  Outside of binary packets `NoBinary` has no effect.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NoBinary<T>(pub T);
+
 struct ArgsIter<'a> {
     args: &'a Args<'a>,
+    // The arguments in `idx..end` haven't been yielded from either end yet
     idx: usize,
+    end: usize,
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Placeholder object is missing num field: {0:?}")]
     NoNumInPlaceholderObject(Value),
+    #[error("Placeholder object's _placeholder field isn't a boolean: {0:?}")]
+    InvalidPlaceholder(Value),
     #[error("Placeholder index out of range: {0}/{1}")]
     PlaceholderIndexOutOfRange(u64, u64),
     #[error("Error deserializing json: {0}, {1}")]
     JsonDeError(String, JsonError),
     #[error("Error serializing object to json: {0}")]
     JsonSerError(JsonError),
+    /// Serializing an argument of a packet failed.  `index` counts from the first argument after
+    /// an event's name, or is 0 for a connect packet's payload.
+    #[error("Error serializing argument {index} to json: {error}")]
+    ArgSerError { index: usize, error: JsonError },
+    #[error("Exceeded maximum nesting depth")]
+    MaxDepthExceeded,
 }
 
 impl<'a> Args<'a> {
@@ -53,70 +85,188 @@ impl<'a> Args<'a> {
         self.args.get(idx).map(|range| Arg {
             arg: &self.message[range.clone()],
             attachments: self.attachments,
+            max_depth: self.max_depth,
         })
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = Arg<'_>> {
-        ArgsIter { args: self, idx: 0 }
+    /// Limit how deeply arrays and objects can be nested when the arguments are converted to
+    /// values or deserialized with attachments, which recurses once per level, so a maliciously
+    /// nested argument fails instead of overflowing the stack.  Defaults to `DEFAULT_MAX_DEPTH`.
+    /// Arguments without attachments are deserialized by `serde_json` directly, which always
+    /// limits nesting to 128 levels.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = Arg<'_>> + DoubleEndedIterator {
+        ArgsIter {
+            args: self,
+            idx: 0,
+            end: self.len(),
+        }
+    }
+
+    /// Deserialize all of the arguments as a sequence, e.g. into a tuple with one element per
+    /// argument, or into a `Vec`.  An empty argument list can also be deserialized into `()`.
+    /// Fails if the number of arguments doesn't match the length `T` expects.
+    pub fn deserialize_all<T>(&self) -> Result<T, Error>
+    where
+        T: Deserialize<'a>,
+    {
+        T::deserialize(deserialize_args::ArgsDeserializer::new(self)).map_err(|err| {
+            let raw = self.args.iter().map(|range| &self.message[range.clone()]);
+            Error::JsonDeError(format!("[{}]", raw.collect::<Vec<_>>().join(",")), err)
+        })
+    }
+
+    /// Like `deserialize_all`, but skipping the first argument, i.e. an event's arguments without
+    /// its name, e.g. `let (user, msg): (User, String) = args.rest()?`.
+    pub fn rest<T>(&self) -> Result<T, Error>
+    where
+        T: Deserialize<'a>,
+    {
+        let rest = Args {
+            args: self.args.get(1..).unwrap_or_default(),
+            ..self.clone()
+        };
+        rest.deserialize_all()
     }
 }
 
 impl<'a> Arg<'a> {
+    /// The argument's JSON text exactly as it was received, e.g. to forward or store it without
+    /// parsing it.  Binary data is left as the `{"_placeholder":true,"num":0}` objects that refer
+    /// to the packet's attachments, so the text alone doesn't contain the data; use
+    /// `to_json_value` to get the argument with the attachments filled in.
+    pub fn raw_json(&self) -> &'a str {
+        self.arg
+    }
+
+    /// Parse the argument into a `serde_json::Value`.  This builds the entire JSON tree in memory,
+    /// so for large arguments prefer `deserialize` into a type that only contains the data needed.
     pub fn to_json_value(&self) -> Result<Value, Error> {
-        // We can unwrap because if the json was going to fail to deserialize we would have failed
-        // to parse
-        let mut value = serde_json::from_str(self.arg).unwrap();
+        // The json was validated when the packet was parsed, but without a nesting limit, so this
+        // can still fail for deeply nested arguments
+        let mut value = serde_json::from_str(self.arg)
+            .map_err(|err| Error::JsonDeError(self.arg.to_string(), err))?;
         if !self.attachments.is_empty() {
-            fill_placeholders_value(&mut value, &self.attachments)?;
+            let mut converted = vec![None; self.attachments.len()];
+            fill_placeholders_value(&mut value, self.attachments, &mut converted, self.max_depth)?;
         }
         Ok(value)
     }
 
+    /// Deserialize the argument into `T`.  Deserialization is streamed directly from the packet
+    /// text, without building an intermediate `Value`, so fields and elements that `T` doesn't
+    /// contain are skipped over rather than allocated, and `&str` fields can borrow from the
+    /// packet.
     pub fn deserialize<T>(&self) -> Result<T, Error>
     where
         T: Deserialize<'a>,
     {
-        (if !self.attachments.is_empty() {
-            deserialize_attachments::deserialize(self.arg, self.attachments)
+        self.deserialize_seed(std::marker::PhantomData)
+            .map_err(|err| Error::JsonDeError(self.arg.to_string(), err))
+    }
+
+    fn deserialize_seed<T>(&self, seed: T) -> Result<T::Value, JsonError>
+    where
+        T: DeserializeSeed<'a>,
+    {
+        if !self.attachments.is_empty() {
+            deserialize_attachments::deserialize_seed(
+                seed,
+                self.arg,
+                self.attachments,
+                self.max_depth,
+            )
         } else {
-            serde_json::from_str(self.arg)
-        })
-        .map_err(|err| Error::JsonDeError(self.arg.to_string(), err))
+            seed.deserialize(&mut JsonDeserializer::from_str(self.arg))
+        }
+    }
+}
+
+impl<T> From<T> for NoBinary<T> {
+    fn from(t: T) -> Self {
+        NoBinary(t)
+    }
+}
+
+impl<T> Serialize for NoBinary<T>
+where
+    T: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(serialize_attachments::NO_BINARY, &self.0)
     }
 }
 
+impl<'de, T> Deserialize<'de> for NoBinary<T>
+where
+    T: Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(NoBinary)
+    }
+}
+
+/// Serializes `value` the same way as `NoBinary(value)`, for use with
+/// `#[serde(serialize_with = "no_binary")]`.
+pub fn no_binary<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Serialize,
+    S: Serializer,
+{
+    NoBinary(value).serialize(serializer)
+}
+
+/// Replace placeholders in `value` with their attachments.  `converted` holds each attachment
+/// once it's been converted to a value, so an attachment referenced by several placeholders is
+/// only converted once.  `depth` is how many more levels of arrays and objects can be nested.
 fn fill_placeholders_value(
     value: &mut Value,
     buffers: &[OwnedSubslice<Vec<u8>>],
+    converted: &mut [Option<Value>],
+    depth: usize,
 ) -> Result<(), Error> {
     use Value::*;
 
+    if let Array(_) | Object(_) = value {
+        if depth == 0 {
+            return Err(Error::MaxDepthExceeded);
+        }
+    }
     let idx = match value {
         Null | Bool(_) | Number(_) | String(_) => return Ok(()),
         Array(values) => {
             return values
                 .iter_mut()
-                .map(|x| fill_placeholders_value(x, buffers))
-                .collect();
+                .try_for_each(|x| fill_placeholders_value(x, buffers, converted, depth - 1));
         }
         Object(map) => {
-            // Determine if it's a placeholder
-            if map.contains_key("_placeholder") {
+            // Only `"_placeholder": true` marks a placeholder, other objects can have the key too
+            let placeholder = match map.get("_placeholder") {
+                None | Some(Bool(false)) => false,
+                Some(Bool(true)) => true,
+                Some(_) => return Err(Error::InvalidPlaceholder(value.clone())),
+            };
+            if placeholder {
                 map.get("num")
                     .and_then(|v| v.as_u64())
                     .ok_or_else(|| Error::NoNumInPlaceholderObject(value.clone()))?
             } else {
                 return map
                     .values_mut()
-                    .map(|x| fill_placeholders_value(x, buffers))
-                    .collect();
+                    .try_for_each(|x| fill_placeholders_value(x, buffers, converted, depth - 1));
             }
         }
     };
     let buffer = buffers
         .get(idx as usize)
-        .ok_or_else(|| Error::PlaceholderIndexOutOfRange(idx, buffers.len() as u64))?;
-    *value = Value::Array(buffer.iter().copied().map(|x| x.into()).collect());
+        .ok_or(Error::PlaceholderIndexOutOfRange(idx, buffers.len() as u64))?;
+    *value = converted[idx as usize]
+        .get_or_insert_with(|| Value::Array(buffer.iter().copied().map(|x| x.into()).collect()))
+        .clone();
     Ok(())
 }
 
@@ -128,18 +278,75 @@ pub fn serialize_arg_binary(
     writer: impl Write,
     arg: &(impl Serialize + ?Sized),
     buffers: &mut Vec<WsMessage>,
+    mode: BinaryMode,
 ) -> Result<(), Error> {
-    serialize_attachments::serialize_json(arg, writer, buffers).map_err(Error::JsonSerError)
+    serialize_attachments::serialize_json(arg, writer, buffers, mode).map_err(Error::JsonSerError)
 }
 
 impl<'a> Iterator for ArgsIter<'a> {
     type Item = Arg<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.idx == self.end {
+            return None;
+        }
         let item = self.args.get(self.idx);
         self.idx += 1;
         item
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end - self.idx;
+        (len, Some(len))
+    }
+}
+
+impl<'a> DoubleEndedIterator for ArgsIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.idx == self.end {
+            return None;
+        }
+        self.end -= 1;
+        self.args.get(self.end)
+    }
+}
+
+impl<'a> ExactSizeIterator for ArgsIter<'a> {}
+
+/// Serialized as a sequence of the arguments' JSON values, with attachments filled in.
+#[cfg(feature = "serialize")]
+impl<'a> Serialize for Args<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+/// Serialized as the argument's JSON value, with attachments filled in as arrays of bytes.
+#[cfg(feature = "serialize")]
+impl<'a> Serialize for Arg<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error as _;
+        self.to_json_value()
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+/// Arguments are equal if their JSON text is exactly the same, e.g. `{"a":1}` and `{"a": 1}`
+/// differ, and the attachments they can refer to have the same data.
+impl<'a, 'b> PartialEq<Args<'b>> for Args<'a> {
+    fn eq(&self, other: &Args<'b>) -> bool {
+        self.len() == other.len()
+            && self.iter().zip(other.iter()).all(|(a, b)| a.arg == b.arg)
+            && self.attachments == other.attachments
+    }
+}
+
+/// Compared like `Args`, by JSON text and the attachments it can refer to.
+impl<'a, 'b> PartialEq<Arg<'b>> for Arg<'a> {
+    fn eq(&self, other: &Arg<'b>) -> bool {
+        self.arg == other.arg && self.attachments == other.attachments
+    }
 }
 
 impl<'a> fmt::Display for Args<'a> {
@@ -159,7 +366,10 @@ impl<'a> fmt::Display for Args<'a> {
 
 impl<'a> fmt::Display for Arg<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.to_json_value().unwrap())
+        match self.to_json_value() {
+            Ok(value) => write!(f, "{}", value),
+            Err(_) => write!(f, "{}", self.arg),
+        }
     }
 }
 
@@ -190,6 +400,105 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_repeated_placeholder() {
+        let placeholder = r#"{"_placeholder":true,"num":0}"#;
+        let m = format!(r#"51-["binary",[{}]]"#, [placeholder; 5].join(","));
+        let partial = match deserialize(EngineMessage::Text(m.into())).unwrap() {
+            DeserializeResult::DataNeeded(partial) => partial,
+            _ => unreachable!(),
+        };
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        let attachments = vec![EngineMessage::Binary(data.clone().into())];
+        let packet = deserialize_partial(partial, attachments).unwrap();
+        let args = match packet.data() {
+            Data::Event { args, .. } => args,
+            _ => unreachable!(),
+        };
+
+        let expected = Value::Array(data.iter().map(|&x| x.into()).collect());
+        match args.get(1).unwrap().to_json_value().unwrap() {
+            Value::Array(values) => {
+                assert_eq!(values.len(), 5);
+                assert!(values.iter().all(|value| *value == expected));
+            }
+            value => panic!("Expected array, got {:?}", value),
+        }
+    }
+
+    #[test]
+    fn test_placeholder_false() {
+        let m = r#"51-["binary",{"_placeholder":false,"num":0},{"_placeholder":true,"num":0}]"#;
+        let partial = match deserialize(EngineMessage::Text(m.to_string().into())).unwrap() {
+            DeserializeResult::DataNeeded(partial) => partial,
+            _ => unreachable!(),
+        };
+        let attachments = vec![EngineMessage::Binary(vec![1u8, 2].into())];
+        let packet = deserialize_partial(partial, attachments).unwrap();
+        let args = match packet.data() {
+            Data::Event { args, .. } => args,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            args.get(1).unwrap().to_json_value().unwrap().to_string(),
+            r#"{"_placeholder":false,"num":0}"#
+        );
+        assert_eq!(
+            args.get(2).unwrap().to_json_value().unwrap().to_string(),
+            "[1,2]"
+        );
+
+        let m = r#"51-["binary",{"_placeholder":"yes","num":0}]"#;
+        let partial = match deserialize(EngineMessage::Text(m.to_string().into())).unwrap() {
+            DeserializeResult::DataNeeded(partial) => partial,
+            _ => unreachable!(),
+        };
+        let attachments = vec![EngineMessage::Binary(vec![1u8, 2].into())];
+        let packet = deserialize_partial(partial, attachments).unwrap();
+        let args = match packet.data() {
+            Data::Event { args, .. } => args,
+            _ => unreachable!(),
+        };
+        assert!(matches!(
+            args.get(1).unwrap().to_json_value(),
+            Err(Error::InvalidPlaceholder(_))
+        ));
+    }
+
+    #[test]
+    fn test_raw_json() {
+        let m = r#"23["test", "hello" ,{"key": "value"}]"#;
+        let packet = deserialize(EngineMessage::Text(m.to_string().into()))
+            .unwrap()
+            .packet()
+            .unwrap();
+        let args = match packet.data() {
+            Data::Event { args, .. } => args,
+            _ => unreachable!(),
+        };
+        assert_eq!(args.get(1).unwrap().raw_json(), r#""hello""#);
+        assert_eq!(args.get(2).unwrap().raw_json(), r#"{"key": "value"}"#);
+
+        // Placeholders aren't filled in
+        let m = r#"51-["binary",{"array":{"_placeholder":true,"num":0}}]"#;
+        let partial = match deserialize(EngineMessage::Text(m.to_string().into())).unwrap() {
+            DeserializeResult::DataNeeded(partial) => partial,
+            _ => unreachable!(),
+        };
+        let attachments = vec![EngineMessage::Binary(vec![1u8, 2].into())];
+        let packet = deserialize_partial(partial, attachments).unwrap();
+        let args = match packet.data() {
+            Data::Event { args, .. } => args,
+            _ => unreachable!(),
+        };
+        let arg = args.get(1).unwrap();
+        assert_eq!(arg.raw_json(), r#"{"array":{"_placeholder":true,"num":0}}"#);
+        assert_eq!(
+            arg.to_json_value().unwrap().to_string(),
+            r#"{"array":[1,2]}"#
+        );
+    }
+
     #[derive(Debug, PartialEq, Deserialize)]
     struct StructBorrowed<'a> {
         key: &'a str,
@@ -241,8 +550,147 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_deserialize_large_prefix() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Prefix<'a> {
+            id: u64,
+            name: &'a str,
+        }
+
+        let data = (0..100_000).map(|i| i.to_string()).collect::<Vec<_>>();
+        let m = format!(
+            r#"2["test",{{"id":7,"name":"big","data":[{}],"nested":{{"data":[{}]}}}}]"#,
+            data.join(","),
+            data.join(",")
+        );
+        let packet = deserialize(EngineMessage::Text(m.into()))
+            .unwrap()
+            .packet()
+            .unwrap();
+        let args = match packet.data() {
+            Data::Event { args, .. } => args,
+            _ => unreachable!(),
+        };
+
+        let arg = args.get(1).unwrap();
+        let prefix = arg.deserialize::<Prefix>().unwrap();
+        assert_eq!(prefix, Prefix { id: 7, name: "big" });
+        // The name is borrowed from the packet rather than copied out of a parsed tree
+        let range = arg.arg.as_bytes().as_ptr_range();
+        assert!(range.contains(&prefix.name.as_ptr()));
+    }
+
+    #[test]
+    fn test_deserialize_all() {
+        let m = "33[\"test\",5,{\"key\":\"value\"}]";
+        let packet = deserialize(EngineMessage::Text(m.to_string().into()))
+            .unwrap()
+            .packet()
+            .unwrap();
+        let args = match packet.data() {
+            Data::Ack { args, .. } => args,
+            _ => unreachable!(),
+        };
+
+        let (name, num, obj): (&str, u8, StructOwned) = args.deserialize_all().unwrap();
+        assert_eq!(name, "test");
+        assert_eq!(num, 5);
+        assert_eq!(
+            obj,
+            StructOwned {
+                key: "value".to_string()
+            }
+        );
+        assert!(args.deserialize_all::<(String, u8)>().is_err());
+        assert!(args
+            .deserialize_all::<(String, u8, StructOwned, u8)>()
+            .is_err());
+        assert!(args.deserialize_all::<()>().is_err());
+
+        let (num, obj): (u8, StructOwned) = args.rest().unwrap();
+        assert_eq!(num, 5);
+        assert_eq!(obj.key, "value");
+        assert!(args.rest::<(u8,)>().is_err());
+    }
+
+    #[test]
+    fn test_deserialize_all_empty() {
+        let m = "33[]";
+        let packet = deserialize(EngineMessage::Text(m.to_string().into()))
+            .unwrap()
+            .packet()
+            .unwrap();
+        let args = match packet.data() {
+            Data::Ack { args, .. } => args,
+            _ => unreachable!(),
+        };
+
+        args.deserialize_all::<()>().unwrap();
+        args.rest::<()>().unwrap();
+        assert_eq!(args.deserialize_all::<Vec<u8>>().unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_iter() {
+        let m = r#"2["event",1,2,3]"#;
+        let packet = deserialize(EngineMessage::Text(m.to_string().into()))
+            .unwrap()
+            .packet()
+            .unwrap();
+        let args = match packet.data() {
+            Data::Event { args, .. } => args,
+            _ => unreachable!(),
+        };
+
+        fn raw<'a>(iter: impl Iterator<Item = Arg<'a>>) -> Vec<&'a str> {
+            iter.map(|arg| arg.raw_json()).collect()
+        }
+        assert_eq!(args.iter().len(), 4);
+        assert_eq!(args.iter().skip(1).len(), 3);
+        assert_eq!(raw(args.iter().rev()), vec!["3", "2", "1", r#""event""#]);
+
+        // Both ends can be taken from without overlapping
+        let mut iter = args.iter();
+        assert_eq!(iter.next().unwrap().raw_json(), r#""event""#);
+        assert_eq!(iter.next_back().unwrap().raw_json(), "3");
+        assert_eq!(iter.len(), 2);
+        assert_eq!(raw(iter.by_ref()), vec!["1", "2"]);
+        assert!(iter.next_back().is_none());
+    }
+
+    #[test]
+    fn test_eq() {
+        fn packet(m: &str) -> Packet {
+            deserialize(EngineMessage::Text(m.to_string().into()))
+                .unwrap()
+                .packet()
+                .unwrap()
+        }
+        fn args(packet: &Packet) -> Args<'_> {
+            match packet.data() {
+                Data::Event { args, .. } => args,
+                _ => unreachable!(),
+            }
+        }
+        let a = packet(r#"2["event",{"a":1}]"#);
+        let b = packet(r#"2/nsp,5["event",{"a":1}]"#);
+        let c = packet(r#"2["event", {"a":1}]"#);
+        let d = packet(r#"2["event",{"a": 1}]"#);
+
+        // Only the arguments are compared, not the rest of the packet
+        assert_eq!(args(&a), args(&b));
+        assert_ne!(a, b);
+        // Whitespace between arguments isn't part of them
+        assert_eq!(args(&a), args(&c));
+        // but whitespace within them is
+        assert_ne!(args(&a), args(&d));
+        assert_eq!(args(&a).get(0), args(&d).get(0));
+        assert_ne!(args(&a).get(1), args(&d).get(1));
+        assert_eq!(a, a.clone());
+    }
+
     #[derive(Deserialize)]
-    #[allow(dead_code)]
     struct BinaryBorrowed<'a> {
         array: &'a [u8],
     }
@@ -264,6 +712,10 @@ mod tests {
             _ => unreachable!(),
         };
 
+        let (name, binary): (String, BinaryBorrowed) = args.deserialize_all().unwrap();
+        assert_eq!(name, "binary");
+        assert_eq!(binary.array, &[222, 173, 190, 239][..]);
+
         assert_eq!(
             args.get(1).unwrap().to_json_value().unwrap(),
             Value::Object(
@@ -277,4 +729,51 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_max_depth() {
+        let nested = |depth| format!("{}0{}", "[".repeat(depth), "]".repeat(depth));
+
+        // Packet parsing doesn't limit nesting, converting to a value has to fail cleanly
+        let m = format!(r#"2["event",{}]"#, nested(100_000));
+        let packet = deserialize(EngineMessage::Text(m.into()))
+            .unwrap()
+            .packet()
+            .unwrap();
+        let arg = match packet.data() {
+            Data::Event { args, .. } => args.get(1).unwrap(),
+            _ => unreachable!(),
+        };
+        assert!(arg.to_json_value().is_err());
+        assert!(arg.deserialize::<Value>().is_err());
+        assert_eq!(arg.to_string().len(), 200_001);
+
+        let m = format!(
+            r#"51-["event",{{"a":{}}},{{"_placeholder":true,"num":0}}]"#,
+            nested(20)
+        );
+        let partial = match deserialize(EngineMessage::Text(m.into())).unwrap() {
+            DeserializeResult::DataNeeded(partial) => partial,
+            _ => unreachable!(),
+        };
+        let attachments = vec![EngineMessage::Binary(vec![1u8].into())];
+        let packet = deserialize_partial(partial, attachments).unwrap();
+        let args = match packet.data() {
+            Data::Event { args, .. } => args,
+            _ => unreachable!(),
+        };
+        assert!(args.get(1).unwrap().deserialize::<Value>().is_ok());
+        assert!(args.get(1).unwrap().to_json_value().is_ok());
+
+        let args = args.with_max_depth(20);
+        assert!(args.get(1).unwrap().deserialize::<Value>().is_err());
+        assert!(matches!(
+            args.get(1).unwrap().to_json_value(),
+            Err(Error::MaxDepthExceeded)
+        ));
+        assert_eq!(
+            args.get(2).unwrap().deserialize::<Vec<u8>>().unwrap(),
+            vec![1]
+        );
+    }
 }
@@ -0,0 +1,217 @@
+use std::{fmt, ops::Range};
+
+use owned_subslice::OwnedSubslice;
+use serde::Serialize;
+
+use super::{serialize_arg, Args, Error, DEFAULT_MAX_DEPTH};
+
+/// An owned copy of a packet's arguments, which can be kept beyond the packet's lifetime and
+/// modified.  Binary attachments are shared with the original packet rather than copied.
+///
+/// Arguments are replaced or added by serializing a value, which is always serialized as plain
+/// JSON, so new values can't contain binary attachments, while existing arguments keep
+/// theirs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedArgs {
+    message: String,
+    args: Vec<Range<usize>>,
+    attachments: Vec<OwnedSubslice<Vec<u8>>>,
+}
+
+impl OwnedArgs {
+    pub fn new() -> Self {
+        OwnedArgs {
+            message: "[]".to_string(),
+            args: Vec::new(),
+            attachments: Vec::new(),
+        }
+    }
+
+    /// Borrow the arguments, to read or deserialize them.
+    pub fn args(&self) -> Args<'_> {
+        Args {
+            message: &self.message,
+            args: &self.args,
+            attachments: &self.attachments,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    /// Replace the argument at `idx`.  Panics if `idx` is out of bounds.
+    pub fn set<T>(&mut self, idx: usize, value: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        assert!(idx < self.len(), "index {} out of bounds", idx);
+        let value = to_json(value)?;
+        let mut args = self.arg_strs();
+        args[idx] = &value;
+        *self = self.rebuild(&args);
+        Ok(())
+    }
+
+    /// Insert an argument at `idx`, shifting later arguments back.  Panics if `idx > len`.
+    pub fn insert<T>(&mut self, idx: usize, value: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        assert!(idx <= self.len(), "index {} out of bounds", idx);
+        let value = to_json(value)?;
+        let mut args = self.arg_strs();
+        args.insert(idx, &value);
+        *self = self.rebuild(&args);
+        Ok(())
+    }
+
+    pub fn push<T>(&mut self, value: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.insert(self.len(), value)
+    }
+
+    /// Remove the argument at `idx`.  Panics if `idx` is out of bounds.
+    pub fn remove(&mut self, idx: usize) {
+        assert!(idx < self.len(), "index {} out of bounds", idx);
+        let mut args = self.arg_strs();
+        args.remove(idx);
+        *self = self.rebuild(&args);
+    }
+
+    fn arg_strs(&self) -> Vec<&str> {
+        self.args.iter().map(|r| &self.message[r.clone()]).collect()
+    }
+
+    /// Build new args from the JSON of each argument, sharing this one's attachments.
+    fn rebuild(&self, args: &[&str]) -> OwnedArgs {
+        let mut message = String::with_capacity(self.message.len());
+        let mut ranges = Vec::with_capacity(args.len());
+        message.push('[');
+        for (i, arg) in args.iter().enumerate() {
+            if i != 0 {
+                message.push(',');
+            }
+            ranges.push(message.len()..message.len() + arg.len());
+            message.push_str(arg);
+        }
+        message.push(']');
+        OwnedArgs {
+            message,
+            args: ranges,
+            attachments: self.attachments.clone(),
+        }
+    }
+}
+
+impl Default for OwnedArgs {
+    fn default() -> Self {
+        OwnedArgs::new()
+    }
+}
+
+impl<'a> Args<'a> {
+    /// Copy the arguments into an `OwnedArgs`.
+    pub fn to_owned_args(&self) -> OwnedArgs {
+        OwnedArgs {
+            message: self.message.to_string(),
+            args: self.args.to_vec(),
+            attachments: self.attachments.to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for OwnedArgs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.args().fmt(f)
+    }
+}
+
+fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<String, Error> {
+    let mut buf = Vec::new();
+    serialize_arg(&mut buf, value)?;
+    // serde_json only writes valid utf-8
+    Ok(String::from_utf8(buf).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::Message as EngineMessage,
+        socket::{deserialize, deserialize_partial, Data},
+    };
+
+    #[test]
+    fn test_modify() {
+        let m = r#"2["event",1,{"key":"value"}]"#;
+        let packet = deserialize(EngineMessage::Text(m.to_string().into()))
+            .unwrap()
+            .packet()
+            .unwrap();
+        let mut owned = match packet.data() {
+            Data::Event { args, .. } => args.to_owned_args(),
+            _ => unreachable!(),
+        };
+        drop(packet);
+        assert_eq!(owned.len(), 3);
+        assert_eq!(
+            owned.args().get(1).unwrap().deserialize::<u32>().unwrap(),
+            1
+        );
+
+        owned.set(1, &2).unwrap();
+        owned.push("last").unwrap();
+        owned.insert(0, &vec!["first"]).unwrap();
+        owned.remove(3);
+        assert_eq!(
+            owned
+                .args()
+                .deserialize_all::<(Vec<String>, String, u32, String)>()
+                .unwrap(),
+            (
+                vec!["first".to_string()],
+                "event".to_string(),
+                2,
+                "last".to_string()
+            )
+        );
+        assert_eq!(owned.to_string(), r#"[["first"], "event", 2, "last"]"#);
+
+        let mut empty = OwnedArgs::new();
+        assert!(empty.is_empty());
+        empty.push(&()).unwrap();
+        assert_eq!(empty.to_string(), "[null]");
+    }
+
+    #[test]
+    fn test_attachments() {
+        let m = r#"51-["event",{"_placeholder":true,"num":0}]"#;
+        let partial = match deserialize(EngineMessage::Text(m.to_string().into())).unwrap() {
+            crate::socket::DeserializeResult::DataNeeded(partial) => partial,
+            _ => unreachable!(),
+        };
+        let attachment = EngineMessage::Binary(vec![1u8, 2].into());
+        let packet = deserialize_partial(partial, vec![attachment]).unwrap();
+        let mut owned = match packet.data() {
+            Data::Event { args, .. } => args.to_owned_args(),
+            _ => unreachable!(),
+        };
+
+        owned.insert(1, "inserted").unwrap();
+        assert_eq!(
+            owned
+                .args()
+                .deserialize_all::<(String, String, Vec<u8>)>()
+                .unwrap(),
+            ("event".to_string(), "inserted".to_string(), vec![1, 2])
+        );
+    }
+}
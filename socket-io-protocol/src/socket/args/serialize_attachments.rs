@@ -13,15 +13,35 @@ use tungstenite::Message as WsMessage;
 
 use crate::engine;
 
+/// The newtype struct name `NoBinary` serializes with, which tells `Wrapper` to serialize the
+/// contents without extracting binary data.
+pub(super) const NO_BINARY: &str = "$socket_io_protocol::NoBinary";
+
+/// Determines which values are sent as binary attachments in a binary packet.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum BinaryMode {
+    /// Byte strings (values serialized with `serialize_bytes`, e.g. via `serde_bytes`) and
+    /// sequences consisting entirely of `u8`s, e.g. `Vec<u8>`, are sent as attachments.
+    #[default]
+    Implicit,
+    /// Only byte strings are sent as attachments, other sequences of `u8`s are sent as JSON
+    /// arrays.
+    Explicit,
+}
+
 pub fn serialize<T: ?Sized + Serialize, S: Serializer>(
     arg: &T,
     serializer: S,
     buffers: &mut Vec<WsMessage>,
+    mode: BinaryMode,
 ) -> Result<S::Ok, S::Error> {
-    let refcell = RefCell::new(buffers);
+    let buffers = Buffers {
+        data: RefCell::new(buffers),
+        mode,
+    };
     let wrapped = Wrapper {
         s: serializer,
-        buffers: &refcell,
+        buffers: &buffers,
     };
     arg.serialize(wrapped)
 }
@@ -30,18 +50,24 @@ pub fn serialize_json<T: ?Sized + Serialize, W: Write>(
     arg: &T,
     write: W,
     buffers: &mut Vec<WsMessage>,
+    mode: BinaryMode,
 ) -> Result<(), JsonError> {
     let mut serializer = JsonSerializer::new(write);
-    serialize(arg, &mut serializer, buffers)
+    serialize(arg, &mut serializer, buffers, mode)
+}
+
+struct Buffers<'a> {
+    data: RefCell<&'a mut Vec<WsMessage>>,
+    mode: BinaryMode,
 }
 
 struct Wrapper<'a, S> {
     s: S,
-    buffers: &'a RefCell<&'a mut Vec<WsMessage>>,
+    buffers: &'a Buffers<'a>,
 }
 
 struct SeqWrapper<'a, S: Serializer> {
-    buffers: &'a RefCell<&'a mut Vec<WsMessage>>,
+    buffers: &'a Buffers<'a>,
     state: BytesState<S>,
 }
 
@@ -200,7 +226,6 @@ where
 
     serialize_forward_wrapped! {
         some(),
-        newtype_struct(name: &'static str),
         newtype_variant(
             name: &'static str,
             variant_index: u32,
@@ -208,8 +233,26 @@ where
         ),
     }
 
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        if name == NO_BINARY {
+            value.serialize(self.s)
+        } else {
+            self.s.serialize_newtype_struct(
+                name,
+                &Wrapper {
+                    s: value,
+                    buffers: self.buffers,
+                },
+            )
+        }
+    }
+
     fn serialize_bytes(self, bytes: &[u8]) -> Result<Self::Ok, Self::Error> {
-        let mut buffers = self.buffers.borrow_mut();
+        let mut buffers = self.buffers.data.borrow_mut();
         let idx = buffers.len();
         buffers.push(engine::encode_binary(bytes));
         Placeholder::new(idx).serialize(self.s)
@@ -218,13 +261,19 @@ where
     type SerializeSeq = SeqWrapper<'a, S>;
 
     fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
-        Ok(SeqWrapper {
-            buffers: self.buffers,
-            state: BytesState::Bytes {
+        let state = match self.buffers.mode {
+            BinaryMode::Implicit => BytesState::Bytes {
                 s: Some(self.s),
                 data: Vec::new(),
                 len,
             },
+            BinaryMode::Explicit => BytesState::Poisoned {
+                s: self.s.serialize_seq(len)?,
+            },
+        };
+        Ok(SeqWrapper {
+            buffers: self.buffers,
+            state,
         })
     }
 
@@ -297,8 +346,9 @@ where
     type Ok = S::Ok;
     type Error = S::Error;
 
+    // Keys aren't transformed, as an attachment placeholder can't be a key
     fn serialize_key<T: ?Sized + Serialize>(&mut self, v: &T) -> Result<(), Self::Error> {
-        self.s.serialize_key(&self.transform(v))
+        self.s.serialize_key(v)
     }
 
     fn serialize_value<T: ?Sized + Serialize>(&mut self, v: &T) -> Result<(), Self::Error> {
@@ -310,8 +360,7 @@ where
         k: &K,
         v: &V,
     ) -> Result<(), Self::Error> {
-        self.s
-            .serialize_entry(&self.transform(k), &self.transform(v))
+        self.s.serialize_entry(k, &self.transform(v))
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
@@ -369,6 +418,32 @@ where
 
 // NOTE: This would be much simpler if serialize_element could be specialized for u8
 
+impl<'a, S> SeqWrapper<'a, S>
+where
+    S: Serializer,
+{
+    /// Switch to serializing a sequence once `v` is found not to be a `u8`, serializing the
+    /// bytes buffered so far as its first elements.  Kept out of `serialize_element` to keep
+    /// the per-byte path small.
+    #[cold]
+    fn poison<T: ?Sized + Serialize>(&mut self, v: &T) -> Result<(), S::Error> {
+        let (s, data, len) = match &mut self.state {
+            BytesState::Bytes { s, data, len } => (s, data, len),
+            BytesState::Poisoned { .. } => unreachable!(),
+        };
+        let mut seq = s.take().unwrap().serialize_seq(*len)?;
+        for b in data.iter() {
+            seq.serialize_element(b)?;
+        }
+        seq.serialize_element(&Wrapper {
+            s: v,
+            buffers: self.buffers,
+        })?;
+        self.state = BytesState::Poisoned { s: seq };
+        Ok(())
+    }
+}
+
 impl<'a, S> SerializeSeq for SeqWrapper<'a, S>
 where
     S: Serializer,
@@ -378,27 +453,18 @@ where
 
     fn serialize_element<T: ?Sized + Serialize>(&mut self, v: &T) -> Result<(), Self::Error> {
         match &mut self.state {
-            BytesState::Bytes { s, data, len } => match v.serialize(SeqElementSerializer {}) {
+            BytesState::Bytes { data, len, .. } => match v.serialize(SeqElementSerializer {}) {
                 Ok(b) => {
                     if data.is_empty() {
+                        // Sized sequences, e.g. `Vec<u8>` and `&[u8]`, are copied without
+                        // growing the buffer
+                        data.reserve_exact(len.map_or(0, |len| len + 1));
                         data.push(engine::BINARY_HEADER);
                     }
                     data.push(b);
                     Ok(())
                 }
-                Err(_) => {
-                    // The data isn't all u8's
-                    let mut seq = s.take().unwrap().serialize_seq(*len)?;
-                    for b in data.iter() {
-                        seq.serialize_element(b)?;
-                    }
-                    seq.serialize_element(&Wrapper {
-                        s: v,
-                        buffers: self.buffers,
-                    })?;
-                    self.state = BytesState::Poisoned { s: seq };
-                    Ok(())
-                }
+                Err(_) => self.poison(v),
             },
             BytesState::Poisoned { s } => s.serialize_element(&Wrapper {
                 s: v,
@@ -414,7 +480,7 @@ where
                     let seq = s.unwrap().serialize_seq(Some(0))?;
                     seq.end()
                 } else {
-                    let mut buffers = self.buffers.borrow_mut();
+                    let mut buffers = self.buffers.data.borrow_mut();
                     let idx = buffers.len();
                     buffers.push(engine::package_binary(data));
                     Placeholder::new(idx).serialize(s.unwrap())
@@ -553,12 +619,21 @@ impl ser::Error for SeqElementSerializerError {
 mod tests {
     use super::*;
 
-    use std::io::Cursor;
+    use std::{collections::BTreeMap, io::Cursor};
 
-    const DEADBEEF: &'static [u8] = &[0xde, 0xad, 0xbe, 0xef];
+    use crate::socket::{no_binary, NoBinary};
+
+    const DEADBEEF: &[u8] = &[0xde, 0xad, 0xbe, 0xef];
 
     fn serialize_json_string<T: ?Sized + Serialize>(
         arg: &T,
+    ) -> Result<(String, Vec<WsMessage>), JsonError> {
+        serialize_json_string_mode(arg, BinaryMode::Implicit)
+    }
+
+    fn serialize_json_string_mode<T: ?Sized + Serialize>(
+        arg: &T,
+        mode: BinaryMode,
     ) -> Result<(String, Vec<WsMessage>), JsonError> {
         let mut buffers = Vec::new();
         let mut string = String::new();
@@ -566,6 +641,7 @@ mod tests {
             arg,
             Cursor::new(unsafe { string.as_mut_vec() }),
             &mut buffers,
+            mode,
         )?;
         Ok((string, buffers))
     }
@@ -603,6 +679,92 @@ mod tests {
         );
     }
 
+    fn bytes<S: Serializer>(v: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_bytes(v)
+    }
+
+    #[derive(Serialize)]
+    struct Modes {
+        vec: Vec<u8>,
+        #[serde(serialize_with = "bytes")]
+        bytes: Vec<u8>,
+        nested: Vec<Vec<u8>>,
+    }
+
+    #[test]
+    fn test_binary_modes() {
+        let m = Modes {
+            vec: vec![1, 2],
+            bytes: DEADBEEF.to_vec(),
+            nested: vec![vec![3, 4]],
+        };
+
+        assert_eq!(
+            serialize_json_string_mode(&m, BinaryMode::Implicit).unwrap(),
+            (
+                r#"{"vec":{"_placeholder":true,"num":0},"bytes":{"_placeholder":true,"num":1},"nested":[{"_placeholder":true,"num":2}]}"#.to_string(),
+                vec![
+                    engine::encode_binary(&[1, 2]),
+                    engine::encode_binary(DEADBEEF),
+                    engine::encode_binary(&[3, 4]),
+                ],
+            )
+        );
+        assert_eq!(
+            serialize_json_string_mode(&m, BinaryMode::Explicit).unwrap(),
+            (
+                r#"{"vec":[1,2],"bytes":{"_placeholder":true,"num":0},"nested":[[3,4]]}"#
+                    .to_string(),
+                vec![engine::encode_binary(DEADBEEF)],
+            )
+        );
+    }
+
+    #[test]
+    fn test_integer_keys() {
+        let mut map = BTreeMap::new();
+        map.insert(-1i64, vec![1u8]);
+        map.insert(7, vec![2u8]);
+        assert_eq!(
+            serialize_json_string(&map).unwrap(),
+            (
+                r#"{"-1":{"_placeholder":true,"num":0},"7":{"_placeholder":true,"num":1}}"#
+                    .to_string(),
+                vec![engine::encode_binary(&[1]), engine::encode_binary(&[2])],
+            )
+        );
+    }
+
+    #[derive(Serialize)]
+    struct Coordinates {
+        coords: NoBinary<Vec<u8>>,
+        #[serde(serialize_with = "no_binary")]
+        more_coords: [u8; 2],
+        data: Vec<u8>,
+    }
+
+    #[test]
+    fn test_no_binary() {
+        let c = Coordinates {
+            coords: vec![1, 2, 3].into(),
+            more_coords: [4, 5],
+            data: DEADBEEF.to_vec(),
+        };
+
+        assert_eq!(
+            serialize_json_string(&c).unwrap(),
+            (
+                r#"{"coords":[1,2,3],"more_coords":[4,5],"data":{"_placeholder":true,"num":0}}"#
+                    .to_string(),
+                vec![engine::encode_binary(DEADBEEF)]
+            )
+        );
+        assert_eq!(
+            serde_json::to_string(&c).unwrap(),
+            r#"{"coords":[1,2,3],"more_coords":[4,5],"data":[222,173,190,239]}"#
+        );
+    }
+
     #[derive(Serialize)]
     struct Nested {
         v: Vec<Inner>,
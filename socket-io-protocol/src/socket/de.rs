@@ -4,7 +4,7 @@ use owned_subslice::OwnedSubslice;
 use regex::Regex;
 use serde_json::value::RawValue;
 
-use super::{EngineMessage, Error, Kind, Packet, ProtocolKind};
+use super::{ConnectData, EngineMessage, Error, Kind, Packet, ProtocolKind};
 
 #[derive(Debug)]
 #[cfg_attr(test, derive(PartialEq))]
@@ -26,6 +26,8 @@ struct Parse {
     namespace: Option<Range<usize>>,
     id: Option<u64>,
     args: Vec<Range<usize>>,
+    // A non-array payload, as sent with connect and connect error packets
+    data: Option<Range<usize>>,
 }
 
 impl Partial {
@@ -45,21 +47,33 @@ impl DeserializeResult {
 
 lazy_static::lazy_static! {
     static ref DESERIALIZE_RE: Regex = {
-        let pattern = r#"^([012356])((0|[1-9][0-9]*)-)?((/.+),)?(0|[1-9][0-9]*)?(\[.*\])?$"#;
+        let pattern = r#"^([0-6])((0|[1-9][0-9]*)-)?((/.+),)?(0|[1-9][0-9]*)?(\[.*\]|\{.*\}|".*")?$"#;
         Regex::new(pattern).unwrap()
     };
 }
 
+/// The default limit on how many attachments a binary packet can have.
+pub const DEFAULT_MAX_ATTACHMENTS: u64 = 64;
+
 pub fn deserialize(msg: EngineMessage) -> Result<DeserializeResult, Error> {
+    deserialize_with_max_attachments(msg, DEFAULT_MAX_ATTACHMENTS)
+}
+
+/// Like `deserialize`, but binary packets with more than `max_attachments` attachments fail with
+/// `Error::TooManyAttachments`, rather than waiting for attachments that may never be sent.
+pub fn deserialize_with_max_attachments(
+    msg: EngineMessage,
+    max_attachments: u64,
+) -> Result<DeserializeResult, Error> {
     match msg {
-        EngineMessage::Text(text) => deserialize_text(text),
+        EngineMessage::Text(text) => deserialize_text(text, max_attachments),
         EngineMessage::Binary(data) => Err(Error::NonAttachmentBinary(data.to_vec())),
     }
 }
 
 fn parse_text(text: OwnedSubslice<String>) -> Result<Parse, Error> {
     let captures = DESERIALIZE_RE
-        .captures(&*text)
+        .captures(&text)
         .ok_or_else(|| Error::InvalidMessage(text.to_string()))?;
     let kind = {
         use ProtocolKind::*;
@@ -75,15 +89,27 @@ fn parse_text(text: OwnedSubslice<String>) -> Result<Parse, Error> {
             '1' => Disconnect,
             '2' => Event,
             '3' => Ack,
+            '4' => ConnectError,
             '5' => BinaryEvent,
             '6' => BinaryAck,
             _ => unreachable!(),
         }
     };
-    let attachments = captures.get(3).map(|x| x.as_str().parse::<u64>().unwrap());
+    // The regex only matches digits, so parsing only fails if the number doesn't fit
+    let number = |m: regex::Match<'_>| {
+        m.as_str()
+            .parse::<u64>()
+            .map_err(|_| Error::InvalidMessage(text.to_string()))
+    };
+    let attachments = captures.get(3).map(number).transpose()?;
     let namespace = captures.get(5).map(|x| x.range());
-    let id = captures.get(6).map(|x| x.as_str().parse::<u64>().unwrap());
-    let args = match captures.get(7) {
+    let id = captures.get(6).map(number).transpose()?;
+    let (args, data) = match captures.get(7) {
+        Some(m) if !m.as_str().starts_with('[') => {
+            let json_err = |e| Error::InvalidDataJson(m.as_str().to_string(), e);
+            let _: &RawValue = serde_json::from_str(m.as_str()).map_err(json_err)?;
+            (Vec::new(), Some(m.range()))
+        }
         Some(m) => {
             let mut args = parse_args(m.as_str())?;
             let offset = m.start();
@@ -91,9 +117,9 @@ fn parse_text(text: OwnedSubslice<String>) -> Result<Parse, Error> {
                 *start += offset;
                 *end += offset
             });
-            args
+            (args, None)
         }
-        None => Vec::new(),
+        None => (Vec::new(), None),
     };
 
     Ok(Parse {
@@ -103,6 +129,7 @@ fn parse_text(text: OwnedSubslice<String>) -> Result<Parse, Error> {
         namespace,
         id,
         args,
+        data,
     })
 }
 
@@ -120,12 +147,16 @@ fn parse_args<'a>(arg_str: &'a str) -> Result<Vec<Range<usize>>, Error> {
         .collect())
 }
 
-fn deserialize_text(text: OwnedSubslice<String>) -> Result<DeserializeResult, Error> {
+fn deserialize_text(
+    text: OwnedSubslice<String>,
+    max_attachments: u64,
+) -> Result<DeserializeResult, Error> {
     let parse = parse_text(text)?;
 
     match parse.kind {
-        ProtocolKind::Connect => {
-            deserialize_dataless(parse, Kind::Connect, "connect").map(DeserializeResult::Packet)
+        ProtocolKind::Connect => deserialize_connect(parse).map(DeserializeResult::Packet),
+        ProtocolKind::ConnectError => {
+            deserialize_connect_error(parse).map(DeserializeResult::Packet)
         }
         ProtocolKind::Disconnect => deserialize_dataless(parse, Kind::Disconnect, "disconnect")
             .map(DeserializeResult::Packet),
@@ -134,13 +165,21 @@ fn deserialize_text(text: OwnedSubslice<String>) -> Result<DeserializeResult, Er
         ProtocolKind::Ack => {
             deserialize_event(parse, Kind::Ack, "ack", Vec::new()).map(DeserializeResult::Packet)
         }
-        ProtocolKind::BinaryEvent => deserialize_binary(parse, Kind::Event, "binary event"),
-        ProtocolKind::BinaryAck => deserialize_binary(parse, Kind::Ack, "binary ack"),
+        ProtocolKind::BinaryEvent => {
+            deserialize_binary(parse, Kind::Event, "binary event", max_attachments)
+        }
+        ProtocolKind::BinaryAck => {
+            deserialize_binary(parse, Kind::Ack, "binary ack", max_attachments)
+        }
     }
 }
 
 fn deserialize_dataless(parse: Parse, kind: Kind, name: &'static str) -> Result<Packet, Error> {
-    if parse.attachments.is_some() || parse.id.is_some() || !parse.args.is_empty() {
+    if parse.attachments.is_some()
+        || parse.id.is_some()
+        || !parse.args.is_empty()
+        || parse.data.is_some()
+    {
         return Err(Error::InvalidExtraData(name, parse.message.to_string()));
     }
     Ok(Packet {
@@ -153,13 +192,59 @@ fn deserialize_dataless(parse: Parse, kind: Kind, name: &'static str) -> Result<
     })
 }
 
+/// Connect acknowledgements from v5 servers carry an object with the namespace's session id, which
+/// is kept as the packet's only argument.  Older servers send no payload.
+fn deserialize_connect(parse: Parse) -> Result<Packet, Error> {
+    let invalid_data =
+        matches!(&parse.data, Some(data) if !parse.message[data.clone()].starts_with('{'));
+    if parse.attachments.is_some() || parse.id.is_some() || !parse.args.is_empty() || invalid_data {
+        return Err(Error::InvalidExtraData(
+            "connect",
+            parse.message.to_string(),
+        ));
+    }
+    if let Some(data) = parse.data.clone() {
+        let data = &parse.message[data];
+        let _: ConnectData =
+            serde_json::from_str(data).map_err(|e| Error::InvalidDataJson(data.to_string(), e))?;
+    }
+    Ok(Packet {
+        message: parse.message,
+        kind: Kind::Connect,
+        namespace: parse.namespace,
+        id: None,
+        args: parse.data.into_iter().collect(),
+        attachments: Vec::new(),
+    })
+}
+
+fn deserialize_connect_error(parse: Parse) -> Result<Packet, Error> {
+    if parse.attachments.is_some() || parse.id.is_some() || !parse.args.is_empty() {
+        return Err(Error::InvalidExtraData(
+            "connect error",
+            parse.message.to_string(),
+        ));
+    }
+    Ok(Packet {
+        message: parse.message,
+        kind: Kind::ConnectError,
+        namespace: parse.namespace,
+        id: None,
+        args: parse.data.into_iter().collect(),
+        attachments: Vec::new(),
+    })
+}
+
 fn deserialize_binary(
     parse: Parse,
     kind: Kind,
     name: &'static str,
+    max_attachments: u64,
 ) -> Result<DeserializeResult, Error> {
     if let Some(attachments) = parse.attachments {
-        if attachments == 0 {
+        if attachments > max_attachments {
+            Err(Error::TooManyAttachments(attachments, max_attachments))
+        } else if attachments == 0 {
             deserialize_event(parse, kind, name, Vec::new()).map(DeserializeResult::Packet)
         } else {
             Ok(DeserializeResult::DataNeeded(Partial(parse)))
@@ -196,9 +281,13 @@ fn deserialize_event(
     name: &'static str,
     attachments: Vec<OwnedSubslice<Vec<u8>>>,
 ) -> Result<Packet, Error> {
-    if (kind == Kind::Ack && parse.id.is_none()) || parse.args.is_empty() {
+    // Events need at least the event name, but acks may legitimately have no arguments
+    if (kind == Kind::Ack && parse.id.is_none()) || (kind == Kind::Event && parse.args.is_empty()) {
         return Err(Error::MissingData(name, parse.message.to_string()));
     }
+    if parse.data.is_some() {
+        return Err(Error::InvalidExtraData(name, parse.message.to_string()));
+    }
     if attachments.len() as u64 != parse.attachments.unwrap_or(0) {
         return Err(Error::InvalidAttachmentCount(
             attachments.len() as u64,
@@ -272,6 +361,7 @@ mod tests {
                 namespace: Some(range(3, 7)),
                 id: Some(1),
                 args: vec![range(10, 46)],
+                data: None,
             }
         );
     }
@@ -292,6 +382,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_deserialize_connect_payload() {
+        let m = r#"0/nsp,{"sid":"abc"}"#;
+        assert_eq!(
+            deserialize(EngineMessage::Text(m.to_string().into())).unwrap(),
+            DeserializeResult::Packet(Packet {
+                message: m.to_string().into(),
+                kind: Kind::Connect,
+                namespace: Some(range(1, 5)),
+                id: None,
+                args: vec![range(6, 19)],
+                attachments: Vec::new(),
+            })
+        );
+        assert!(deserialize(EngineMessage::Text(r#"0{"sid":1}"#.to_string().into())).is_err());
+        assert!(deserialize(EngineMessage::Text(r#"0"abc""#.to_string().into())).is_err());
+        assert!(deserialize(EngineMessage::Text(r#"1{"a":1}"#.to_string().into())).is_err());
+        assert!(deserialize(EngineMessage::Text(r#"2{"a":1}"#.to_string().into())).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_connect_error() {
+        let m = r#"4/nsp,{"message":"Not authorized"}"#;
+        assert_eq!(
+            deserialize(EngineMessage::Text(m.to_string().into())).unwrap(),
+            DeserializeResult::Packet(Packet {
+                message: m.to_string().into(),
+                kind: Kind::ConnectError,
+                namespace: Some(range(1, 5)),
+                id: None,
+                args: vec![range(6, 34)],
+                attachments: Vec::new(),
+            })
+        );
+
+        let m = r#"4"Invalid namespace""#;
+        assert_eq!(
+            deserialize(EngineMessage::Text(m.to_string().into())).unwrap(),
+            DeserializeResult::Packet(Packet {
+                message: m.to_string().into(),
+                kind: Kind::ConnectError,
+                namespace: None,
+                id: None,
+                args: vec![range(1, 20)],
+                attachments: Vec::new(),
+            })
+        );
+
+        let m = "4";
+        let packet = deserialize(EngineMessage::Text(m.to_string().into()))
+            .unwrap()
+            .packet()
+            .unwrap();
+        assert!(matches!(
+            packet.data(),
+            crate::socket::Data::ConnectError { data: None, .. }
+        ));
+        assert!(deserialize(EngineMessage::Text(r#"4{"a":}"#.to_string().into())).is_err());
+    }
+
     #[test]
     fn test_deserialize_disconnect() {
         let m = "1/nsp,";
@@ -340,6 +490,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_deserialize_empty_ack() {
+        let m = "34[]";
+        assert_eq!(
+            deserialize(EngineMessage::Text(m.to_string().into())).unwrap(),
+            DeserializeResult::Packet(Packet {
+                message: m.to_string().into(),
+                kind: Kind::Ack,
+                namespace: None,
+                id: Some(4),
+                args: Vec::new(),
+                attachments: Vec::new(),
+            })
+        );
+        assert!(deserialize(EngineMessage::Text("24[]".to_string().into())).is_err());
+    }
+
     #[test]
     fn test_deserialize_binary_event() {
         let m = "51-[\"binary\",{\"_placeholder\":true,\"num\":0}]";
@@ -391,4 +558,33 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_deserialize_too_many_attachments() {
+        let text = |m: &str| EngineMessage::Text(m.to_string().into());
+        assert!(matches!(
+            deserialize(text(r#"55999999999999-["event"]"#)),
+            Err(Error::TooManyAttachments(
+                5_999_999_999_999,
+                DEFAULT_MAX_ATTACHMENTS
+            ))
+        ));
+        assert!(matches!(
+            deserialize_with_max_attachments(text(r#"52-["event"]"#), 1),
+            Err(Error::TooManyAttachments(2, 1))
+        ));
+        assert!(matches!(
+            deserialize_with_max_attachments(text(r#"51-["event"]"#), 1),
+            Ok(DeserializeResult::DataNeeded(_))
+        ));
+        // Numbers that don't fit are invalid rather than panicking
+        assert!(matches!(
+            deserialize(text(r#"599999999999999999999-["event"]"#)),
+            Err(Error::InvalidMessage(_))
+        ));
+        assert!(matches!(
+            deserialize(text(r#"399999999999999999999["event"]"#)),
+            Err(Error::InvalidMessage(_))
+        ));
+    }
 }
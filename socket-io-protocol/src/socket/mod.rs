@@ -2,6 +2,7 @@ use std::fmt;
 use std::ops::Range;
 
 use owned_subslice::OwnedSubslice;
+use serde::Deserialize;
 use serde_json::Error as JsonError;
 
 use super::engine::Message as EngineMessage;
@@ -10,12 +11,20 @@ mod args;
 mod de;
 mod ser;
 
-pub use args::{Arg, Args, Error as ArgsError};
-pub use de::{deserialize, deserialize_partial, DeserializeResult, Partial};
-pub use ser::{serialize_connect, serialize_disconnect, PacketBuilder};
+pub use args::{
+    no_binary, Arg, Args, BinaryMode, Error as ArgsError, NoBinary, OwnedArgs, DEFAULT_MAX_DEPTH,
+};
+pub use de::{
+    deserialize, deserialize_partial, deserialize_with_max_attachments, DeserializeResult, Partial,
+    DEFAULT_MAX_ATTACHMENTS,
+};
+pub use ser::{
+    serialize_connect, serialize_connect_with_auth, serialize_disconnect, PacketBuilder,
+    PacketPreview,
+};
 
-#[derive(Debug, Clone)]
-#[cfg_attr(test, derive(PartialEq))]
+/// Packets are equal if their text and attachments are the same.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Packet {
     message: OwnedSubslice<String>,
     kind: Kind,
@@ -31,14 +40,39 @@ enum Kind {
     Disconnect,
     Event,
     Ack,
+    ConnectError,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
 pub enum Data<'a> {
-    Connect,
+    /// The server accepted a connection to the namespace.  v5 servers include the namespace's
+    /// session id, which older servers don't send.
+    Connect {
+        sid: Option<String>,
+    },
     Disconnect,
-    Event { id: Option<u64>, args: Args<'a> },
-    Ack { id: u64, args: Args<'a> },
+    /// The server refused a connection to the namespace, with optional data describing why.
+    /// `message` is taken from the data: the `message` field of v5 servers' error objects, or the
+    /// string older servers send, or else the data's JSON.  It's empty if there's no data.
+    ConnectError {
+        message: String,
+        data: Option<Arg<'a>>,
+    },
+    Event {
+        id: Option<u64>,
+        args: Args<'a>,
+    },
+    Ack {
+        id: u64,
+        args: Args<'a>,
+    },
+}
+
+/// The payload of a connect packet sent by the server.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct ConnectData {
+    pub sid: Option<String>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -57,6 +91,8 @@ pub enum Error {
     InvalidDataJson(String, JsonError),
     #[error("Wrong number of attachments provided: {0} instead of {1}")]
     InvalidAttachmentCount(u64, u64),
+    #[error("Binary packet has {0} attachments, more than the maximum of {1}")]
+    TooManyAttachments(u64, u64),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -65,6 +101,7 @@ enum ProtocolKind {
     Disconnect,
     Event,
     Ack,
+    ConnectError,
     BinaryEvent,
     BinaryAck,
 }
@@ -77,9 +114,30 @@ impl Packet {
             .unwrap_or("/")
     }
 
+    /// Whether this is an event the sender expects an ack for.
+    pub fn expects_ack(&self) -> bool {
+        self.ack_id().is_some()
+    }
+
+    /// The id to ack this event with, if the sender expects an ack.  `None` for other packets,
+    /// including acks.
+    pub fn ack_id(&self) -> Option<u64> {
+        match self.kind {
+            Kind::Event => self.id,
+            _ => None,
+        }
+    }
+
+    /// The bytes of each binary attachment, in order.
+    pub fn attachments_iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.attachments.iter().map(|attachment| &attachment[..])
+    }
+
     pub fn data(&self) -> Data<'_> {
         match self.kind {
-            Kind::Connect => Data::Connect,
+            Kind::Connect => Data::Connect {
+                sid: self.connect_data().sid,
+            },
             Kind::Disconnect => Data::Disconnect,
             Kind::Event => Data::Event {
                 id: self.id,
@@ -89,18 +147,48 @@ impl Packet {
                 id: self.id.unwrap(),
                 args: self.args(),
             },
+            Kind::ConnectError => {
+                let data = self.args().get(0);
+                Data::ConnectError {
+                    message: data.as_ref().map(connect_error_message).unwrap_or_default(),
+                    data,
+                }
+            }
         }
     }
 
+    /// The payload was checked when deserializing, so a connect packet's only argument parses.
+    fn connect_data(&self) -> ConnectData {
+        self.args
+            .first()
+            .map(|range| serde_json::from_str(&self.message[range.clone()]).unwrap())
+            .unwrap_or_default()
+    }
+
     fn args(&self) -> Args<'_> {
         Args {
-            message: &*self.message,
+            message: &self.message,
             args: self.args.as_slice(),
             attachments: self.attachments.as_slice(),
+            max_depth: DEFAULT_MAX_DEPTH,
         }
     }
 }
 
+fn connect_error_message(data: &Arg) -> String {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Payload {
+        Object { message: String },
+        Message(String),
+    }
+
+    match data.deserialize() {
+        Ok(Payload::Object { message }) | Ok(Payload::Message(message)) => message,
+        Err(_) => data.raw_json().to_string(),
+    }
+}
+
 impl fmt::Display for Packet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.data())
@@ -111,10 +199,103 @@ impl<'a> fmt::Display for Data<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use Data::*;
         match self {
-            Connect => write!(f, "Connect"),
+            Connect { sid: Some(sid) } => write!(f, "Connect {{ sid: {} }}", sid),
+            Connect { sid: None } => write!(f, "Connect"),
             Disconnect => write!(f, "Disconnect"),
+            ConnectError {
+                data: Some(data), ..
+            } => write!(f, "ConnectError {{ data: {} }}", data),
+            ConnectError { data: None, .. } => write!(f, "ConnectError"),
             Event { id, args } => write!(f, "Event {{ id: {:?}, args: {} }}", id, args),
             Ack { id, args } => write!(f, "Ack {{ id: {:?}, args: {} }}", id, args),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(m: &str) -> Packet {
+        deserialize(EngineMessage::Text(m.to_string().into()))
+            .unwrap()
+            .packet()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_ack_id() {
+        let event = packet(r#"2/nsp,12["event"]"#);
+        assert!(event.expects_ack());
+        assert_eq!(event.ack_id(), Some(12));
+
+        let event = packet(r#"2["event"]"#);
+        assert!(!event.expects_ack());
+        assert_eq!(event.ack_id(), None);
+
+        let ack = packet(r#"312["response"]"#);
+        assert!(!ack.expects_ack());
+        assert_eq!(ack.ack_id(), None);
+
+        assert_eq!(packet("0/nsp,").ack_id(), None);
+    }
+
+    #[test]
+    fn test_connect_error_message() {
+        let message = |m| match packet(m).data() {
+            Data::ConnectError { message, .. } => message,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            message(r#"4/nsp,{"message":"Not authorized","data":{"code":1}}"#),
+            "Not authorized"
+        );
+        assert_eq!(message(r#"4"Invalid namespace""#), "Invalid namespace");
+        assert_eq!(message(r#"4{"code":1}"#), r#"{"code":1}"#);
+        assert_eq!(message("4/nsp,"), "");
+    }
+
+    #[test]
+    fn test_attachments_iter() {
+        let m = r#"52-["event",{"_placeholder":true,"num":0},{"_placeholder":true,"num":1}]"#;
+        let partial = match deserialize(EngineMessage::Text(m.to_string().into())).unwrap() {
+            DeserializeResult::DataNeeded(partial) => partial,
+            _ => unreachable!(),
+        };
+        let attachments = vec![
+            EngineMessage::Binary(vec![1u8, 2].into()),
+            EngineMessage::Binary(vec![3u8].into()),
+        ];
+        let binary = deserialize_partial(partial, attachments).unwrap();
+        let attachments: Vec<&[u8]> = binary.attachments_iter().collect();
+        assert_eq!(attachments, vec![&[1, 2][..], &[3][..]]);
+
+        assert_eq!(packet(r#"2["event"]"#).attachments_iter().count(), 0);
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn test_serialize_data() {
+        let to_json = |packet: &Packet| serde_json::to_string(&packet.data()).unwrap();
+        assert_eq!(
+            to_json(&packet(r#"2/nsp,3["event", {"a":1}]"#)),
+            r#"{"Event":{"id":3,"args":["event",{"a":1}]}}"#
+        );
+        assert_eq!(
+            to_json(&packet(r#"0{"sid":"abc"}"#)),
+            r#"{"Connect":{"sid":"abc"}}"#
+        );
+
+        let m = r#"51-["event",{"_placeholder":true,"num":0}]"#;
+        let partial = match deserialize(EngineMessage::Text(m.to_string().into())).unwrap() {
+            DeserializeResult::DataNeeded(partial) => partial,
+            _ => unreachable!(),
+        };
+        let binary =
+            deserialize_partial(partial, vec![EngineMessage::Binary(vec![1u8, 2].into())]).unwrap();
+        assert_eq!(
+            to_json(&binary),
+            r#"{"Event":{"id":null,"args":["event",[1,2]]}}"#
+        );
+    }
+}
@@ -1,29 +1,44 @@
 use std::{
     borrow::Cow,
     io::{Cursor, Write},
+    ops::Range,
 };
 
-use serde::Serialize;
+use serde::{ser::Error as _, Serialize};
 use tungstenite::Message as WsMessage;
 
 use crate::engine::{self, Message as EngineMessage, MESSAGE_HEADER as ENGINE_MESSAGE_HEADER};
 
-use super::{args, ArgsError, ProtocolKind};
+use super::{args, ArgsError, BinaryMode, ProtocolKind};
 
+/// Builds a socket.io packet as the websocket messages it's sent as, i.e. the text message with
+/// the engine.io and socket.io headers followed by any binary attachments.  The messages can be
+/// sent as is, and don't depend on the connection, so a packet can be built once and sent several
+/// times or on several connections.
 pub struct PacketBuilder {
     buffer: Vec<u8>,
     approach: Approach,
     first: bool,
+    // The number of arguments serialized, not counting an event's name, which is the index the
+    // next one is reported with if it fails
+    args: usize,
 }
 
 enum Approach {
-    Normal,
+    Normal {
+        // The position of the id in the header, which is written up front
+        id: Range<usize>,
+    },
     Binary {
         kind: ProtocolKind,
         namespace: Cow<'static, str>,
         id: Option<u64>,
         attachments: Vec<WsMessage>,
+        mode: BinaryMode,
     },
+    /// Connect and disconnect packets, which have no id, and at most a single payload instead of
+    /// an array of arguments.
+    Control { payload: bool },
 }
 
 enum Kind {
@@ -31,7 +46,18 @@ enum Kind {
     Ack,
 }
 
+/// The size of a packet as it would be sent if it was finished.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PacketPreview {
+    /// The total length of all of the packet's websocket messages, including headers.
+    pub byte_len: usize,
+    /// The number of binary attachments sent after the text message.
+    pub attachment_count: usize,
+}
+
 impl PacketBuilder {
+    /// An event packet, with `event` as its first argument.  With an id it requests an ack from
+    /// the receiver.  A binary packet sends binary data in its arguments as attachments.
     pub fn new_event<'a>(
         event: &str,
         namespace: impl Into<Cow<'a, str>>,
@@ -42,13 +68,37 @@ impl PacketBuilder {
         builder
             .serialize_arg(event)
             .expect("Serialization of &str failed");
+        builder.args = 0;
         builder
     }
 
+    /// An ack for the event with `id`.
     pub fn new_ack<'a>(namespace: impl Into<Cow<'a, str>>, id: u64, binary: bool) -> Self {
         PacketBuilder::new(namespace, Some(id), binary, Kind::Ack)
     }
 
+    /// A request to connect to `namespace`.  A single argument can be added as the packet's
+    /// payload, e.g. auth the server checks before accepting the connection (protocol v5), like
+    /// `serialize_connect_with_auth`.
+    pub fn new_connect(namespace: &str) -> Self {
+        PacketBuilder::new_control(ProtocolKind::Connect, namespace, true)
+    }
+
+    /// A notice that the client is leaving `namespace`, like `serialize_disconnect`.  Has no
+    /// arguments.
+    pub fn new_disconnect(namespace: &str) -> Self {
+        PacketBuilder::new_control(ProtocolKind::Disconnect, namespace, false)
+    }
+
+    fn new_control(kind: ProtocolKind, namespace: &str, payload: bool) -> Self {
+        PacketBuilder {
+            buffer: serialize_header(kind, None, namespace, None).into_bytes(),
+            approach: Approach::Control { payload },
+            first: true,
+            args: 0,
+        }
+    }
+
     fn new<'a>(
         namespace: impl Into<Cow<'a, str>>,
         id: Option<u64>,
@@ -63,11 +113,15 @@ impl PacketBuilder {
         };
         let namespace = namespace.into();
         if !binary {
-            let buffer = serialize_header(kind, None, &*namespace, id).into_bytes();
+            let id_start = serialize_header(kind, None, &namespace, None).len();
+            let buffer = serialize_header(kind, None, &namespace, id).into_bytes();
             PacketBuilder {
+                approach: Approach::Normal {
+                    id: id_start..buffer.len(),
+                },
                 buffer,
-                approach: Approach::Normal,
                 first: true,
+                args: 0,
             }
         } else {
             let buffer = Vec::new();
@@ -83,22 +137,115 @@ impl PacketBuilder {
                     namespace,
                     id,
                     attachments: Vec::new(),
+                    mode: BinaryMode::default(),
                 },
                 first: true,
+                args: 0,
+            }
+        }
+    }
+
+    /// Set the id of the packet, replacing the id it was created with if there was one.  For an
+    /// event this requests an ack from the receiver.  Connect and disconnect packets have no id,
+    /// so it has no effect on them.
+    pub fn set_id(&mut self, new_id: u64) {
+        match &mut self.approach {
+            Approach::Normal { id } => {
+                let digits = new_id.to_string();
+                let end = id.start + digits.len();
+                self.buffer.splice(id.clone(), digits.bytes());
+                id.end = end;
             }
+            Approach::Binary { id, .. } => *id = Some(new_id),
+            Approach::Control { .. } => (),
+        }
+    }
+
+    /// Set how binary data is detected in arguments serialized after this call.  Has no effect on
+    /// non-binary packets.
+    pub fn set_binary_mode(&mut self, new_mode: BinaryMode) {
+        if let Approach::Binary { mode, .. } = &mut self.approach {
+            *mode = new_mode;
         }
     }
 
     /// Serialize the given argument using its `Serialize` implementation.  Fails if `T`'s
-    /// implementation of `Serialize` decides to fail, or if `T` contains a map with non-string
-    /// keys.  If serialization fails, the internal state will be unchanged.
+    /// implementation of `Serialize` decides to fail, or if `T` contains a map with keys that
+    /// can't be JSON object keys.  Integer, `bool` and `char` keys are converted to strings, as
+    /// JavaScript does, so e.g. a `HashMap<u32, T>` can be sent.  If serialization fails, the
+    /// internal state will be unchanged.
+    ///
+    /// In a binary packet, byte strings and sequences consisting entirely of `u8`s are sent as
+    /// attachments, wrap values in `NoBinary` to send them as JSON arrays instead, or use
+    /// `BinaryMode::Explicit` to only send byte strings as attachments.  serde serializes
+    /// `Vec<u8>` and `&[u8]` one `u8` at a time, so large binary data is much faster to send as a
+    /// byte string, e.g. wrapped in `serde_bytes::Bytes`, which is copied at once.
     pub fn serialize_arg<T>(&mut self, arg: &T) -> Result<(), ArgsError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.serialize(arg, true)
+    }
+
+    /// Sign the packet: `sign` is called with the bytes of the text message as it would be sent
+    /// if the packet was finished now, and its result is added as the last argument.  The
+    /// signature is always serialized as plain JSON, even in a binary packet, so it doesn't add
+    /// an attachment and change the signed header.  It covers the header and the arguments
+    /// serialized so far, including attachment placeholders but not the attachments themselves,
+    /// so it should be added after every other argument.  The receiver can verify it against the
+    /// received message with the signature argument removed.
+    pub fn sign<T, F>(&mut self, sign: F) -> Result<(), ArgsError>
+    where
+        T: Serialize,
+        F: FnOnce(&[u8]) -> T,
+    {
+        let signature = sign(&self.text());
+        self.serialize(&signature, false)
+    }
+
+    /// The text message as it would be sent if the packet was finished now.
+    fn text(&self) -> Vec<u8> {
+        let mut text = match &self.approach {
+            Approach::Normal { .. } | Approach::Control { .. } => Vec::new(),
+            Approach::Binary {
+                kind,
+                namespace,
+                id,
+                attachments,
+                ..
+            } => {
+                serialize_header(*kind, Some(attachments.len() as u64), namespace, *id).into_bytes()
+            }
+        };
+        text.extend_from_slice(&self.buffer);
+        text.extend_from_slice(self.closing().as_bytes());
+        text
+    }
+
+    fn serialize<T>(&mut self, arg: &T, allow_binary: bool) -> Result<(), ArgsError>
     where
         T: Serialize + ?Sized,
     {
         let start_pos = self.buffer.len();
         let mut cursor = Cursor::new(&mut self.buffer);
         cursor.set_position(start_pos as u64);
+        if let Approach::Control { payload } = self.approach {
+            if !(payload && self.first) {
+                let error = "connect packets have a single payload, disconnect packets have none";
+                return Err(ArgsError::JsonSerError(serde_json::Error::custom(error)));
+            }
+            let result = args::serialize_arg(cursor, arg).map_err(|e| self.arg_error(e));
+            match result {
+                Ok(()) => {
+                    self.first = false;
+                    self.args += 1;
+                }
+                Err(_) => self
+                    .buffer
+                    .resize_with(start_pos, || panic!("shrinking vector")),
+            }
+            return result;
+        }
         if self.first {
             write!(cursor, "[").unwrap();
             self.first = false;
@@ -106,40 +253,93 @@ impl PacketBuilder {
             write!(cursor, ",").unwrap();
         }
         let result = match &mut self.approach {
-            Approach::Normal => args::serialize_arg(cursor, arg),
-            Approach::Binary { attachments, .. } => {
+            Approach::Binary {
+                attachments, mode, ..
+            } if allow_binary => {
                 let attachment_start = attachments.len();
-                let result = args::serialize_arg_binary(cursor, arg, attachments);
+                let result = args::serialize_arg_binary(cursor, arg, attachments, *mode);
                 if result.is_err() {
                     attachments.resize_with(attachment_start, || panic!("shrinking vector"));
                 }
                 result
             }
+            _ => args::serialize_arg(cursor, arg),
         };
-        if result.is_err() {
-            self.buffer
-                .resize_with(start_pos, || panic!("shrinking vector"));
+        match result {
+            Ok(()) => self.args += 1,
+            Err(_) => self
+                .buffer
+                .resize_with(start_pos, || panic!("shrinking vector")),
+        }
+        result.map_err(|e| self.arg_error(e))
+    }
+
+    /// Add the index of the argument being serialized to a serialization error.
+    fn arg_error(&self, error: ArgsError) -> ArgsError {
+        match error {
+            ArgsError::JsonSerError(error) => ArgsError::ArgSerError {
+                index: self.args,
+                error,
+            },
+            e => e,
+        }
+    }
+
+    /// The size of the packet if it was finished with the arguments serialized so far.
+    pub fn preview(&self) -> PacketPreview {
+        let closing = self.closing().len();
+        match &self.approach {
+            Approach::Normal { .. } | Approach::Control { .. } => PacketPreview {
+                byte_len: self.buffer.len() + closing,
+                attachment_count: 0,
+            },
+            Approach::Binary {
+                kind,
+                namespace,
+                id,
+                attachments,
+                ..
+            } => {
+                let header =
+                    serialize_header(*kind, Some(attachments.len() as u64), namespace, *id);
+                let attachment_len: usize = attachments.iter().map(WsMessage::len).sum();
+                PacketPreview {
+                    byte_len: header.len() + self.buffer.len() + closing + attachment_len,
+                    attachment_count: attachments.len(),
+                }
+            }
+        }
+    }
+
+    /// The text that closes the argument array: its closing bracket, or an empty array if there
+    /// are no arguments, as an ack with no arguments still needs one.
+    fn closing(&self) -> &'static str {
+        match self.approach {
+            Approach::Control { .. } => "",
+            _ if self.first => "[]",
+            _ => "]",
         }
-        result
     }
 
+    /// The websocket messages the packet is sent as: the text message, followed by any
+    /// attachments in a binary packet.
     pub fn finish(self) -> Vec<WsMessage> {
+        let closing = self.closing();
         // This is safe because we've only written to this via write!, and json serialization
         let mut s = unsafe { String::from_utf8_unchecked(self.buffer) };
-        if !self.first {
-            s.push(']');
-        }
+        s.push_str(closing);
         match self.approach {
-            Approach::Normal => vec![engine::package_message(s)],
+            Approach::Normal { .. } | Approach::Control { .. } => vec![engine::package_message(s)],
             Approach::Binary {
                 kind,
                 namespace,
                 id,
                 mut attachments,
+                mode: _,
             } => {
                 // Create the header
                 let mut header =
-                    serialize_header(kind, Some(attachments.len() as u64), &*namespace, id);
+                    serialize_header(kind, Some(attachments.len() as u64), &namespace, id);
                 header.push_str(s.as_str());
                 attachments.insert(0, engine::package_message(header));
                 attachments
@@ -148,10 +348,26 @@ impl PacketBuilder {
     }
 }
 
+/// A request to connect to `namespace`, as the engine.io message it's sent in.
 pub fn serialize_connect(namespace: &str) -> EngineMessage {
     EngineMessage::Text(serialize_header(ProtocolKind::Connect, None, namespace, None).into())
 }
 
+/// Like `serialize_connect`, with `auth` as the packet's payload, e.g. a token the server checks
+/// before accepting the connection.  Payloads on connect packets were added in protocol v5.
+pub fn serialize_connect_with_auth<T>(namespace: &str, auth: &T) -> Result<EngineMessage, ArgsError>
+where
+    T: Serialize + ?Sized,
+{
+    let mut message = serialize_header(ProtocolKind::Connect, None, namespace, None).into_bytes();
+    args::serialize_arg(&mut message, auth)?;
+    // serde_json only writes valid utf-8
+    Ok(EngineMessage::Text(
+        String::from_utf8(message).unwrap().into(),
+    ))
+}
+
+/// A notice that the client is leaving `namespace`, as the engine.io message it's sent in.
 pub fn serialize_disconnect(namespace: &str) -> EngineMessage {
     EngineMessage::Text(serialize_header(ProtocolKind::Disconnect, None, namespace, None).into())
 }
@@ -168,6 +384,7 @@ fn serialize_header(
         ProtocolKind::Disconnect => '1',
         ProtocolKind::Event => '2',
         ProtocolKind::Ack => '3',
+        ProtocolKind::ConnectError => '4',
         ProtocolKind::BinaryEvent => '5',
         ProtocolKind::BinaryAck => '6',
     };
@@ -188,6 +405,8 @@ fn serialize_header(
 mod tests {
     use super::*;
 
+    use serde_json::json;
+
     #[test]
     fn test_connect() {
         assert_eq!(
@@ -196,6 +415,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_connect_with_auth() {
+        #[derive(Serialize)]
+        struct Auth {
+            token: &'static str,
+        }
+
+        let auth = Auth { token: "abc" };
+        assert_eq!(
+            serialize_connect_with_auth("/", &auth).unwrap(),
+            EngineMessage::Text(r#"40{"token":"abc"}"#.to_string().into())
+        );
+        assert_eq!(
+            serialize_connect_with_auth("/nsp", &auth).unwrap(),
+            EngineMessage::Text(r#"40/nsp,{"token":"abc"}"#.to_string().into())
+        );
+    }
+
     #[test]
     fn test_disconnect() {
         assert_eq!(
@@ -204,12 +441,186 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_connect_disconnect_builders() {
+        let packet = PacketBuilder::new_connect("/nsp").finish();
+        assert_eq!(packet, vec![WsMessage::Text("40/nsp,".to_string())]);
+
+        let mut builder = PacketBuilder::new_connect("/");
+        builder.set_id(1);
+        builder.serialize_arg(&json!({"token": "abc"})).unwrap();
+        assert!(builder.serialize_arg("second").is_err());
+        assert_eq!(builder.preview().byte_len, 17);
+        assert_eq!(
+            builder.finish(),
+            vec![WsMessage::Text(r#"40{"token":"abc"}"#.to_string())]
+        );
+
+        let mut builder = PacketBuilder::new_disconnect("/nsp");
+        assert!(builder.serialize_arg("payload").is_err());
+        assert_eq!(
+            builder.finish(),
+            vec![WsMessage::Text("41/nsp,".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_empty_ack() {
+        let builder = PacketBuilder::new_ack("/", 3, false);
+        assert_eq!(builder.preview().byte_len, 5);
+        assert_eq!(builder.finish(), vec![WsMessage::Text("433[]".to_string())]);
+
+        let packet = PacketBuilder::new_ack("/nsp", 3, true).finish();
+        assert_eq!(packet, vec![WsMessage::Text("460-/nsp,3[]".to_string())]);
+    }
+
     #[test]
     fn test_simple() {
         let packet = PacketBuilder::new_event("event", "/", None, false).finish();
         assert_eq!(packet, vec![WsMessage::Text(r#"42["event"]"#.to_string())]);
     }
 
+    #[test]
+    fn test_set_id() {
+        let mut builder = PacketBuilder::new_event("event", "/nsp", None, false);
+        builder.set_id(12);
+        builder.serialize_arg("arg").unwrap();
+        builder.set_id(3);
+        assert_eq!(
+            builder.finish(),
+            vec![WsMessage::Text(r#"42/nsp,3["event","arg"]"#.to_string())]
+        );
+
+        let mut builder = PacketBuilder::new_event("event", "/", Some(5), true);
+        builder.set_id(7);
+        assert_eq!(
+            builder.finish(),
+            vec![WsMessage::Text(r#"450-7["event"]"#.to_string())]
+        );
+    }
+
+    #[test]
+    fn test_binary_mode() {
+        let data = vec![1u8, 2, 3];
+        let mut builder = PacketBuilder::new_event("event", "/", None, true);
+        builder.serialize_arg(&data).unwrap();
+        builder.set_binary_mode(BinaryMode::Explicit);
+        builder.serialize_arg(&data).unwrap();
+        assert_eq!(
+            builder.finish(),
+            vec![
+                WsMessage::Text(
+                    r#"451-["event",{"_placeholder":true,"num":0},[1,2,3]]"#.to_string()
+                ),
+                WsMessage::Binary(vec![4, 1, 2, 3])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_integer_keys() {
+        let mut map = std::collections::HashMap::new();
+        map.insert(3u32, "three");
+        for &binary in &[false, true] {
+            let mut builder = PacketBuilder::new_event("event", "/", None, binary);
+            builder.serialize_arg(&map).unwrap();
+            let text = match &builder.finish()[0] {
+                WsMessage::Text(text) => text.clone(),
+                msg => panic!("Unexpected message {:?}", msg),
+            };
+            assert!(text.ends_with(r#"["event",{"3":"three"}]"#));
+        }
+
+        // Keys are never sent as attachments
+        let mut map = std::collections::HashMap::new();
+        map.insert(vec![1u8], "one");
+        let mut builder = PacketBuilder::new_event("event", "/", None, true);
+        assert!(builder.serialize_arg(&map).is_err());
+        assert_eq!(builder.preview().attachment_count, 0);
+    }
+
+    #[test]
+    fn test_arg_error_index() {
+        let mut map = std::collections::HashMap::new();
+        map.insert(vec![1u8], "one");
+        for &binary in &[false, true] {
+            let mut builder = PacketBuilder::new_event("event", "/", None, binary);
+            builder.serialize_arg("first").unwrap();
+            match builder.serialize_arg(&map) {
+                Err(ArgsError::ArgSerError { index: 1, .. }) => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+            // The failed argument isn't counted
+            builder.serialize_arg("second").unwrap();
+            match builder.serialize_arg(&map) {
+                Err(e) => assert!(e.to_string().starts_with("Error serializing argument 2 ")),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+
+        let mut builder = PacketBuilder::new_ack("/", 1, false);
+        assert!(matches!(
+            builder.serialize_arg(&map),
+            Err(ArgsError::ArgSerError { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_preview() {
+        fn check(builder: PacketBuilder) {
+            let preview = builder.preview();
+            let packet = builder.finish();
+            assert_eq!(
+                preview.byte_len,
+                packet.iter().map(WsMessage::len).sum::<usize>()
+            );
+            assert_eq!(preview.attachment_count, packet.len() - 1);
+        }
+
+        check(PacketBuilder::new_ack("/", 1, false));
+        check(PacketBuilder::new_ack("/nsp", 1, true));
+        let mut builder = PacketBuilder::new_event("event", "/nsp", Some(10), false);
+        builder.serialize_arg(&[1u8, 2]).unwrap();
+        check(builder);
+        let mut builder = PacketBuilder::new_event("event", "/nsp", Some(10), true);
+        builder.serialize_arg(&vec![1u8, 2]).unwrap();
+        builder.serialize_arg(&vec![3u8; 20]).unwrap();
+        assert_eq!(builder.preview().attachment_count, 2);
+        check(builder);
+    }
+
+    #[test]
+    fn test_sign() {
+        let mut builder = PacketBuilder::new_event("event", "/nsp", Some(1), false);
+        builder.serialize_arg(&1).unwrap();
+        builder
+            .sign(|text| {
+                assert_eq!(text, br#"42/nsp,1["event",1]"#);
+                text.len()
+            })
+            .unwrap();
+        assert_eq!(
+            builder.finish(),
+            vec![WsMessage::Text(r#"42/nsp,1["event",1,19]"#.to_string())]
+        );
+
+        let mut builder = PacketBuilder::new_event("event", "/", None, true);
+        builder.serialize_arg(&[1u8][..]).unwrap();
+        builder
+            .sign(|text| {
+                assert_eq!(text, br#"451-["event",{"_placeholder":true,"num":0}]"#);
+                vec![2u8]
+            })
+            .unwrap();
+        assert_eq!(
+            builder.finish(),
+            vec![
+                WsMessage::Text(r#"451-["event",{"_placeholder":true,"num":0},[2]]"#.to_string()),
+                WsMessage::Binary(vec![4, 1]),
+            ]
+        );
+    }
+
     #[test]
     fn test_simple_binary() {
         let data = [0xdeu8, 0xad, 0xbe, 0xef];